@@ -5,10 +5,13 @@ use std::{
 
 use criterion::{measurement::WallTime, BenchmarkGroup, Criterion};
 use goodmetrics::{
-    allocator::AlwaysNewMetricsAllocator,
+    allocator::{AlwaysNewMetricsAllocator, MetricsAllocator},
     downstream::OpentelemetryBatcher,
-    pipeline::{Aggregator, DistributionMode, StreamSink},
-    MetricsFactory,
+    pipeline::{
+        Aggregator, DistributionMode, MetricsReceiver, ShardRouting, ShardedAggregator,
+        ShardedSink, Sink, StreamSink,
+    },
+    Metrics, MetricsFactory,
 };
 use tokio::sync::mpsc;
 
@@ -26,6 +29,7 @@ pub fn aggregation(criterion: &mut Criterion) {
         },
     );
     bench_distribution_mode(&mut group, DistributionMode::TDigest);
+    bench_sharded(&mut group, 4);
 }
 
 fn bench_distribution_mode(
@@ -48,33 +52,156 @@ fn bench_distribution_mode(
         OpentelemetryBatcher,
     ));
 
+    bench_concurrency(group, &format!("{distribution_mode}"), &metrics_factory);
+}
+
+fn bench_sharded(group: &mut BenchmarkGroup<'_, WallTime>, shards: usize) {
+    let distribution_mode = DistributionMode::ExponentialHistogram {
+        max_buckets: 160,
+        desired_scale: 2,
+    };
+    let (sink, receivers) = ShardedSink::new(shards, ShardRouting::Position);
+    let aggregator = ShardedAggregator::new(
+        receivers
+            .into_iter()
+            .map(|receiver| Aggregator::new(receiver, distribution_mode)),
+    );
+    let metrics_factory: MetricsFactory<AlwaysNewMetricsAllocator, ShardedSink<_>> =
+        MetricsFactory::new(sink);
+    let (aggregated_batch_sender, _r) = mpsc::channel(128);
+    let metrics_runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .worker_threads(shards)
+        .build()
+        .expect("I can make a runtime");
+    metrics_runtime.spawn(aggregator.aggregate_metrics_forever(
+        Duration::from_secs(1),
+        aggregated_batch_sender,
+        OpentelemetryBatcher,
+    ));
+
+    bench_concurrency(
+        group,
+        &format!("{distribution_mode}-sharded-{shards}"),
+        &metrics_factory,
+    );
+}
+
+fn bench_concurrency<TSink>(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    name: &str,
+    metrics_factory: &MetricsFactory<AlwaysNewMetricsAllocator, TSink>,
+) where
+    TSink: Sink<Metrics> + Sync + 'static,
+{
     for threads in [1, 4, 16] {
-        group.bench_function(
-            format!("{distribution_mode}-concurrency-{threads:02}"),
-            |bencher| {
-                bencher.iter_custom(|iterations| {
-                    let thread_count = max(1, min(threads, iterations));
-                    let iterations_per_thread = iterations / thread_count;
-
-                    let start = Instant::now();
-                    std::thread::scope(|scope| {
-                        for _ in 0..thread_count {
-                            scope.spawn(|| {
-                                for i in 0..iterations_per_thread {
-                                    let mut metrics = metrics_factory.record_scope("demo");
-                                    let _scope = metrics.time("timed_delay");
-                                    metrics.measurement("ran", 1);
-                                    metrics.dimension("mod", i % 8);
-                                }
-                            });
-                        }
-                    });
-
-                    start.elapsed()
+        group.bench_function(format!("{name}-concurrency-{threads:02}"), |bencher| {
+            bencher.iter_custom(|iterations| {
+                let thread_count = max(1, min(threads, iterations));
+                let iterations_per_thread = iterations / thread_count;
+
+                let start = Instant::now();
+                std::thread::scope(|scope| {
+                    for _ in 0..thread_count {
+                        scope.spawn(|| {
+                            for i in 0..iterations_per_thread {
+                                let mut metrics = metrics_factory.record_scope("demo");
+                                let _scope = metrics.time("timed_delay");
+                                metrics.measurement("ran", 1);
+                                metrics.dimension("mod", i % 8);
+                            }
+                        });
+                    }
                 });
-            },
-        );
+
+                start.elapsed()
+            });
+        });
+    }
+}
+
+/// How long an idle Aggregator takes to pick up 1 metric, by how its receiver notices it
+pub fn wakeup(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("aggregation_wakeup");
+    group.sample_size(20);
+
+    let (sink, receiver) = StreamSink::new();
+    bench_wakeup(
+        &mut group,
+        "woken",
+        move |metrics| sink.accept(metrics),
+        receiver,
+    );
+
+    let (sender, receiver) = std::sync::mpsc::sync_channel(1024);
+    bench_wakeup(
+        &mut group,
+        "polled",
+        move |metrics| {
+            let _ = sender.try_send(metrics);
+        },
+        MetricsReceiver::from(receiver),
+    );
+}
+
+fn bench_wakeup(
+    group: &mut BenchmarkGroup<'_, WallTime>,
+    name: &str,
+    send: impl Fn(Signalling),
+    receiver: MetricsReceiver<Signalling>,
+) {
+    let aggregator = Aggregator::new(receiver, DistributionMode::Histogram);
+    let (aggregated_batch_sender, _r) = mpsc::channel(128);
+    let metrics_runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .worker_threads(1)
+        .build()
+        .expect("I can make a runtime");
+    metrics_runtime.spawn(aggregator.aggregate_metrics_forever(
+        Duration::from_secs(1),
+        aggregated_batch_sender,
+        OpentelemetryBatcher,
+    ));
+
+    let (aggregated, wait_for_aggregated) = std::sync::mpsc::sync_channel(1);
+    group.bench_function(name, |bencher| {
+        bencher.iter(|| {
+            let mut metrics = AlwaysNewMetricsAllocator.new_metrics("wakeup");
+            metrics.measurement("ran", 1);
+            send(Signalling {
+                metrics,
+                aggregated: aggregated.clone(),
+            });
+            wait_for_aggregated
+                .recv()
+                .expect("the aggregator is running");
+        });
+    });
+}
+
+/// Metrics that say when the Aggregator is done with them, so each iteration waits for the
+/// receiver to wake up instead of only for the send.
+struct Signalling {
+    metrics: Metrics,
+    aggregated: std::sync::mpsc::SyncSender<()>,
+}
+
+impl AsRef<Metrics> for Signalling {
+    fn as_ref(&self) -> &Metrics {
+        &self.metrics
+    }
+}
+
+impl AsMut<Metrics> for Signalling {
+    fn as_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+}
+
+impl Drop for Signalling {
+    fn drop(&mut self) {
+        let _ = self.aggregated.try_send(());
     }
 }
 
-criterion::criterion_group!(benches, aggregation);
+criterion::criterion_group!(benches, aggregation, wakeup);
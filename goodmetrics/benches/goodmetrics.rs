@@ -4,7 +4,7 @@ use criterion::Criterion;
 
 use goodmetrics::{
     allocator::AlwaysNewMetricsAllocator,
-    downstream::{get_client, GoodmetricsBatcher, GoodmetricsDownstream},
+    downstream::{get_client, GoodmetricsBatcher, GoodmetricsDownstream, TlsConfiguration},
     pipeline::{Aggregator, DistributionMode, StreamSink},
     MetricsFactory,
 };
@@ -32,7 +32,7 @@ pub fn goodmetrics_demo(criterion: &mut Criterion) {
         runtime.block_on(async move {
             let channel = get_client(
                 &endpoint,
-                || None,
+                &TlsConfiguration::dangerously_accept_any_certificate(),
                 goodmetrics::proto::goodmetrics::metrics_client::MetricsClient::with_origin,
             )
             .expect("i can make a channel to goodmetrics");
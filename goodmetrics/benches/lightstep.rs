@@ -5,7 +5,7 @@ use goodmetrics::allocator::AlwaysNewMetricsAllocator;
 use goodmetrics::pipeline::StreamSink;
 
 use goodmetrics::{
-    downstream::{get_client, OpenTelemetryDownstream, OpentelemetryBatcher},
+    downstream::{get_client, OpenTelemetryDownstream, OpentelemetryBatcher, TlsConfiguration},
     pipeline::{Aggregator, DistributionMode},
     MetricsFactory,
 };
@@ -31,12 +31,9 @@ pub fn lightstep_demo(criterion: &mut Criterion) {
         runtime.block_on(async move {
             let channel = get_client(
                 "https://ingest.lightstep.com",
-                || {
-                    let store = RootCertStore {
-                        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-                    };
-                    Some(store)
-                },
+                &TlsConfiguration::with_roots(RootCertStore {
+                    roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+                }),
                 goodmetrics::proto::opentelemetry::collector::metrics::v1::metrics_service_client::MetricsServiceClient::with_origin,
             )
             .expect("i can make a channel to lightstep");
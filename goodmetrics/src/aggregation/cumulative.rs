@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+
+use exponential_histogram::ExponentialHistogram;
+
+use super::{Aggregation, ExponentialBuckets, StatisticSet, TDigest};
+
+/// The exponential_histogram crate's default, and the otlp canonical bucket count
+const MAX_EXPONENTIAL_BUCKETS: u16 = 160;
+
+/// Running totals of an aggregation, across reporting windows.
+///
+/// Goodmetrics aggregates in windows and forgets. Scrapers and cumulative collectors want
+/// values that only go up, so this folds each window into what came before.
+#[derive(Debug, Clone)]
+pub(crate) enum Cumulative {
+    Sum(i64),
+    /// Sum and count add up. Min and max are from the latest window; they don't add up.
+    StatisticSet {
+        window: StatisticSet,
+        sum: i64,
+        count: u64,
+    },
+    /// Bucket -> count, like Histogram
+    Histogram(BTreeMap<i64, u64>),
+    ExponentialHistogram(CumulativeExponentialHistogram),
+    TDigest(TDigest),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct CumulativeExponentialHistogram {
+    pub(crate) buckets: ExponentialBuckets,
+    pub(crate) sum: f64,
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+}
+
+impl Cumulative {
+    pub(crate) fn new(aggregation: Aggregation) -> Self {
+        match aggregation {
+            Aggregation::Sum(sum) => Self::Sum(sum.sum),
+            Aggregation::StatisticSet(statistic_set) => Self::StatisticSet {
+                sum: statistic_set.sum,
+                count: statistic_set.count,
+                window: statistic_set,
+            },
+            Aggregation::Histogram(histogram) => {
+                Self::Histogram(histogram.into_map().into_iter().collect())
+            }
+            Aggregation::ExponentialHistogram(histogram) => {
+                let mut cumulative = CumulativeExponentialHistogram {
+                    buckets: ExponentialBuckets::new(MAX_EXPONENTIAL_BUCKETS, histogram.scale()),
+                    sum: 0.0,
+                    min: 0.0,
+                    max: 0.0,
+                };
+                cumulative.accumulate(histogram);
+                Self::ExponentialHistogram(cumulative)
+            }
+            Aggregation::TDigest(mut digest) => Self::TDigest(digest.reset_mut()),
+        }
+    }
+
+    /// Fold the next window in
+    pub(crate) fn accumulate(&mut self, aggregation: Aggregation) {
+        match (self, aggregation) {
+            (Self::Sum(total), Aggregation::Sum(sum)) => *total += sum.sum,
+            (
+                Self::StatisticSet { window, sum, count },
+                Aggregation::StatisticSet(statistic_set),
+            ) => {
+                *sum += statistic_set.sum;
+                *count += statistic_set.count;
+                *window = statistic_set;
+            }
+            (Self::Histogram(buckets), Aggregation::Histogram(histogram)) => {
+                for (bucket, count) in histogram.into_map() {
+                    *buckets.entry(bucket).or_default() += count;
+                }
+            }
+            (
+                Self::ExponentialHistogram(cumulative),
+                Aggregation::ExponentialHistogram(histogram),
+            ) => cumulative.accumulate(histogram),
+            (Self::TDigest(total), Aggregation::TDigest(mut digest)) => {
+                *total = TDigest::merge_digests(vec![std::mem::take(total), digest.reset_mut()])
+            }
+            // The measurement changed type. Start over rather than mixing them.
+            (this, aggregation) => *this = Self::new(aggregation),
+        }
+    }
+}
+
+impl CumulativeExponentialHistogram {
+    fn accumulate(&mut self, histogram: ExponentialHistogram) {
+        if histogram.is_empty() {
+            return;
+        }
+        if self.buckets.count() == 0 {
+            self.min = histogram.min();
+            self.max = histogram.max();
+        } else {
+            self.min = self.min.min(histogram.min());
+            self.max = self.max.max(histogram.max());
+        }
+        self.sum += histogram.sum();
+
+        let scale = histogram.scale() as i32;
+        let offset = histogram.bucket_start_offset() as i32;
+        let (positives, negatives) = histogram.take_counts();
+        for (negative, counts) in [(false, positives), (true, negatives)] {
+            for (i, count) in counts.into_iter().enumerate() {
+                self.buckets
+                    .record_bucket(negative, scale, offset + i as i32, count as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use exponential_histogram::ExponentialHistogram;
+
+    use crate::aggregation::{Aggregation, Histogram, StatisticSet, Sum};
+
+    use super::Cumulative;
+
+    #[test]
+    fn windows_add_up() {
+        let mut sum = Cumulative::new(Aggregation::Sum(Sum { sum: 2 }));
+        sum.accumulate(Aggregation::Sum(Sum { sum: 3 }));
+        assert!(matches!(sum, Cumulative::Sum(5)));
+
+        let mut statistic_set = Cumulative::new(Aggregation::StatisticSet(StatisticSet {
+            min: 1,
+            max: 4,
+            sum: 5,
+            count: 2,
+        }));
+        statistic_set.accumulate(Aggregation::StatisticSet(StatisticSet {
+            min: 2,
+            max: 2,
+            sum: 2,
+            count: 1,
+        }));
+        let Cumulative::StatisticSet { window, sum, count } = statistic_set else {
+            panic!("should still be a statistic set")
+        };
+        assert_eq!((7, 3), (sum, count));
+        assert_eq!(
+            (2, 2),
+            (window.min, window.max),
+            "min and max are per-window"
+        );
+
+        let mut histogram = Histogram::default();
+        histogram.accumulate(5);
+        let mut cumulative = Cumulative::new(Aggregation::Histogram(histogram.clone()));
+        cumulative.accumulate(Aggregation::Histogram(histogram));
+        let Cumulative::Histogram(buckets) = cumulative else {
+            panic!("should still be a histogram")
+        };
+        assert_eq!(vec![(5, 2)], buckets.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn exponential_histograms_add_up() {
+        let mut histogram = ExponentialHistogram::new(3);
+        histogram.accumulate(3);
+        histogram.accumulate(30);
+        let mut cumulative = Cumulative::new(Aggregation::ExponentialHistogram(histogram));
+
+        let mut histogram = ExponentialHistogram::new(8);
+        histogram.accumulate(300);
+        cumulative.accumulate(Aggregation::ExponentialHistogram(histogram));
+
+        let Cumulative::ExponentialHistogram(cumulative) = cumulative else {
+            panic!("should still be an exponential histogram")
+        };
+        assert_eq!(3, cumulative.buckets.count());
+        assert_eq!(3, cumulative.buckets.scale());
+    }
+
+    #[test]
+    fn changing_type_starts_over() {
+        let mut cumulative = Cumulative::new(Aggregation::Sum(Sum { sum: 2 }));
+        cumulative.accumulate(Aggregation::StatisticSet(StatisticSet {
+            min: 1,
+            max: 1,
+            sum: 1,
+            count: 1,
+        }));
+        assert!(matches!(
+            cumulative,
+            Cumulative::StatisticSet {
+                sum: 1,
+                count: 1,
+                ..
+            }
+        ));
+    }
+}
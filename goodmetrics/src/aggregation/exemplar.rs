@@ -0,0 +1,100 @@
+use std::time::SystemTime;
+
+/// An example observation from a distribution, with the trace it came from.
+///
+/// When a percentile looks bad, an exemplar is a trace you can go look at.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    /// W3C trace id
+    pub trace_id: [u8; 16],
+    /// W3C span id
+    pub span_id: [u8; 8],
+    /// The value that was observed
+    pub value: f64,
+    /// When the value was aggregated
+    pub time: SystemTime,
+}
+
+/// A bounded, uniform sample of the exemplars offered during a reporting window.
+///
+/// Every offered exemplar has the same chance to be kept, so a busy distribution costs no
+/// more than a quiet one.
+#[derive(Debug, Clone, Default)]
+pub struct ExemplarReservoir {
+    capacity: usize,
+    offered: u64,
+    exemplars: Vec<Exemplar>,
+}
+
+impl ExemplarReservoir {
+    /// A reservoir that keeps up to capacity exemplars
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            offered: 0,
+            exemplars: Vec::new(),
+        }
+    }
+
+    /// Maybe keep this exemplar, replacing one that was kept before
+    pub fn offer(&mut self, exemplar: Exemplar) {
+        self.offered += 1;
+        if self.exemplars.len() < self.capacity {
+            self.exemplars.push(exemplar);
+            return;
+        }
+        // Algorithm R: the nth exemplar replaces a kept one with probability capacity/n
+        let slot = rand::random_range(0..self.offered);
+        if let Some(kept) = self.exemplars.get_mut(slot as usize) {
+            *kept = exemplar;
+        }
+    }
+
+    /// How many exemplars were offered, including the ones that were not kept
+    pub fn offered(&self) -> u64 {
+        self.offered
+    }
+
+    /// The exemplars that were kept
+    pub fn into_exemplars(self) -> Vec<Exemplar> {
+        self.exemplars
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use super::{Exemplar, ExemplarReservoir};
+
+    fn exemplar(value: f64) -> Exemplar {
+        Exemplar {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            value,
+            time: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test_log::test]
+    fn reservoir_is_bounded() {
+        let mut reservoir = ExemplarReservoir::new(4);
+        for i in 0..1000 {
+            reservoir.offer(exemplar(i as f64));
+        }
+        assert_eq!(1000, reservoir.offered());
+        let exemplars = reservoir.into_exemplars();
+        assert_eq!(4, exemplars.len());
+        assert!(
+            exemplars.iter().any(|exemplar| 4.0 <= exemplar.value),
+            "later exemplars get a chance too: {exemplars:?}"
+        );
+    }
+
+    #[test_log::test]
+    fn empty_reservoir_keeps_nothing() {
+        let mut reservoir = ExemplarReservoir::new(0);
+        reservoir.offer(exemplar(1.0));
+        assert_eq!(Vec::<Exemplar>::new(), reservoir.into_exemplars());
+    }
+}
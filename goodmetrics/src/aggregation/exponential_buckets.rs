@@ -0,0 +1,220 @@
+/// The smallest scale otlp allows. At -10 every f64 fits in 2 buckets.
+const MIN_SCALE: i32 = -10;
+
+/// Otlp-shaped exponential histogram buckets that accept weighted values.
+///
+/// The exponential_histogram crate records 1 observation at a time, which is right for
+/// the hot path. This is for when you already have (value, count) pairs, like t-digest
+/// centroids, and want otlp buckets out of them.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExponentialBuckets {
+    max_buckets: usize,
+    scale: i32,
+    zero_count: u64,
+    positive: Buckets,
+    negative: Buckets,
+}
+
+/// A dense run of bucket counts, starting at bucket index `offset`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Buckets {
+    offset: i32,
+    counts: Vec<u64>,
+}
+
+impl ExponentialBuckets {
+    pub(crate) fn new(max_buckets: u16, desired_scale: u8) -> Self {
+        Self {
+            max_buckets: max_buckets.max(1) as usize,
+            scale: desired_scale as i32,
+            zero_count: 0,
+            positive: Default::default(),
+            negative: Default::default(),
+        }
+    }
+
+    /// Record `count` observations of `value`. Scales down as needed to stay within max_buckets.
+    pub(crate) fn record(&mut self, value: f64, count: u64) {
+        if count == 0 || value.is_nan() {
+            return;
+        }
+        if value == 0.0 {
+            self.zero_count += count;
+            return;
+        }
+        let index = bucket_index(value.abs(), self.scale);
+        self.add(value < 0.0, index, count);
+    }
+
+    /// Add `count` to a bucket from some other otlp-shaped histogram, at that histogram's scale.
+    pub(crate) fn record_bucket(&mut self, negative: bool, scale: i32, index: i32, count: u64) {
+        if count == 0 {
+            return;
+        }
+        if scale < self.scale {
+            self.downscale((self.scale - scale) as u32);
+        }
+        let index = index >> (scale - self.scale);
+        self.add(negative, index, count);
+    }
+
+    /// index is at the current scale
+    fn add(&mut self, negative: bool, mut index: i32, count: u64) {
+        loop {
+            let buckets = if negative {
+                &mut self.negative
+            } else {
+                &mut self.positive
+            };
+            if buckets.span_with(index) <= self.max_buckets || self.scale <= MIN_SCALE {
+                buckets.increment(index, count);
+                return;
+            }
+            self.downscale(1);
+            index >>= 1;
+        }
+    }
+
+    fn downscale(&mut self, by: u32) {
+        self.positive.downscale(by);
+        self.negative.downscale(by);
+        self.scale -= by as i32;
+    }
+
+    pub(crate) fn scale(&self) -> i32 {
+        self.scale
+    }
+
+    pub(crate) fn zero_count(&self) -> u64 {
+        self.zero_count
+    }
+
+    /// How many values were recorded, including zeros
+    pub(crate) fn count(&self) -> u64 {
+        self.zero_count + self.positive.total() + self.negative.total()
+    }
+
+    pub(crate) fn take_buckets(self) -> (Buckets, Buckets) {
+        (self.positive, self.negative)
+    }
+
+    /// (upper bound, count) for every bucket, ascending. Zeros are in the bucket bounded by 0.
+    pub(crate) fn upper_bound_counts(&self) -> Vec<(f64, u64)> {
+        let base = 2_f64.powf(2_f64.powi(-self.scale));
+        let mut counts =
+            Vec::with_capacity(self.negative.counts.len() + self.positive.counts.len() + 1);
+        // Negative bucket i holds [-base^(i+1), -base^i), so the biggest index comes first
+        for (i, count) in self.negative.counts.iter().enumerate().rev() {
+            counts.push((-base.powi(self.negative.offset + i as i32), *count));
+        }
+        if 0 < self.zero_count {
+            counts.push((0.0, self.zero_count));
+        }
+        for (i, count) in self.positive.counts.iter().enumerate() {
+            counts.push((base.powi(self.positive.offset + i as i32 + 1), *count));
+        }
+        counts
+    }
+}
+
+impl Buckets {
+    pub(crate) fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub(crate) fn into_counts(self) -> Vec<u64> {
+        self.counts
+    }
+
+    fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// How many buckets would be needed to also hold index
+    fn span_with(&self, index: i32) -> usize {
+        if self.counts.is_empty() {
+            return 1;
+        }
+        let low = self.offset.min(index);
+        let high = (self.offset + self.counts.len() as i32 - 1).max(index);
+        (high - low) as usize + 1
+    }
+
+    fn increment(&mut self, index: i32, count: u64) {
+        if self.counts.is_empty() {
+            self.offset = index;
+            self.counts.push(count);
+            return;
+        }
+        if index < self.offset {
+            let grow_by = (self.offset - index) as usize;
+            self.counts.splice(0..0, std::iter::repeat_n(0, grow_by));
+            self.offset = index;
+        }
+        let position = (index - self.offset) as usize;
+        if self.counts.len() <= position {
+            self.counts.resize(position + 1, 0);
+        }
+        self.counts[position] += count;
+    }
+
+    fn downscale(&mut self, by: u32) {
+        if self.counts.is_empty() {
+            return;
+        }
+        // Arithmetic shift floors, which is what otlp wants for negative indices too.
+        let offset = self.offset >> by;
+        let high = (self.offset + self.counts.len() as i32 - 1) >> by;
+        let mut counts = vec![0; (high - offset) as usize + 1];
+        for (i, count) in self.counts.drain(..).enumerate() {
+            counts[(((self.offset + i as i32) >> by) - offset) as usize] += count;
+        }
+        self.offset = offset;
+        self.counts = counts;
+    }
+}
+
+/// Otlp bucket index i holds (base^i, base^(i+1)] where base = 2^(2^-scale)
+fn bucket_index(magnitude: f64, scale: i32) -> i32 {
+    (magnitude.log2() * 2_f64.powi(scale)).ceil() as i32 - 1
+}
+
+#[cfg(test)]
+mod test {
+    use super::ExponentialBuckets;
+
+    #[test]
+    fn buckets_follow_otlp_boundaries() {
+        let mut buckets = ExponentialBuckets::new(160, 0);
+        // Scale 0 is powers of 2: (1, 2], (2, 4], (4, 8]
+        buckets.record(2.0, 1);
+        buckets.record(3.0, 2);
+        buckets.record(8.0, 3);
+        buckets.record(0.0, 4);
+        buckets.record(-3.0, 5);
+
+        assert_eq!(0, buckets.scale());
+        assert_eq!(4, buckets.zero_count());
+        assert_eq!(15, buckets.count());
+        let (positive, negative) = buckets.take_buckets();
+        assert_eq!(0, positive.offset());
+        assert_eq!(vec![1, 2, 3], positive.into_counts());
+        assert_eq!(1, negative.offset());
+        assert_eq!(vec![5], negative.into_counts());
+    }
+
+    #[test]
+    fn scales_down_to_fit() {
+        let mut buckets = ExponentialBuckets::new(4, 8);
+        for value in [1.5, 10.0, 100.0, 1000.0, 10_000.0] {
+            buckets.record(value, 1);
+        }
+
+        assert!(buckets.scale() < 8);
+        assert_eq!(5, buckets.count());
+        let (positive, _) = buckets.take_buckets();
+        let counts = positive.into_counts();
+        assert!(counts.len() <= 4, "{counts:?}");
+        assert_eq!(5, counts.iter().sum::<u64>());
+    }
+}
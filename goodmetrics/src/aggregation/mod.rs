@@ -1,6 +1,9 @@
 //! Types for working with in-memory local aggregations
 
 mod bucket;
+mod cumulative;
+mod exemplar;
+mod exponential_buckets;
 mod histogram;
 mod online_tdigest;
 mod statistic_set;
@@ -9,6 +12,9 @@ mod sum;
 mod tdigest;
 
 pub(crate) use bucket::bucket_10_below_2_sigfigs;
+pub(crate) use cumulative::Cumulative;
+pub use exemplar::{Exemplar, ExemplarReservoir};
+pub(crate) use exponential_buckets::ExponentialBuckets;
 use exponential_histogram::ExponentialHistogram;
 pub use histogram::Histogram;
 pub use online_tdigest::OnlineTdigest;
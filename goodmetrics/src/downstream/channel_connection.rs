@@ -1,12 +1,10 @@
-use std::{str::FromStr, sync::Arc};
+use std::str::FromStr;
 
 use hyper::Uri;
+use hyper_rustls::FixedServerNameResolver;
 use hyper_util::{client::legacy::connect::HttpConnector, rt::TokioExecutor};
-use tokio_rustls::rustls::{
-    client::danger::ServerCertVerifier, crypto::aws_lc_rs, ClientConfig, RootCertStore,
-};
 
-use super::StdError;
+use super::{StdError, TlsConfiguration};
 
 /// Type alias for internal channel type
 pub type ChannelType = hyper_util::client::legacy::Client<
@@ -14,53 +12,23 @@ pub type ChannelType = hyper_util::client::legacy::Client<
     tonic::body::Body,
 >;
 
-/// You can make an insecure connection by passing `|| { None }` to tls_trust.
-/// If you want to make a safer connection you can add your trust roots,
-/// for example:
+/// Make a grpc client for an endpoint, like "https://ingest.example.com".
+/// For a public collector you can trust the usual roots, for example:
 /// ```rust
-/// || {
-///     Some(tokio_rustls::rustls::RootCertStore {
-///         roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-///     })
-/// };
+/// # use goodmetrics::downstream::TlsConfiguration;
+/// TlsConfiguration::with_roots(tokio_rustls::rustls::RootCertStore {
+///     roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+/// });
 /// ```
-pub fn get_client<TrustFunction, WithOrigin, U>(
+pub fn get_client<WithOrigin, U>(
     endpoint: &str,
-    tls_trust: TrustFunction,
+    tls: &TlsConfiguration,
     with_origin: WithOrigin,
 ) -> Result<U, StdError>
 where
-    TrustFunction: FnOnce() -> Option<RootCertStore>,
     WithOrigin: Fn(ChannelType, Uri) -> U,
 {
-    let tls = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
-        .with_safe_default_protocol_versions()?;
-    let tls = match tls_trust() {
-        Some(trust) => tls.with_root_certificates(trust).with_no_client_auth(),
-        None => {
-            let mut config = tls
-                .with_root_certificates(RootCertStore::empty())
-                .with_no_client_auth();
-            config
-                .dangerous()
-                .set_certificate_verifier(Arc::new(StupidVerifier {}));
-            config
-        }
-    };
-
-    let mut http_connector = HttpConnector::new();
-    http_connector.enforce_http(false);
-    let https_connector = tower::ServiceBuilder::new()
-        .layer_fn(move |http_connector| {
-            let tls = tls.clone();
-
-            hyper_rustls::HttpsConnectorBuilder::new()
-                .with_tls_config(tls)
-                .https_or_http()
-                .enable_http2()
-                .wrap_connector(http_connector)
-        })
-        .service(http_connector);
+    let https_connector = https_connector(tls, true)?;
 
     let https_client = hyper_util::client::legacy::Client::builder(TokioExecutor::new())
         .http2_only(true)
@@ -73,52 +41,37 @@ where
     Ok(with_origin(https_client, uri))
 }
 
-#[derive(Debug)]
-struct StupidVerifier {}
-
-impl ServerCertVerifier for StupidVerifier {
-    fn verify_server_cert(
-        &self,
-        _end_entity: &tonic::transport::CertificateDer<'_>,
-        _intermediates: &[tonic::transport::CertificateDer<'_>],
-        _server_name: &tokio_rustls::rustls::pki_types::ServerName<'_>,
-        _ocsp_response: &[u8],
-        _now: tokio_rustls::rustls::pki_types::UnixTime,
-    ) -> Result<tokio_rustls::rustls::client::danger::ServerCertVerified, tokio_rustls::rustls::Error>
-    {
-        // roflmao
-        Ok(tokio_rustls::rustls::client::danger::ServerCertVerified::assertion())
-    }
-
-    fn verify_tls12_signature(
-        &self,
-        _message: &[u8],
-        _cert: &tonic::transport::CertificateDer<'_>,
-        _dss: &tokio_rustls::rustls::DigitallySignedStruct,
-    ) -> Result<
-        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
-        tokio_rustls::rustls::Error,
-    > {
-        // roflmao
-        Ok(tokio_rustls::rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
+/// Type alias for the plain http client type, for OTLP/HTTP
+pub type HttpChannelType = hyper_util::client::legacy::Client<
+    hyper_rustls::HttpsConnector<HttpConnector>,
+    http_body_util::Full<bytes::Bytes>,
+>;
 
-    fn verify_tls13_signature(
-        &self,
-        _message: &[u8],
-        _cert: &tonic::transport::CertificateDer<'_>,
-        _dss: &tokio_rustls::rustls::DigitallySignedStruct,
-    ) -> Result<
-        tokio_rustls::rustls::client::danger::HandshakeSignatureValid,
-        tokio_rustls::rustls::Error,
-    > {
-        // roflmao
-        Ok(tokio_rustls::rustls::client::danger::HandshakeSignatureValid::assertion())
-    }
+/// Like get_client, but for plain http/1.1 and h2 requests rather than grpc.
+/// It works through http proxies that cannot carry grpc.
+pub fn get_http_client(tls: &TlsConfiguration) -> Result<HttpChannelType, StdError> {
+    let https_connector = https_connector(tls, false)?;
+    Ok(hyper_util::client::legacy::Client::builder(TokioExecutor::new()).build(https_connector))
+}
 
-    fn supported_verify_schemes(&self) -> Vec<tokio_rustls::rustls::SignatureScheme> {
-        tokio_rustls::rustls::crypto::aws_lc_rs::default_provider()
-            .signature_verification_algorithms
-            .supported_schemes()
+fn https_connector(
+    tls: &TlsConfiguration,
+    http2_only: bool,
+) -> Result<hyper_rustls::HttpsConnector<HttpConnector>, StdError> {
+    let mut http_connector = HttpConnector::new();
+    http_connector.enforce_http(false);
+    let mut builder = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls.client_config()?)
+        .https_or_http();
+    if let Some(server_name) = tls.server_name_override()? {
+        builder = builder.with_server_name_resolver(FixedServerNameResolver::new(server_name));
     }
+    Ok(if http2_only {
+        builder.enable_http2().wrap_connector(http_connector)
+    } else {
+        builder
+            .enable_http1()
+            .enable_http2()
+            .wrap_connector(http_connector)
+    })
 }
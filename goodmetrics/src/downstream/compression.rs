@@ -0,0 +1,75 @@
+use tonic::codec::CompressionEncoding;
+
+/// Log how big a request is, before and after compression.
+///
+/// Tonic compresses out of sight, so measuring the compressed size means compressing again.
+/// That is not free, so it only happens when debug logging is on.
+pub(crate) fn log_request_size(
+    compression: Option<CompressionEncoding>,
+    request: &impl prost::Message,
+) {
+    if !log::log_enabled!(log::Level::Debug) {
+        return;
+    }
+    let uncompressed = request.encoded_len();
+    match compression.and_then(|encoding| {
+        compressed_len(encoding, &request.encode_to_vec()).map(|len| (encoding, len))
+    }) {
+        Some((encoding, compressed)) => log::debug!(
+            "request is {uncompressed} bytes, {compressed} bytes with {encoding:?} compression"
+        ),
+        None => log::debug!("request is {uncompressed} bytes"),
+    }
+}
+
+/// The size of bytes compressed the way tonic does it, if this build knows how
+#[allow(unused_variables)]
+fn compressed_len(encoding: CompressionEncoding, bytes: &[u8]) -> Option<usize> {
+    match encoding {
+        #[cfg(feature = "gzip")]
+        CompressionEncoding::Gzip => {
+            use std::io::Write;
+            // Tonic uses level 6
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(6));
+            encoder.write_all(bytes).ok()?;
+            encoder.finish().ok().map(|compressed| compressed.len())
+        }
+        #[cfg(feature = "zstd")]
+        CompressionEncoding::Zstd => zstd::bulk::compress(bytes, zstd::DEFAULT_COMPRESSION_LEVEL)
+            .ok()
+            .map(|compressed| compressed.len()),
+        // Another crate may have turned on an encoding that goodmetrics does not know about
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[allow(unused)]
+    use tonic::codec::CompressionEncoding;
+
+    #[allow(unused)]
+    fn assert_compresses(encoding: CompressionEncoding) {
+        let payload = "dimension_key=dimension_value,".repeat(100);
+        let compressed =
+            super::compressed_len(encoding, payload.as_bytes()).expect("encoding is enabled");
+        assert!(
+            compressed < payload.len() / 10,
+            "{encoding:?}: {compressed}"
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test_log::test]
+    fn gzip() {
+        assert_compresses(CompressionEncoding::Gzip)
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test_log::test]
+    fn zstd() {
+        assert_compresses(CompressionEncoding::Zstd)
+    }
+}
@@ -0,0 +1,855 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::pin,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{select, Either},
+    FutureExt, Stream, StreamExt,
+};
+
+#[cfg(feature = "introspect")]
+use super::delivery_introspection::DeliveryIntrospection;
+use super::{spool::SpoolPosition, Spool};
+
+/// What to do with a new batch when the retry queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued batch to make room. Fresh data is usually the more interesting data.
+    DropOldest,
+    /// Drop the incoming batch and keep what is already queued.
+    DropNewest,
+}
+
+/// How a downstream holds on to and retries batches that failed to send.
+///
+/// Failed batches wait in a bounded in-memory queue and are retried oldest-first with
+/// jittered exponential backoff. A batch is given up on when it hits a non-retryable
+/// error, or when it has been retrying for longer than the retry deadline.
+///
+/// When every sender for a downstream is dropped, like when your Aggregator shuts down, the
+/// downstream keeps trying what is queued for up to the shutdown deadline and then returns.
+///
+/// With a spool, batches that retryably failed, overflowed or outlived the shutdown deadline
+/// are written to disk instead of dropped. With an aggregator spool, windows that an Aggregator
+/// could not fit in its channel to this downstream are replayed too.
+#[derive(Debug, Clone)]
+pub struct DeliveryConfiguration {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_deadline: Duration,
+    shutdown_deadline: Duration,
+    max_queued_batches: usize,
+    overflow_policy: OverflowPolicy,
+    spool: Option<Spool>,
+    aggregator_spool: Option<Spool>,
+}
+
+impl Default for DeliveryConfiguration {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(15),
+            retry_deadline: Duration::from_secs(120),
+            shutdown_deadline: Duration::from_secs(10),
+            max_queued_batches: 128,
+            overflow_policy: OverflowPolicy::DropOldest,
+            spool: None,
+            aggregator_spool: None,
+        }
+    }
+}
+
+impl DeliveryConfiguration {
+    /// Set the delay before the first retry (default 100ms). It doubles per attempt, up to max_backoff.
+    pub fn initial_backoff(&mut self, initial_backoff: Duration) {
+        self.initial_backoff = initial_backoff
+    }
+
+    /// Set the longest delay between retries (default 15s)
+    pub fn max_backoff(&mut self, max_backoff: Duration) {
+        self.max_backoff = max_backoff
+    }
+
+    /// Set how long a batch may be retried before it is dropped (default 2 minutes).
+    /// Zero disables retries.
+    pub fn retry_deadline(&mut self, retry_deadline: Duration) {
+        self.retry_deadline = retry_deadline
+    }
+
+    /// Set how long to keep sending queued batches after the upstream closes (default 10s).
+    /// Whatever is still queued after that is dropped.
+    pub fn shutdown_deadline(&mut self, shutdown_deadline: Duration) {
+        self.shutdown_deadline = shutdown_deadline
+    }
+
+    /// Set how many batches may wait for delivery at once (default 128).
+    /// A batch that is split into several requests still takes 1 place in the queue.
+    pub fn max_queued_batches(&mut self, max_queued_batches: usize) {
+        self.max_queued_batches = max_queued_batches.max(1)
+    }
+
+    /// Set what happens when the queue is full (default DropOldest)
+    pub fn overflow_policy(&mut self, overflow_policy: OverflowPolicy) {
+        self.overflow_policy = overflow_policy
+    }
+
+    /// Keep batches on disk instead of dropping them when they run out of retries, overflow the
+    /// queue, or are still queued at shutdown. Spooled batches are replayed, oldest first,
+    /// whenever the downstream has caught up with fresh batches.
+    pub fn spool(&mut self, spool: Spool) {
+        self.spool = Some(spool)
+    }
+
+    /// Replay batches that an Aggregator kept in this spool because its channel to this
+    /// downstream was full. Give the Aggregator the same spool with `with_spool`, and use a
+    /// different directory than for `spool`. A replayed batch is sent like a fresh batch.
+    pub fn aggregator_spool(&mut self, spool: Spool) {
+        self.aggregator_spool = Some(spool)
+    }
+
+    fn backoff(&self, attempts: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff);
+        // Equal jitter: keep at least half of the backoff so a flapping collector still gets some air.
+        exponential.mul_f64(rand::random_range(0.5..=1.0))
+    }
+}
+
+/// Why a send did not work out, and whether it is worth trying again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DeliveryFailure {
+    retryable: bool,
+    retry_after: Option<Duration>,
+}
+
+impl DeliveryFailure {
+    pub(crate) fn retryable() -> Self {
+        Self {
+            retryable: true,
+            retry_after: None,
+        }
+    }
+
+    pub(crate) fn permanent() -> Self {
+        Self {
+            retryable: false,
+            retry_after: None,
+        }
+    }
+
+    /// The collector asked for this much quiet before the next attempt
+    pub(crate) fn with_retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+}
+
+impl From<&tonic::Status> for DeliveryFailure {
+    fn from(status: &tonic::Status) -> Self {
+        // Per the otlp spec for which grpc codes are retryable. Connection failures show up as Unavailable
+        // or, depending on where in the stack they happen, Unknown.
+        match status.code() {
+            tonic::Code::Cancelled
+            | tonic::Code::DeadlineExceeded
+            | tonic::Code::ResourceExhausted
+            | tonic::Code::Aborted
+            | tonic::Code::OutOfRange
+            | tonic::Code::Unavailable
+            | tonic::Code::DataLoss
+            | tonic::Code::Unknown => Self::retryable(),
+            _ => Self::permanent(),
+        }
+    }
+}
+
+/// A protocol the delivery queue can send through.
+pub(crate) trait Delivery {
+    /// What the aggregation pipeline hands to the downstream
+    type Batch;
+    /// What goes over the wire. It is cloned for each attempt.
+    type Request: Clone;
+
+    /// Wrap a batch up for sending, in as many requests as it takes.
+    /// No requests means the batch can't be sent, and it is dropped.
+    fn prepare(&mut self, batch: Self::Batch) -> Vec<Self::Request>;
+
+    /// Make 1 attempt to send a request
+    fn send(&mut self, request: Self::Request)
+        -> impl Future<Output = Result<(), DeliveryFailure>>;
+
+    /// The oldest batch an Aggregator spooled for this downstream.
+    /// By default a downstream does not replay them.
+    fn unspool_batch(&self, _spool: &Spool) -> Option<(SpoolPosition, Self::Batch)> {
+        None
+    }
+
+    /// Where to report how delivery is going
+    #[cfg(feature = "introspect")]
+    fn introspection(&self) -> Option<&'static DeliveryIntrospection> {
+        None
+    }
+}
+
+struct Pending<TRequest> {
+    request: TRequest,
+    /// Which batch this request was prepared from. A batch's requests are next to each other.
+    batch: u64,
+    enqueued: Instant,
+    attempts: u32,
+    next_attempt: Instant,
+    /// Where this request is in the spool, if it is being replayed from there
+    spooled: Option<SpoolPosition>,
+}
+
+/// The shared send loop for downstreams: a bounded queue of requests that are retried until they work or expire.
+pub(crate) struct DeliveryQueue<TRequest> {
+    configuration: DeliveryConfiguration,
+    queue: VecDeque<Pending<TRequest>>,
+    /// Distinct batches in the queue, for max_queued_batches
+    queued_batches: usize,
+    next_batch: u64,
+    /// Times in a row the front of the spool ran out of retries
+    replay_failures: u32,
+    /// When the front of the spool may be replayed again, after it ran out of retries
+    next_replay: Option<Instant>,
+}
+
+impl<TRequest> DeliveryQueue<TRequest>
+where
+    TRequest: Clone + prost::Message + Default,
+{
+    pub(crate) fn new(configuration: DeliveryConfiguration) -> Self {
+        Self {
+            queue: VecDeque::with_capacity(configuration.max_queued_batches.min(1024)),
+            configuration,
+            queued_batches: 0,
+            next_batch: 0,
+            replay_failures: 0,
+            next_replay: None,
+        }
+    }
+
+    /// Send everything from the stream. When the stream ends, this keeps trying whatever is still
+    /// queued until it is sent, expires, or the shutdown deadline passes, then returns.
+    pub(crate) async fn deliver_forever<TDelivery>(
+        mut self,
+        mut delivery: TDelivery,
+        mut batches: impl Stream<Item = TDelivery::Batch> + Unpin,
+    ) where
+        TDelivery: Delivery<Request = TRequest>,
+    {
+        let mut shutdown_deadline: Option<Instant> = None;
+        loop {
+            #[cfg(feature = "introspect")]
+            if let Some(introspection) = delivery.introspection() {
+                introspection.queue_depth(self.queue.len())
+            }
+            let upstream_open = shutdown_deadline.is_none();
+            let next_attempt = match self.queue.front() {
+                Some(pending) => pending.next_attempt,
+                None => {
+                    if !upstream_open {
+                        return;
+                    }
+                    // Fresh batches go first. The spool is replayed when there is nothing else to do.
+                    let batch = match batches.next().now_or_never() {
+                        Some(batch) => batch,
+                        None if self.replay_spooled() => continue,
+                        None if self.replay_aggregator_spooled(&mut delivery) => continue,
+                        None => match self.next_replay {
+                            Some(next_replay) => {
+                                match tokio::time::timeout_at(next_replay.into(), batches.next())
+                                    .await
+                                {
+                                    Ok(batch) => batch,
+                                    Err(_time_to_replay) => continue,
+                                }
+                            }
+                            None => batches.next().await,
+                        },
+                    };
+                    match batch {
+                        Some(batch) => {
+                            self.push(delivery.prepare(batch));
+                            continue;
+                        }
+                        None => return,
+                    }
+                }
+            };
+
+            // Backing off. Keep accepting new batches while we wait so the overflow policy applies to them.
+            if upstream_open && Instant::now() < next_attempt {
+                match tokio::time::timeout_at(next_attempt.into(), batches.next()).await {
+                    Ok(Some(batch)) => {
+                        self.push(delivery.prepare(batch));
+                        continue;
+                    }
+                    Ok(None) => {
+                        shutdown_deadline =
+                            Some(Instant::now() + self.configuration.shutdown_deadline);
+                        continue;
+                    }
+                    Err(_waited_for_backoff) => (),
+                }
+            }
+
+            if let Some(shutdown_deadline) = shutdown_deadline {
+                if shutdown_deadline < next_attempt
+                    || tokio::time::timeout_at(shutdown_deadline.into(), async {
+                        tokio::time::sleep_until(next_attempt.into()).await;
+                        self.attempt_front(&mut delivery, &mut batches, false).await
+                    })
+                    .await
+                    .is_err()
+                {
+                    self.spool_or_drop_queue();
+                    return;
+                }
+            } else if !self.attempt_front(&mut delivery, &mut batches, true).await {
+                shutdown_deadline = Some(Instant::now() + self.configuration.shutdown_deadline);
+            }
+        }
+    }
+
+    /// Queue the requests for 1 batch
+    fn push(&mut self, requests: Vec<TRequest>) {
+        if requests.is_empty() {
+            return;
+        }
+        let batch = self.new_batch();
+        if self.configuration.max_queued_batches < self.queued_batches {
+            match self.configuration.overflow_policy {
+                OverflowPolicy::DropOldest => {
+                    if let Some(oldest) = self.queue.front().map(|pending| pending.batch) {
+                        while self
+                            .queue
+                            .front()
+                            .is_some_and(|pending| pending.batch == oldest)
+                        {
+                            if let Some(pending) = self.pop_front() {
+                                self.spool_or_drop(
+                                    pending,
+                                    "metrics delivery queue is full",
+                                    "the oldest",
+                                );
+                            }
+                        }
+                    }
+                }
+                OverflowPolicy::DropNewest => {
+                    self.queued_batches -= 1;
+                    for request in requests {
+                        self.spool_or_drop(
+                            Pending::new(request, batch, None),
+                            "metrics delivery queue is full",
+                            "the newest",
+                        );
+                    }
+                    return;
+                }
+            }
+        }
+        self.queue.extend(
+            requests
+                .into_iter()
+                .map(|request| Pending::new(request, batch, None)),
+        );
+    }
+
+    /// Count a new batch into the queue
+    fn new_batch(&mut self) -> u64 {
+        self.next_batch += 1;
+        self.queued_batches += 1;
+        self.next_batch
+    }
+
+    /// Take the front request, and count its batch out of the queue if it was the last of it
+    fn pop_front(&mut self) -> Option<Pending<TRequest>> {
+        let pending = self.queue.pop_front()?;
+        if self
+            .queue
+            .front()
+            .is_none_or(|next| next.batch != pending.batch)
+        {
+            self.queued_batches -= 1;
+        }
+        Some(pending)
+    }
+
+    /// Queue up the oldest spooled request, if there is one and it is not backing off
+    fn replay_spooled(&mut self) -> bool {
+        let Some(spool) = &self.configuration.spool else {
+            return false;
+        };
+        if self
+            .next_replay
+            .is_some_and(|next_replay| Instant::now() < next_replay)
+        {
+            return false;
+        }
+        let Some((position, request)) = spool.peek() else {
+            self.next_replay = None;
+            return false;
+        };
+        log::debug!("replaying a spooled metrics batch");
+        let batch = self.new_batch();
+        self.queue
+            .push_back(Pending::new(request, batch, Some(position)));
+        true
+    }
+
+    /// Queue up the oldest batch an Aggregator spooled, like a fresh batch
+    fn replay_aggregator_spooled<TDelivery>(&mut self, delivery: &mut TDelivery) -> bool
+    where
+        TDelivery: Delivery<Request = TRequest>,
+    {
+        let Some(spool) = &self.configuration.aggregator_spool else {
+            return false;
+        };
+        let Some((position, batch)) = delivery.unspool_batch(spool) else {
+            return false;
+        };
+        log::debug!("replaying a metrics batch that the aggregator spooled");
+        // From here it is like any other batch, so it is spooled or dropped like one
+        spool.discard(position);
+        self.push(delivery.prepare(batch));
+        true
+    }
+
+    /// Spooled requests are already in the spool. They stay there, at the front.
+    fn spool_or_drop(&self, pending: Pending<TRequest>, reason: &str, which: &str) {
+        if pending.spooled.is_some() {
+            log::warn!("{reason} - leaving {which} batch in the spool for later");
+            return;
+        }
+        match &self.configuration.spool {
+            Some(spool) => {
+                log::warn!("{reason} - spooling {which} batch");
+                spool.push(&pending.request);
+            }
+            None => log::error!("{reason} - dropping {which} batch"),
+        }
+    }
+
+    fn spool_or_drop_queue(&mut self) {
+        let queued = std::mem::take(&mut self.queued_batches);
+        for pending in std::mem::take(&mut self.queue) {
+            if pending.spooled.is_none() {
+                if let Some(spool) = &self.configuration.spool {
+                    spool.push(&pending.request);
+                }
+            }
+        }
+        match self.configuration.spool {
+            Some(_) => {
+                log::warn!("shutdown deadline passed - spooled {queued} queued metrics batches")
+            }
+            None => {
+                log::error!("shutdown deadline passed - dropping {queued} queued metrics batches")
+            }
+        }
+    }
+
+    /// Try the request at the front of the queue. While it is in flight, keep reading the
+    /// upstream so it does not back up into the Aggregator. Returns false if the upstream ended.
+    async fn attempt_front<TDelivery>(
+        &mut self,
+        delivery: &mut TDelivery,
+        batches: &mut (impl Stream<Item = TDelivery::Batch> + Unpin),
+        receiving: bool,
+    ) -> bool
+    where
+        TDelivery: Delivery<Request = TRequest>,
+    {
+        let Some(pending) = self.queue.front_mut() else {
+            return true;
+        };
+        pending.attempts += 1;
+        let request = pending.request.clone();
+
+        let mut upstream_open = receiving;
+        let mut arrived = Vec::new();
+        let result = {
+            let mut send = pin!(delivery.send(request));
+            loop {
+                if !upstream_open {
+                    break send.await;
+                }
+                match select(send.as_mut(), batches.next()).await {
+                    Either::Left((result, _)) => break result,
+                    Either::Right((Some(batch), _)) => arrived.push(batch),
+                    Either::Right((None, _)) => upstream_open = false,
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                if let Some(Pending {
+                    spooled: Some(position),
+                    ..
+                }) = self.pop_front()
+                {
+                    if let Some(spool) = &self.configuration.spool {
+                        spool.discard(position);
+                    }
+                    self.replay_failures = 0;
+                    self.next_replay = None;
+                }
+            }
+            Err(failure) => self.failed_front(failure),
+        }
+        for batch in arrived {
+            self.push(delivery.prepare(batch));
+        }
+        upstream_open || !receiving
+    }
+
+    fn failed_front(&mut self, failure: DeliveryFailure) {
+        let Some(pending) = self.queue.front_mut() else {
+            return;
+        };
+        let now = Instant::now();
+        let delay = failure
+            .retry_after
+            .unwrap_or_default()
+            .max(self.configuration.backoff(pending.attempts));
+        let deadline = pending.enqueued + self.configuration.retry_deadline;
+        if failure.retryable && now + delay < deadline {
+            log::warn!(
+                "retrying metrics batch in {delay:?} (attempt {})",
+                pending.attempts
+            );
+            pending.next_attempt = now + delay;
+            return;
+        }
+        let Some(pending) = self.pop_front() else {
+            return;
+        };
+        let reason = format!(
+            "metrics batch failed after {} attempts (retryable: {})",
+            pending.attempts, failure.retryable
+        );
+        match (
+            failure.retryable,
+            pending.spooled,
+            &self.configuration.spool,
+        ) {
+            (true, Some(_), _) => {
+                // It stays at the front of the spool. Back off before replaying it again, so a
+                // short retry deadline does not turn into a tight loop against a down collector.
+                self.replay_failures += 1;
+                self.next_replay = Some(now + self.configuration.backoff(self.replay_failures));
+                self.spool_or_drop(pending, &reason, "the")
+            }
+            (true, None, _) => self.spool_or_drop(pending, &reason, "the"),
+            (false, Some(position), Some(spool)) => {
+                log::error!("{reason} - dropping the batch from the spool");
+                spool.discard(position);
+            }
+            (false, _, _) => log::error!("{reason} - dropping the batch"),
+        }
+    }
+}
+
+impl<TRequest> Pending<TRequest> {
+    fn new(request: TRequest, batch: u64, spooled: Option<SpoolPosition>) -> Self {
+        let now = Instant::now();
+        Self {
+            request,
+            batch,
+            enqueued: now,
+            attempts: 0,
+            next_attempt: now,
+            spooled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use crate::downstream::Spool;
+
+    use super::{Delivery, DeliveryConfiguration, DeliveryFailure, DeliveryQueue, OverflowPolicy};
+
+    /// Fails every send until `failures` runs out, recording what it eventually sent.
+    struct FlakyDelivery {
+        failures: usize,
+        failure: DeliveryFailure,
+        attempts: usize,
+        sent: Vec<u32>,
+    }
+
+    impl Delivery for &mut FlakyDelivery {
+        type Batch = u32;
+        type Request = u32;
+
+        fn prepare(&mut self, batch: u32) -> Vec<u32> {
+            vec![batch]
+        }
+
+        async fn send(&mut self, request: u32) -> Result<(), DeliveryFailure> {
+            self.attempts += 1;
+            if 0 < self.failures {
+                self.failures -= 1;
+                return Err(self.failure.clone());
+            }
+            self.sent.push(request);
+            Ok(())
+        }
+    }
+
+    /// Splits each batch into `parts` requests, numbered `batch * 10 + part`
+    struct SplittingDelivery {
+        parts: u32,
+        sent: Vec<u32>,
+    }
+
+    impl Delivery for &mut SplittingDelivery {
+        type Batch = u32;
+        type Request = u32;
+
+        fn prepare(&mut self, batch: u32) -> Vec<u32> {
+            (0..self.parts).map(|part| batch * 10 + part).collect()
+        }
+
+        async fn send(&mut self, request: u32) -> Result<(), DeliveryFailure> {
+            self.sent.push(request);
+            Ok(())
+        }
+    }
+
+    fn fast_configuration() -> DeliveryConfiguration {
+        let mut configuration = DeliveryConfiguration::default();
+        configuration.initial_backoff(Duration::from_millis(1));
+        configuration.max_backoff(Duration::from_millis(4));
+        configuration
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn retries_until_success() {
+        let mut delivery = FlakyDelivery {
+            failures: 3,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        DeliveryQueue::new(fast_configuration())
+            .deliver_forever(&mut delivery, futures::stream::iter([1, 2, 3]))
+            .await;
+
+        assert_eq!(vec![1, 2, 3], delivery.sent, "order is preserved");
+        assert_eq!(6, delivery.attempts);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn permanent_failures_are_dropped() {
+        let mut delivery = FlakyDelivery {
+            failures: 1,
+            failure: DeliveryFailure::permanent(),
+            attempts: 0,
+            sent: vec![],
+        };
+        DeliveryQueue::new(fast_configuration())
+            .deliver_forever(&mut delivery, futures::stream::iter([1, 2]))
+            .await;
+
+        assert_eq!(vec![2], delivery.sent);
+        assert_eq!(2, delivery.attempts);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn gives_up_after_the_deadline() {
+        let mut delivery = FlakyDelivery {
+            failures: usize::MAX,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        let mut configuration = fast_configuration();
+        configuration.retry_deadline(Duration::from_millis(50));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            DeliveryQueue::new(configuration)
+                .deliver_forever(&mut delivery, futures::stream::iter([1])),
+        )
+        .await
+        .expect("the batch should be dropped at the deadline");
+
+        assert!(delivery.sent.is_empty());
+        assert!(1 < delivery.attempts, "it should have retried a few times");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn stops_at_the_shutdown_deadline() {
+        let mut delivery = FlakyDelivery {
+            failures: usize::MAX,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        let mut configuration = fast_configuration();
+        configuration.shutdown_deadline(Duration::from_millis(50));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            DeliveryQueue::new(configuration)
+                .deliver_forever(&mut delivery, futures::stream::iter([1, 2])),
+        )
+        .await
+        .expect("the queue should be given up on after the upstream closes");
+
+        assert!(delivery.sent.is_empty());
+        assert!(
+            1 < delivery.attempts,
+            "it should have retried until the deadline"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn overflow_policy() {
+        for (policy, expected) in [
+            // 1 is evicted by 3, which leaves 2 at the front - ready to send right away.
+            (OverflowPolicy::DropOldest, vec![2, 3, 4]),
+            (OverflowPolicy::DropNewest, vec![1, 2]),
+        ] {
+            let mut delivery = FlakyDelivery {
+                failures: 1,
+                failure: DeliveryFailure::retryable(),
+                attempts: 0,
+                sent: vec![],
+            };
+            let mut configuration = fast_configuration();
+            configuration.initial_backoff(Duration::from_millis(200));
+            configuration.max_backoff(Duration::from_millis(200));
+            configuration.max_queued_batches(2);
+            configuration.overflow_policy(policy);
+
+            // The first attempt fails, and the rest of the batches pile up during the backoff.
+            let (sender, receiver) = mpsc::channel(8);
+            let (first_attempted, first_attempted_receiver) = tokio::sync::oneshot::channel();
+            let mut first_attempted = Some(first_attempted);
+            let batches = ReceiverStream::new(receiver).inspect(move |_| {
+                if let Some(first_attempted) = first_attempted.take() {
+                    let _ = first_attempted.send(());
+                }
+            });
+            let producer = async move {
+                sender.send(1).await.expect("receiver is alive");
+                first_attempted_receiver.await.expect("delivery is alive");
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                for i in 2..=4 {
+                    sender.send(i).await.expect("receiver is alive");
+                }
+            };
+            tokio::join!(
+                producer,
+                DeliveryQueue::new(configuration).deliver_forever(&mut delivery, batches),
+            );
+
+            assert_eq!(expected, delivery.sent, "{policy:?}");
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn split_batches_take_1_place_in_the_queue() {
+        let mut delivery = SplittingDelivery {
+            parts: 5,
+            sent: vec![],
+        };
+        let mut configuration = fast_configuration();
+        configuration.max_queued_batches(2);
+        DeliveryQueue::new(configuration)
+            .deliver_forever(&mut delivery, futures::stream::iter([1, 2]))
+            .await;
+
+        assert_eq!(
+            vec![10, 11, 12, 13, 14, 20, 21, 22, 23, 24],
+            delivery.sent,
+            "no request is dropped for overflow"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn spooled_batches_are_replayed_in_order() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut configuration = fast_configuration();
+        configuration.retry_deadline(Duration::ZERO);
+        configuration.spool(Spool::open(directory.path(), 1 << 20).unwrap());
+        let mut down = FlakyDelivery {
+            failures: usize::MAX,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        DeliveryQueue::new(configuration)
+            .deliver_forever(&mut down, futures::stream::iter([1, 2, 3]))
+            .await;
+        assert!(down.sent.is_empty());
+
+        // Like the next process, after a restart
+        let mut configuration = fast_configuration();
+        configuration.spool(Spool::open(directory.path(), 1 << 20).unwrap());
+        let mut up = FlakyDelivery {
+            failures: 0,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        let _still_running = tokio::time::timeout(
+            Duration::from_millis(200),
+            DeliveryQueue::new(configuration).deliver_forever(
+                &mut up,
+                futures::stream::iter([4]).chain(futures::stream::pending()),
+            ),
+        )
+        .await;
+
+        assert_eq!(vec![4, 1, 2, 3], up.sent, "fresh batches go first");
+        assert!(Spool::open(directory.path(), 1 << 20).unwrap().is_empty());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn spool_replays_back_off() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut configuration = DeliveryConfiguration::default();
+        configuration.initial_backoff(Duration::from_millis(20));
+        configuration.max_backoff(Duration::from_millis(100));
+        configuration.retry_deadline(Duration::ZERO);
+        configuration.spool(Spool::open(directory.path(), 1 << 20).unwrap());
+        let mut down = FlakyDelivery {
+            failures: usize::MAX,
+            failure: DeliveryFailure::retryable(),
+            attempts: 0,
+            sent: vec![],
+        };
+        let _still_running = tokio::time::timeout(
+            Duration::from_millis(300),
+            DeliveryQueue::new(configuration).deliver_forever(
+                &mut down,
+                futures::stream::iter([1]).chain(futures::stream::pending()),
+            ),
+        )
+        .await;
+
+        assert!(down.sent.is_empty());
+        // 20ms doubling to 100ms, with up to half of that jittered off, fits at most ~12 tries
+        assert!(
+            (2..=15).contains(&down.attempts),
+            "{} attempts",
+            down.attempts
+        );
+        assert!(!Spool::open(directory.path(), 1 << 20).unwrap().is_empty());
+    }
+}
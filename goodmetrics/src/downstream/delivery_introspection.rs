@@ -0,0 +1,169 @@
+use std::time::Instant;
+
+use crate::{
+    gauge::SumHandle,
+    introspect::{LazyGaugeFamily, LazyHistogramGauge, LazyStatisticSetGauge},
+    Name,
+};
+
+/// How a downstream is doing, reported in `gm_introspect`
+pub(crate) struct DeliveryIntrospection {
+    requests: LazyGaugeFamily<SumHandle>,
+    latency: LazyHistogramGauge,
+    request_bytes: LazyHistogramGauge,
+    request_datapoints: LazyHistogramGauge,
+    queue_depth: LazyStatisticSetGauge,
+}
+
+impl DeliveryIntrospection {
+    /// `dimensions` tell this downstream apart from the others, like `[("downstream", "otlp")]`
+    pub(crate) const fn new(dimensions: &'static [(&'static str, &'static str)]) -> Self {
+        Self {
+            requests: LazyGaugeFamily::new_with_dimensions(
+                Name::Str("downstream_requests"),
+                dimensions,
+                "status",
+            ),
+            latency: LazyHistogramGauge::new_with_dimensions(
+                Name::Str("downstream_latency"),
+                dimensions,
+            ),
+            request_bytes: LazyHistogramGauge::new_with_dimensions(
+                Name::Str("downstream_request_bytes"),
+                dimensions,
+            ),
+            request_datapoints: LazyHistogramGauge::new_with_dimensions(
+                Name::Str("downstream_request_datapoints"),
+                dimensions,
+            ),
+            queue_depth: LazyStatisticSetGauge::new_with_dimensions(
+                Name::Str("downstream_queue_depth"),
+                dimensions,
+            ),
+        }
+    }
+
+    /// A request is about to go out. Pass the returned Instant to `response`.
+    pub(crate) fn request(&self, encoded_bytes: usize, datapoints: usize) -> Instant {
+        if let Some(gauge) = self.request_bytes.gauge() {
+            gauge.observe(encoded_bytes as i64)
+        }
+        if let Some(gauge) = self.request_datapoints.gauge() {
+            gauge.observe(datapoints as i64)
+        }
+        Instant::now()
+    }
+
+    /// The request that started at `started` finished with `status`
+    pub(crate) fn response(&self, started: Instant, status: tonic::Code) {
+        if let Some(gauge) = self.latency.gauge() {
+            gauge.observe(started.elapsed().as_nanos().min(i64::MAX as u128) as i64)
+        }
+        if let Some(gauge) = self.requests.gauge(&format!("{status:?}")) {
+            gauge.observe(1)
+        }
+    }
+
+    /// How many requests are waiting for delivery
+    pub(crate) fn queue_depth(&self, depth: usize) {
+        if let Some(gauge) = self.queue_depth.gauge() {
+            gauge.observe(depth as i64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        aggregation::Aggregation,
+        introspect::{
+            introspection_factory, run_introspection_metrics, IntrospectionConfiguration,
+        },
+        pipeline::{AggregatedMetricsMap, AggregationBatcher, DimensionedMeasurementsMap},
+        Dimension, Name,
+    };
+
+    use super::DeliveryIntrospection;
+
+    #[derive(Clone)]
+    struct BatchTaker;
+    impl AggregationBatcher for BatchTaker {
+        type TBatch = HashMap<Name, DimensionedMeasurementsMap>;
+
+        fn batch_aggregations(
+            &mut self,
+            _now: SystemTime,
+            _covered_time: Duration,
+            aggregations: &mut AggregatedMetricsMap,
+        ) -> Self::TBatch {
+            std::mem::take(aggregations)
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn reports_requests_by_status() {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(128);
+        let mut configuration = IntrospectionConfiguration::new(sender, BatchTaker);
+        configuration.cadence(Duration::from_millis(10));
+        tokio::spawn(run_introspection_metrics(configuration));
+        while introspection_factory().is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        let introspection = DeliveryIntrospection::new(&[("downstream", "test")]);
+        let started = introspection.request(100, 3);
+        introspection.response(started, tonic::Code::Ok);
+        let started = introspection.request(200, 5);
+        introspection.response(started, tonic::Code::Unavailable);
+        introspection.queue_depth(2);
+
+        let mut reported = DimensionedMeasurementsMap::default();
+        while reported.len() < 3 {
+            let mut batch = receiver.recv().await.expect("introspection is running");
+            for (position, measurements) in batch
+                .remove(&Name::Str("gm_introspect"))
+                .unwrap_or_default()
+            {
+                if position.get(&Name::Str("downstream")) == Some(&Dimension::from("test")) {
+                    reported.entry(position).or_default().extend(measurements);
+                }
+            }
+        }
+
+        let mut statuses: Vec<String> = reported
+            .iter()
+            .filter_map(|(position, measurements)| {
+                let Some(Aggregation::Sum(sum)) =
+                    measurements.get(&Name::Str("downstream_requests"))
+                else {
+                    return None;
+                };
+                assert_eq!(1, sum.sum);
+                position.get(&Name::Str("status")).map(ToString::to_string)
+            })
+            .collect();
+        statuses.sort();
+        assert_eq!(vec!["Ok", "Unavailable"], statuses);
+        let measurements = reported
+            .iter()
+            .find(|(position, _)| !position.contains_key(&Name::Str("status")))
+            .map(|(_, measurements)| measurements)
+            .expect("the gauges without a status are reported");
+        for name in [
+            "downstream_latency",
+            "downstream_request_bytes",
+            "downstream_request_datapoints",
+            "downstream_queue_depth",
+        ] {
+            assert!(
+                measurements.contains_key(&Name::Str(name)),
+                "{name}: {measurements:?}"
+            );
+        }
+    }
+}
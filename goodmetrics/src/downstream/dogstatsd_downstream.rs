@@ -0,0 +1,519 @@
+use std::{
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::{
+    aggregation::Cumulative,
+    pipeline::{AggregatedMetricsMap, AggregationBatcher, DimensionPosition},
+    types::{Dimension, Name},
+};
+
+/// The datadog agent's advice for udp: it fits in 1 ethernet frame with room for headers
+const DEFAULT_UDP_MTU: usize = 1432;
+/// The datadog agent's default buffer for unix datagrams
+#[cfg(unix)]
+const DEFAULT_UNIX_MTU: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DogstatsdType {
+    Counter,
+    Gauge,
+    Distribution,
+}
+
+impl DogstatsdType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DogstatsdType::Counter => "c",
+            DogstatsdType::Gauge => "g",
+            DogstatsdType::Distribution => "d",
+        }
+    }
+}
+
+/// One DogStatsD line: a metric name, a value, and its tags.
+/// These come from DogstatsdBatcher and go to DogstatsdDownstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DogstatsdMetric {
+    name: String,
+    value: f64,
+    kind: DogstatsdType,
+    /// Distributions stand in for `1 / sample_rate` observations of their value
+    sample_rate: f64,
+    /// Already formatted, and shared by every metric at the same dimension position
+    tags: Arc<str>,
+}
+
+/// Maps each window's aggregations to DogStatsD metrics. The agent does its own aggregation,
+/// so nothing is remembered between windows:
+/// * Sums are counters.
+/// * StatisticSets are `_min`, `_max`, `_sum` and `_count` gauges.
+/// * Histograms, exponential histograms and t-digests are distributions. Each bucket or
+///   centroid is sent once, with a sample rate that tells the agent how many observations it
+///   stands for.
+/// * Dimensions are tags.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DogstatsdBatcher;
+
+impl AggregationBatcher for DogstatsdBatcher {
+    type TBatch = Vec<DogstatsdMetric>;
+
+    fn batch_aggregations(
+        &mut self,
+        _now: SystemTime,
+        _covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+    ) -> Self::TBatch {
+        let mut batch = Vec::new();
+        for (name, mut dimensioned_measurements) in aggregations.drain() {
+            for (dimension_position, mut measurements) in dimensioned_measurements.drain() {
+                let tags: Arc<str> = as_tags(&dimension_position).into();
+                for (measurement_name, aggregation) in measurements.drain() {
+                    add_metrics(
+                        &mut batch,
+                        metric_name(&format!("{name}_{measurement_name}")),
+                        &tags,
+                        Cumulative::new(aggregation),
+                    );
+                }
+            }
+        }
+        batch
+    }
+}
+
+/// A window is a cumulative that has only seen 1 window
+fn add_metrics(
+    batch: &mut Vec<DogstatsdMetric>,
+    name: String,
+    tags: &Arc<str>,
+    window: Cumulative,
+) {
+    let mut add = |name: String, value: f64, kind: DogstatsdType, count: u64| {
+        if value.is_finite() && 0 < count {
+            batch.push(DogstatsdMetric {
+                name,
+                value,
+                kind,
+                sample_rate: 1.0 / count as f64,
+                tags: tags.clone(),
+            })
+        }
+    };
+    match window {
+        Cumulative::Sum(sum) => add(name, sum as f64, DogstatsdType::Counter, 1),
+        Cumulative::StatisticSet { window, .. } => {
+            for (component, value) in [
+                ("min", window.min as f64),
+                ("max", window.max as f64),
+                ("sum", window.sum as f64),
+                ("count", window.count as f64),
+            ] {
+                add(
+                    format!("{name}_{component}"),
+                    value,
+                    DogstatsdType::Gauge,
+                    1,
+                );
+            }
+        }
+        Cumulative::Histogram(buckets) => {
+            for (bucket, count) in buckets {
+                add(
+                    name.clone(),
+                    bucket as f64,
+                    DogstatsdType::Distribution,
+                    count,
+                );
+            }
+        }
+        Cumulative::ExponentialHistogram(histogram) => {
+            for (upper_bound, count) in histogram.buckets.upper_bound_counts() {
+                add(
+                    name.clone(),
+                    upper_bound,
+                    DogstatsdType::Distribution,
+                    count,
+                );
+            }
+        }
+        Cumulative::TDigest(mut digest) => {
+            for centroid in digest.drain_centroids() {
+                add(
+                    name.clone(),
+                    centroid.mean(),
+                    DogstatsdType::Distribution,
+                    centroid.weight().round() as u64,
+                );
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixDatagram),
+}
+
+impl Socket {
+    async fn send(&self, datagram: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Udp(socket) => socket.send(datagram).await,
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(datagram).await,
+        }
+    }
+}
+
+/// Sends DogStatsD datagrams to a datadog agent, or anything else that speaks DogStatsD.
+///
+/// Metrics are packed into as few datagrams as fit within the mtu. Datagrams are fire and
+/// forget: if the agent is not there, the metrics are gone.
+/// ```no_run
+/// # use std::time::Duration;
+/// # use goodmetrics::default_gauge_factory;
+/// # use goodmetrics::downstream::{DogstatsdBatcher, DogstatsdDownstream};
+/// # async fn example() {
+/// let downstream = DogstatsdDownstream::connect_udp("127.0.0.1:8125")
+///     .await
+///     .expect("can make a udp socket")
+///     .with_shared_dimensions([("service", "example")]);
+/// let (sender, receiver) = tokio::sync::mpsc::channel(128);
+/// tokio::spawn(downstream.send_batches_forever(receiver));
+/// tokio::spawn(default_gauge_factory().clone().report_gauges_forever(
+///     Duration::from_secs(10),
+///     sender,
+///     DogstatsdBatcher,
+/// ));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DogstatsdDownstream {
+    socket: Socket,
+    mtu: usize,
+    shared_tags: String,
+}
+
+impl DogstatsdDownstream {
+    /// Send to an agent's udp port, usually 8125. The mtu defaults to 1432 bytes.
+    pub async fn connect_udp(address: impl ToSocketAddrs) -> std::io::Result<Self> {
+        let address = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+        let socket = if address.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(address).await?;
+        Ok(Self::new(Socket::Udp(socket), DEFAULT_UDP_MTU))
+    }
+
+    /// Send to an agent's unix datagram socket, like `/var/run/datadog/dsd.socket`.
+    /// The mtu defaults to 8192 bytes.
+    #[cfg(unix)]
+    pub fn connect_unix(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let socket = tokio::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self::new(Socket::Unix(socket), DEFAULT_UNIX_MTU))
+    }
+
+    fn new(socket: Socket, mtu: usize) -> Self {
+        Self {
+            socket,
+            mtu,
+            shared_tags: String::new(),
+        }
+    }
+
+    /// Pack datagrams up to this many bytes. A single metric that is bigger than this is
+    /// sent in a datagram by itself.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Tag every metric sent through this downstream with these dimensions
+    pub fn with_shared_dimensions(
+        mut self,
+        shared_dimensions: impl IntoIterator<Item = (impl Into<Name>, impl Into<Dimension>)>,
+    ) -> Self {
+        self.shared_tags = as_tags(
+            &shared_dimensions
+                .into_iter()
+                .map(|(n, d)| (n.into(), d.into()))
+                .collect(),
+        );
+        self
+    }
+
+    /// Spawn this on a tokio runtime to send your metrics to your agent
+    pub async fn send_batches_forever(self, mut receiver: mpsc::Receiver<Vec<DogstatsdMetric>>) {
+        while let Some(batch) = receiver.recv().await {
+            for datagram in self.datagrams(batch) {
+                if let Err(e) = self.socket.send(datagram.as_bytes()).await {
+                    log::error!("failed to send metrics: {e}");
+                }
+            }
+        }
+    }
+
+    fn datagrams(&self, batch: Vec<DogstatsdMetric>) -> Vec<String> {
+        let mut datagrams = Vec::new();
+        let mut datagram = String::with_capacity(self.mtu);
+        let mut line = String::new();
+        for metric in batch {
+            line.clear();
+            self.write_line(&mut line, &metric);
+            if !datagram.is_empty() && self.mtu < datagram.len() + 1 + line.len() {
+                datagrams.push(std::mem::replace(
+                    &mut datagram,
+                    String::with_capacity(self.mtu),
+                ));
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+        datagrams
+    }
+
+    /// `name:value|type|@sample_rate|#tag:value,tag:value`
+    fn write_line(&self, line: &mut String, metric: &DogstatsdMetric) {
+        let _ = write!(
+            line,
+            "{}:{}|{}",
+            metric.name,
+            metric.value,
+            metric.kind.as_str()
+        );
+        if metric.sample_rate < 1.0 {
+            let _ = write!(line, "|@{}", metric.sample_rate);
+        }
+        match (metric.tags.is_empty(), self.shared_tags.is_empty()) {
+            (true, true) => (),
+            (false, true) => {
+                let _ = write!(line, "|#{}", metric.tags);
+            }
+            (true, false) => {
+                let _ = write!(line, "|#{}", self.shared_tags);
+            }
+            (false, false) => {
+                let _ = write!(line, "|#{},{}", metric.tags, self.shared_tags);
+            }
+        }
+    }
+}
+
+/// DogStatsD names are letters, digits, underscores and periods. Anything else becomes _
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn as_tags(dimension_position: &DimensionPosition) -> String {
+    let mut tags = String::new();
+    for (name, dimension) in dimension_position {
+        if !tags.is_empty() {
+            tags.push(',');
+        }
+        tags.push_str(&tag_part(name.as_str(), true));
+        tags.push(':');
+        tags.push_str(&tag_part(&dimension.to_string(), false));
+    }
+    tags
+}
+
+/// Keep tags from breaking the line. A colon is fine in a value but not in a key.
+fn tag_part(part: &str, is_key: bool) -> String {
+    part.chars()
+        .map(|c| match c {
+            '|' | ',' | '#' | '\n' | '\r' => '_',
+            ':' if is_key => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::{Duration, SystemTime},
+    };
+
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        aggregation::{Aggregation, Histogram, StatisticSet, Sum},
+        pipeline::{AggregatedMetricsMap, AggregationBatcher},
+        types::{Dimension, Name},
+    };
+
+    use super::{DogstatsdBatcher, DogstatsdDownstream};
+
+    fn window(measurements: Vec<(&'static str, Aggregation)>) -> AggregatedMetricsMap {
+        HashMap::from([(
+            Name::from("api"),
+            HashMap::from([(
+                BTreeMap::from([(Name::from("host"), Dimension::from("a|b"))]),
+                measurements
+                    .into_iter()
+                    .map(|(name, aggregation)| (Name::from(name), aggregation))
+                    .collect(),
+            )]),
+        )])
+    }
+
+    async fn listener() -> (UdpSocket, DogstatsdDownstream) {
+        let listener = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let downstream = DogstatsdDownstream::connect_udp(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        (listener, downstream)
+    }
+
+    async fn receive(listener: &UdpSocket) -> String {
+        let mut buffer = vec![0; 65536];
+        let length = tokio::time::timeout(Duration::from_secs(5), listener.recv(&mut buffer))
+            .await
+            .expect("a datagram should arrive")
+            .unwrap();
+        String::from_utf8(buffer[..length].to_vec()).unwrap()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn aggregations_become_datagrams() {
+        let (listener, downstream) = listener().await;
+        let downstream = downstream.with_shared_dimensions([("service", "test")]);
+        let mut histogram = Histogram::default();
+        histogram.accumulate(5);
+        histogram.accumulate(5);
+        histogram.accumulate(5);
+        histogram.accumulate(5);
+        let batch = DogstatsdBatcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(10),
+            &mut window(vec![
+                ("requests", Aggregation::Sum(Sum { sum: 3 })),
+                ("latency", Aggregation::Histogram(histogram)),
+                (
+                    "bytes",
+                    Aggregation::StatisticSet(StatisticSet {
+                        min: 1,
+                        max: 4,
+                        sum: 5,
+                        count: 2,
+                    }),
+                ),
+            ]),
+        );
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        sender.send(batch).await.unwrap();
+        drop(sender);
+        downstream.send_batches_forever(receiver).await;
+
+        let mut lines: Vec<String> = receive(&listener)
+            .await
+            .lines()
+            .map(ToString::to_string)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            vec![
+                "api_bytes_count:2|g|#host:a_b,service:test",
+                "api_bytes_max:4|g|#host:a_b,service:test",
+                "api_bytes_min:1|g|#host:a_b,service:test",
+                "api_bytes_sum:5|g|#host:a_b,service:test",
+                "api_latency:5|d|@0.25|#host:a_b,service:test",
+                "api_requests:3|c|#host:a_b,service:test",
+            ],
+            lines
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn datagrams_fit_the_mtu() {
+        let (listener, downstream) = listener().await;
+        let downstream = downstream.with_mtu(64);
+        let batch = DogstatsdBatcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(10),
+            &mut window(
+                ["a", "b", "c", "d", "e", "f", "g", "h"]
+                    .into_iter()
+                    .map(|name| (name, Aggregation::Sum(Sum { sum: 1 })))
+                    .collect(),
+            ),
+        );
+
+        let datagrams = downstream.datagrams(batch.clone());
+        assert!(1 < datagrams.len(), "{datagrams:?}");
+        for datagram in &datagrams {
+            assert!(datagram.len() <= 64, "{datagram}");
+        }
+        assert_eq!(
+            8,
+            datagrams
+                .iter()
+                .map(|datagram| datagram.lines().count())
+                .sum::<usize>()
+        );
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        sender.send(batch).await.unwrap();
+        drop(sender);
+        downstream.send_batches_forever(receiver).await;
+        for datagram in datagrams {
+            assert_eq!(datagram, receive(&listener).await);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_log::test(tokio::test)]
+    async fn unix_datagrams() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("dsd.socket");
+        let listener = tokio::net::UnixDatagram::bind(&path).unwrap();
+        let downstream = DogstatsdDownstream::connect_unix(&path).unwrap();
+
+        let (sender, receiver) = tokio::sync::mpsc::channel(1);
+        sender
+            .send(DogstatsdBatcher.batch_aggregations(
+                SystemTime::now(),
+                Duration::from_secs(10),
+                &mut window(vec![("requests", Aggregation::Sum(Sum { sum: 3 }))]),
+            ))
+            .await
+            .unwrap();
+        drop(sender);
+        downstream.send_batches_forever(receiver).await;
+
+        let mut buffer = vec![0; 8192];
+        let length = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"api_requests:3|c|#host:a_b", &buffer[..length]);
+    }
+}
@@ -1,14 +1,14 @@
 use std::{
     collections::HashMap,
-    pin::pin,
     time::{Duration, SystemTime},
 };
 
 use exponential_histogram::ExponentialHistogram;
-use futures::{Stream, StreamExt};
+use futures::Stream;
+use prost::Message;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::metadata::AsciiMetadataValue;
+use tonic::{codec::CompressionEncoding, metadata::AsciiMetadataValue};
 
 use crate::{
     aggregation::{
@@ -17,18 +17,35 @@ use crate::{
     pipeline::{AggregatedMetricsMap, AggregationBatcher, DimensionedMeasurementsMap},
     proto::{
         self,
-        goodmetrics::{metrics_client::MetricsClient, Datum, MetricsRequest},
+        goodmetrics::{metrics_client::MetricsClient, Datum, MetricsReply, MetricsRequest},
     },
     types::{Dimension, Distribution, Measurement, Name, Observation},
 };
 
-use super::{EpochTime, StdError};
+use super::{
+    compression::log_request_size,
+    delivery::{Delivery, DeliveryFailure, DeliveryQueue},
+    header_provider::DynamicHeaders,
+    spool::SpoolPosition,
+    DeliveryConfiguration, EpochTime, HeaderProvider, RequestLimits, Spool, StdError,
+};
+
+#[cfg(feature = "introspect")]
+use super::delivery_introspection::DeliveryIntrospection;
+
+#[cfg(feature = "introspect")]
+static INTROSPECTION: DeliveryIntrospection =
+    DeliveryIntrospection::new(&[("downstream", "goodmetrics")]);
 
 /// A downstream that sends metrics to a `goodmetricsd` or other goodmetrics grpc server.
 pub struct GoodmetricsDownstream<TChannel> {
     client: MetricsClient<TChannel>,
     header: Option<(&'static str, AsciiMetadataValue)>,
+    header_provider: Option<DynamicHeaders>,
     shared_dimensions: HashMap<String, proto::goodmetrics::Dimension>,
+    delivery_configuration: DeliveryConfiguration,
+    request_limits: RequestLimits,
+    compression: Option<CompressionEncoding>,
 }
 
 impl<TChannel> GoodmetricsDownstream<TChannel>
@@ -47,13 +64,48 @@ where
         GoodmetricsDownstream {
             client,
             header,
+            header_provider: None,
             shared_dimensions: shared_dimensions
                 .into_iter()
                 .map(|(k, v)| (k.into(), v.into().into()))
                 .collect(),
+            delivery_configuration: Default::default(),
+            request_limits: Default::default(),
+            compression: None,
         }
     }
 
+    /// Customize how failed batches are retried. By default, batches are retried for up to 2 minutes.
+    pub fn with_delivery_configuration(
+        mut self,
+        delivery_configuration: DeliveryConfiguration,
+    ) -> Self {
+        self.delivery_configuration = delivery_configuration;
+        self
+    }
+
+    /// Split batches into requests of at most this size. By default requests stay under 4 MiB.
+    pub fn with_request_limits(mut self, request_limits: RequestLimits) -> Self {
+        self.request_limits = request_limits;
+        self
+    }
+
+    /// Ask a provider for headers before each request, like for short-lived tokens. Its headers
+    /// replace the static header when they have the same name.
+    pub fn with_header_provider(mut self, header_provider: impl HeaderProvider) -> Self {
+        self.header_provider = Some(DynamicHeaders::new(header_provider));
+        self
+    }
+
+    /// Compress requests, like with `CompressionEncoding::Gzip`. Enable the matching goodmetrics
+    /// feature, `gzip` or `zstd`, and make sure your collector accepts it.
+    /// Request sizes before and after compression are logged at debug level.
+    pub fn with_compression(mut self, compression: CompressionEncoding) -> Self {
+        self.client = self.client.send_compressed(compression);
+        self.compression = Some(compression);
+        self
+    }
+
     /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
     pub async fn send_batches_forever(self, receiver: mpsc::Receiver<Vec<Datum>>) {
         self.send_metrics_stream_forever(ReceiverStream::new(receiver))
@@ -62,38 +114,12 @@ where
 
     /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
     pub async fn send_metrics_stream_forever(
-        mut self,
-        mut receiver: impl Stream<Item = Vec<Datum>> + Unpin,
+        self,
+        receiver: impl Stream<Item = Vec<Datum>> + Unpin,
     ) {
-        let mut interval = tokio::time::interval(Duration::from_millis(500));
-        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-        loop {
-            interval.tick().await;
-            // Send as quickly as possible while there are more batches
-            while let Some(batch) = pin!(&mut receiver).next().await {
-                let result = self
-                    .client
-                    .send_metrics(self.request(MetricsRequest {
-                        shared_dimensions: self.shared_dimensions.clone(),
-                        metrics: batch,
-                    }))
-                    .await;
-                match result {
-                    Ok(success) => {
-                        log::debug!("sent metrics: {success:?}");
-                    }
-                    Err(err) => {
-                        if !err.metadata().is_empty() {
-                            log::error!(
-                                "failed to send metrics: {err}. Metadata: {:?}",
-                                err.metadata()
-                            );
-                        }
-                        log::error!("failed to send metrics: {err:?}")
-                    }
-                };
-            }
-        }
+        DeliveryQueue::new(self.delivery_configuration.clone())
+            .deliver_forever(self, receiver)
+            .await
     }
 
     fn request<T>(&self, request: T) -> tonic::Request<T> {
@@ -103,9 +129,117 @@ where
         }
         request
     }
+
+    /// 1 attempt, with headers from the provider
+    async fn send_once(
+        &mut self,
+        request: MetricsRequest,
+    ) -> Result<tonic::Response<MetricsReply>, tonic::Status> {
+        #[cfg(feature = "introspect")]
+        let started = INTROSPECTION.request(
+            request.encoded_len(),
+            request
+                .metrics
+                .iter()
+                .map(|datum| datum.measurements.len())
+                .sum(),
+        );
+        let mut request = self.request(request);
+        let result = match &mut self.header_provider {
+            Some(header_provider) => header_provider.apply(&mut request).await,
+            None => Ok(()),
+        };
+        let result = match result {
+            Ok(()) => self.client.send_metrics(request).await,
+            Err(status) => Err(status),
+        };
+        #[cfg(feature = "introspect")]
+        INTROSPECTION.response(
+            started,
+            result
+                .as_ref()
+                .map_or_else(|status| status.code(), |_| tonic::Code::Ok),
+        );
+        result
+    }
+}
+
+impl<TChannel> Delivery for GoodmetricsDownstream<TChannel>
+where
+    TChannel: tonic::client::GrpcService<tonic::body::Body>,
+    TChannel::Error: Into<StdError>,
+    TChannel::ResponseBody: http_body::Body<Data = bytes::Bytes> + Send + 'static,
+    <TChannel::ResponseBody as http_body::Body>::Error: Into<StdError> + Send,
+{
+    type Batch = Vec<Datum>;
+    type Request = MetricsRequest;
+
+    #[cfg(feature = "introspect")]
+    fn introspection(&self) -> Option<&'static DeliveryIntrospection> {
+        Some(&INTROSPECTION)
+    }
+
+    fn prepare(&mut self, batch: Vec<Datum>) -> Vec<MetricsRequest> {
+        let overhead = MetricsRequest {
+            shared_dimensions: self.shared_dimensions.clone(),
+            metrics: vec![],
+        }
+        .encoded_len();
+        self.request_limits
+            .split(batch, overhead, |datum| datum.measurements.len())
+            .into_iter()
+            .map(|metrics| {
+                let request = MetricsRequest {
+                    shared_dimensions: self.shared_dimensions.clone(),
+                    metrics,
+                };
+                log_request_size(self.compression, &request);
+                request
+            })
+            .collect()
+    }
+
+    fn unspool_batch(&self, spool: &Spool) -> Option<(SpoolPosition, Vec<Datum>)> {
+        spool
+            .peek::<MetricsRequest>()
+            .map(|(position, request)| (position, request.metrics))
+    }
+
+    async fn send(&mut self, request: MetricsRequest) -> Result<(), DeliveryFailure> {
+        let retry = self.header_provider.is_some().then(|| request.clone());
+        let mut result = self.send_once(request).await;
+        if let (Err(status), Some(retry)) = (&result, retry) {
+            if status.code() == tonic::Code::Unauthenticated {
+                log::warn!("collector said unauthenticated - trying again with refreshed headers");
+                if let Some(header_provider) = &mut self.header_provider {
+                    header_provider.refresh();
+                }
+                result = self.send_once(retry).await;
+            }
+        }
+        match result {
+            Ok(success) => {
+                log::debug!("sent metrics: {success:?}");
+                Ok(())
+            }
+            Err(err) => {
+                if !err.metadata().is_empty() {
+                    log::error!(
+                        "failed to send metrics: {err}. Metadata: {:?}",
+                        err.metadata()
+                    );
+                }
+                log::error!("failed to send metrics: {err:?}");
+                Err((&err).into())
+            }
+        }
+    }
 }
 
-/// The default mapping from in-memory representation to goodmetrics wire representation
+/// The default mapping from in-memory representation to goodmetrics wire representation.
+///
+/// The goodmetrics protocol has no place for units and descriptions yet, so a MetadataRegistry
+/// does not apply here.
 pub struct GoodmetricsBatcher;
 
 impl AggregationBatcher for GoodmetricsBatcher {
@@ -124,6 +258,16 @@ impl AggregationBatcher for GoodmetricsBatcher {
             })
             .collect()
     }
+
+    /// Spooled as a MetricsRequest without shared dimensions. GoodmetricsDownstream adds its
+    /// own when it replays it.
+    fn spool_batch(&self, spool: &Spool, batch: Vec<Datum>) -> bool {
+        spool.push(&MetricsRequest {
+            shared_dimensions: Default::default(),
+            metrics: batch,
+        });
+        true
+    }
 }
 
 fn as_datums(
@@ -268,3 +412,369 @@ impl From<Centroid> for proto::goodmetrics::t_digest::Centroid {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+    use tokio_rustls::rustls::RootCertStore;
+    use tonic::{metadata::MetadataMap, Request, Response, Status};
+
+    use crate::{
+        allocator::AlwaysNewMetricsAllocator,
+        downstream::{
+            get_client, DeliveryConfiguration, GoodmetricsBatcher, GoodmetricsDownstream,
+            RequestLimits, Spool, TlsConfiguration,
+        },
+        pipeline::{Aggregator, DistributionMode, StreamSink},
+        proto::goodmetrics::{
+            metrics_client::MetricsClient,
+            metrics_server::{Metrics, MetricsServer},
+            Datum, MetricsReply, MetricsRequest,
+        },
+        MetricsFactory,
+    };
+
+    /// Fails with `Unavailable` until it runs out of failures.
+    /// Says `Unauthenticated` when the authorization header is not the required one.
+    #[derive(Default, Clone)]
+    struct FailingServer {
+        failures: Arc<AtomicUsize>,
+        required_authorization: Arc<Mutex<Option<String>>>,
+        received: Arc<Mutex<Vec<MetricsRequest>>>,
+        encodings: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    #[tonic::async_trait]
+    impl Metrics for FailingServer {
+        async fn send_metrics(
+            &self,
+            request: Request<MetricsRequest>,
+        ) -> Result<Response<MetricsReply>, Status> {
+            if self
+                .failures
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |f| f.checked_sub(1))
+                .is_ok()
+            {
+                return Err(Status::unavailable("failing on purpose"));
+            }
+            if let Some(required) = &*self.required_authorization.lock().expect("local mutex") {
+                if request
+                    .metadata()
+                    .get("authorization")
+                    .is_none_or(|authorization| authorization != required.as_str())
+                {
+                    return Err(Status::unauthenticated("token expired"));
+                }
+            }
+            self.encodings.lock().expect("local mutex").push(
+                request
+                    .metadata()
+                    .get("grpc-encoding")
+                    .and_then(|encoding| encoding.to_str().ok())
+                    .map(str::to_string),
+            );
+            self.received
+                .lock()
+                .expect("local mutex")
+                .push(request.into_inner());
+            Ok(Response::new(MetricsReply {}))
+        }
+    }
+
+    fn datum(metric: &str) -> Datum {
+        Datum {
+            metric: metric.to_string(),
+            ..Default::default()
+        }
+    }
+
+    async fn serve(service: MetricsServer<FailingServer>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("can bind a local port");
+        let address = listener.local_addr().expect("bound address");
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(service)
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+        address
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn retries_through_a_collector_outage() {
+        let server = FailingServer::default();
+        server.failures.store(3, Ordering::Relaxed);
+        let address = serve(MetricsServer::new(server.clone())).await;
+
+        let mut delivery_configuration = DeliveryConfiguration::default();
+        delivery_configuration.initial_backoff(Duration::from_millis(5));
+        let downstream = GoodmetricsDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::with_roots(RootCertStore::empty()),
+                MetricsClient::with_origin,
+            )
+            .expect("can make a client"),
+            None,
+            [("shared", "dimension")],
+        )
+        .with_delivery_configuration(delivery_configuration);
+
+        let (sender, receiver) = mpsc::channel(8);
+        sender.send(vec![datum("a")]).await.expect("channel open");
+        sender.send(vec![datum("b")]).await.expect("channel open");
+        drop(sender);
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_batches_forever(receiver),
+        )
+        .await
+        .expect("the downstream should finish when its batches are delivered");
+
+        let received = server.received.lock().expect("local mutex");
+        assert_eq!(
+            vec!["a", "b"],
+            received
+                .iter()
+                .map(|request| request.metrics[0].metric.as_str())
+                .collect::<Vec<_>>(),
+            "both batches should arrive, in order"
+        );
+        assert!(received
+            .iter()
+            .all(|request| request.shared_dimensions.contains_key("shared")));
+        assert_eq!(0, server.failures.load(Ordering::Relaxed));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn unauthenticated_refreshes_headers() {
+        let server = FailingServer::default();
+        *server.required_authorization.lock().expect("local mutex") = Some("Bearer 2".to_string());
+        let address = serve(MetricsServer::new(server.clone())).await;
+
+        let refreshes = Arc::new(Mutex::new(Vec::new()));
+        let record = refreshes.clone();
+        let token = AtomicUsize::new(1);
+        let downstream = GoodmetricsDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::with_roots(RootCertStore::empty()),
+                MetricsClient::with_origin,
+            )
+            .expect("can make a client"),
+            None,
+            [("shared", "dimension")],
+        )
+        .with_header_provider(move |refresh: bool| {
+            record.lock().expect("local mutex").push(refresh);
+            if refresh {
+                token.fetch_add(1, Ordering::Relaxed);
+            }
+            let token = token.load(Ordering::Relaxed);
+            async move {
+                let mut headers = MetadataMap::new();
+                headers.insert(
+                    "authorization",
+                    format!("Bearer {token}").parse().expect("valid header"),
+                );
+                headers.insert("x-tenant", "test".parse().expect("valid header"));
+                Ok(headers)
+            }
+        });
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([
+                vec![datum("a")],
+                vec![datum("b")],
+            ])),
+        )
+        .await
+        .expect("the downstream should finish when its batches are delivered");
+
+        assert_eq!(
+            vec!["a", "b"],
+            server
+                .received
+                .lock()
+                .expect("local mutex")
+                .iter()
+                .map(|request| request.metrics[0].metric.as_str())
+                .collect::<Vec<_>>(),
+            "the rejected batch is sent again with the new token"
+        );
+        assert_eq!(
+            vec![false, true, false],
+            *refreshes.lock().expect("local mutex")
+        );
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test_log::test(tokio::test)]
+    async fn gzip() {
+        use tonic::codec::CompressionEncoding;
+
+        let server = FailingServer::default();
+        let address =
+            serve(MetricsServer::new(server.clone()).accept_compressed(CompressionEncoding::Gzip))
+                .await;
+        let downstream = GoodmetricsDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::with_roots(RootCertStore::empty()),
+                MetricsClient::with_origin,
+            )
+            .expect("can make a client"),
+            None,
+            [("shared", "dimension")],
+        )
+        .with_compression(CompressionEncoding::Gzip);
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([vec![datum("a")]])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+
+        assert_eq!(
+            vec![Some("gzip".to_string())],
+            *server.encodings.lock().expect("local mutex")
+        );
+        assert_eq!(1, server.received.lock().expect("local mutex").len());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn oversized_batches_are_split() {
+        let server = FailingServer::default();
+        let address = serve(MetricsServer::new(server.clone())).await;
+        let mut request_limits = RequestLimits::default();
+        request_limits.max_bytes(256);
+        let downstream = GoodmetricsDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::with_roots(RootCertStore::empty()),
+                MetricsClient::with_origin,
+            )
+            .expect("can make a client"),
+            None,
+            [("shared", "dimension")],
+        )
+        .with_request_limits(request_limits);
+
+        let batch: Vec<Datum> = (0..40).map(|i| datum(&format!("metric_{i}"))).collect();
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([batch])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+
+        let received = server.received.lock().expect("local mutex");
+        assert!(1 < received.len(), "the batch should be split");
+        for request in received.iter() {
+            assert!(
+                prost::Message::encoded_len(request) <= 256,
+                "{}",
+                prost::Message::encoded_len(request)
+            );
+            assert!(request.shared_dimensions.contains_key("shared"));
+        }
+        assert_eq!(
+            (0..40).map(|i| format!("metric_{i}")).collect::<Vec<_>>(),
+            received
+                .iter()
+                .flat_map(|request| request.metrics.iter().map(|datum| datum.metric.clone()))
+                .collect::<Vec<_>>(),
+            "every datum arrives once, in order"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn windows_that_overflow_the_channel_are_replayed_from_the_spool() {
+        let directory = tempfile::tempdir().expect("can make a directory");
+        let spool = Spool::open(directory.path(), 1 << 20).expect("can open a spool");
+        let (sink, metrics_receiver) = StreamSink::new();
+        let metrics_factory: MetricsFactory<AlwaysNewMetricsAllocator, _> =
+            MetricsFactory::new(sink);
+        let aggregator = Aggregator::new(metrics_receiver, DistributionMode::Histogram)
+            .with_spool(spool.clone());
+        // Nothing reads the channel yet, so it is full after the first window
+        let (sender, receiver) = mpsc::channel(1);
+        let aggregating = tokio::spawn(aggregator.aggregate_metrics_forever(
+            Duration::from_millis(10),
+            sender,
+            GoodmetricsBatcher,
+        ));
+
+        let mut windows = Vec::new();
+        while windows.len() < 3 || spool.is_empty() {
+            assert!(windows.len() < 100, "windows should be spooled");
+            let window = format!("w{}", windows.len());
+            metrics_factory
+                .record_scope(window.clone())
+                .measurement("ran", 1);
+            windows.push(window);
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+
+        let server = FailingServer::default();
+        let address = serve(MetricsServer::new(server.clone())).await;
+        let mut delivery_configuration = DeliveryConfiguration::default();
+        delivery_configuration.aggregator_spool(spool.clone());
+        let downstream = GoodmetricsDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::with_roots(RootCertStore::empty()),
+                MetricsClient::with_origin,
+            )
+            .expect("can make a client"),
+            None,
+            [("shared", "dimension")],
+        )
+        .with_delivery_configuration(delivery_configuration);
+        let sending = tokio::spawn(downstream.send_batches_forever(receiver));
+
+        let received_metrics = |received: &[MetricsRequest]| {
+            received
+                .iter()
+                .flat_map(|request| &request.metrics)
+                .map(|datum| datum.metric.clone())
+                .collect::<Vec<_>>()
+        };
+        // A slow test thread can put 2 windows' metrics in 1 window, so count metrics
+        let received = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let received = server.received.lock().expect("local mutex").clone();
+                if windows.len() <= received_metrics(&received).len() {
+                    return received;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("every window should arrive");
+        aggregating.abort();
+        sending.abort();
+
+        assert_eq!(
+            windows,
+            received_metrics(&received),
+            "the window in the channel goes first, then the spooled ones in order"
+        );
+        assert!(received
+            .iter()
+            .all(|request| request.shared_dimensions.contains_key("shared")));
+        assert!(spool.is_empty());
+    }
+}
@@ -0,0 +1,100 @@
+use std::{future::Future, sync::Arc};
+
+use futures::future::BoxFuture;
+use tonic::metadata::MetadataMap;
+
+/// Supplies the headers for each request to a grpc downstream, like short-lived bearer tokens.
+///
+/// It is asked before every request. When a collector answers `Unauthenticated`, it is asked
+/// again with `refresh` set and the request is tried once more, so a cached token can be
+/// replaced. An error fails the request like a collector's status would.
+///
+/// Closures work too:
+/// ```
+/// # use tonic::metadata::MetadataMap;
+/// # async fn fetch_token(refresh: bool) -> String { "token".to_string() }
+/// let provider = |refresh: bool| async move {
+///     let mut headers = MetadataMap::new();
+///     let token = fetch_token(refresh).await;
+///     headers.insert(
+///         "authorization",
+///         format!("Bearer {token}")
+///             .parse()
+///             .map_err(|_| tonic::Status::unauthenticated("bad token"))?,
+///     );
+///     headers.insert("x-tenant", "my-team".parse().expect("valid header"));
+///     Ok::<_, tonic::Status>(headers)
+/// };
+/// # fn takes_a_provider(_: impl goodmetrics::downstream::HeaderProvider) {}
+/// # takes_a_provider(provider);
+/// ```
+pub trait HeaderProvider: Send + Sync + 'static {
+    /// Headers for the next request. `refresh` is true after the collector said
+    /// `Unauthenticated`, when a cached token should not be reused.
+    fn headers(
+        &self,
+        refresh: bool,
+    ) -> impl Future<Output = Result<MetadataMap, tonic::Status>> + Send;
+}
+
+impl<F, TFuture> HeaderProvider for F
+where
+    F: Fn(bool) -> TFuture + Send + Sync + 'static,
+    TFuture: Future<Output = Result<MetadataMap, tonic::Status>> + Send,
+{
+    fn headers(
+        &self,
+        refresh: bool,
+    ) -> impl Future<Output = Result<MetadataMap, tonic::Status>> + Send {
+        self(refresh)
+    }
+}
+
+type ProvideHeaders =
+    Arc<dyn Fn(bool) -> BoxFuture<'static, Result<MetadataMap, tonic::Status>> + Send + Sync>;
+
+/// A HeaderProvider for a downstream, which remembers when it needs a refresh
+#[derive(Clone)]
+pub(crate) struct DynamicHeaders {
+    provide: ProvideHeaders,
+    refresh: bool,
+}
+
+impl DynamicHeaders {
+    pub(crate) fn new(provider: impl HeaderProvider) -> Self {
+        let provider = Arc::new(provider);
+        Self {
+            provide: Arc::new(move |refresh| {
+                let provider = provider.clone();
+                Box::pin(async move { provider.headers(refresh).await })
+            }),
+            refresh: false,
+        }
+    }
+
+    /// Ask for a refresh on the next request
+    pub(crate) fn refresh(&mut self) {
+        self.refresh = true
+    }
+
+    /// Add the provider's headers to a request, replacing any with the same name
+    pub(crate) async fn apply<T>(
+        &mut self,
+        request: &mut tonic::Request<T>,
+    ) -> Result<(), tonic::Status> {
+        let refresh = std::mem::take(&mut self.refresh);
+        let provided = (self.provide)(refresh).await?;
+        let mut headers = std::mem::take(request.metadata_mut()).into_headers();
+        headers.extend(provided.into_headers());
+        *request.metadata_mut() = MetadataMap::from_headers(headers);
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for DynamicHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicHeaders")
+            .field("refresh", &self.refresh)
+            .finish()
+    }
+}
@@ -0,0 +1,672 @@
+use std::{
+    fmt::Write,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE},
+    HeaderMap, Method, StatusCode, Uri,
+};
+use tokio::{io::AsyncWriteExt, sync::mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    aggregation::Cumulative,
+    pipeline::{AggregatedMetricsMap, AggregationBatcher, DimensionPosition},
+    types::{Dimension, Name},
+};
+
+use super::{
+    delivery::{Delivery, DeliveryFailure, DeliveryQueue},
+    otlp_http_downstream::retry_after,
+    DeliveryConfiguration, EpochTime, HttpChannelType, StdError,
+};
+
+/// One line of InfluxDB line protocol: a measurement, its tags and fields, and a timestamp.
+/// These come from InfluxBatcher and go to InfluxDownstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InfluxPoint {
+    measurement: String,
+    tags: Vec<(String, String)>,
+    fields: Vec<(String, InfluxField)>,
+    timestamp_nanos: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InfluxField {
+    Integer(i64),
+    Float(f64),
+}
+
+/// Maps each window's aggregations to InfluxDB points. The metric name is the measurement,
+/// dimensions are tags, and every measurement of the metric at that position is a field
+/// (or a few) on the same point:
+/// * Sums are an integer field.
+/// * StatisticSets are `_min`, `_max`, `_sum` and `_count` fields.
+/// * Histograms are a `_bucket_<bucket>` count field per bucket and a `_count` field.
+///   Exponential histograms add `_sum`, `_min` and `_max`.
+/// * t-digests are `_p<quantile>` fields, like `_p99.9`, with `_sum` and `_count`.
+///
+/// Windows are not accumulated: each point is what happened in 1 window.
+#[derive(Debug, Default, Clone)]
+pub struct InfluxBatcher {
+    tdigest_quantiles: Option<Vec<f64>>,
+}
+
+impl InfluxBatcher {
+    /// Choose the quantiles reported for t-digests (default 0.5, 0.9, 0.99, 0.999)
+    pub fn with_tdigest_quantiles(mut self, quantiles: Vec<f64>) -> Self {
+        self.tdigest_quantiles = Some(quantiles);
+        self
+    }
+}
+
+impl AggregationBatcher for InfluxBatcher {
+    type TBatch = Vec<InfluxPoint>;
+
+    fn batch_aggregations(
+        &mut self,
+        now: SystemTime,
+        _covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+    ) -> Self::TBatch {
+        let timestamp_nanos = now.nanos_since_epoch();
+        let mut batch = Vec::new();
+        for (name, mut dimensioned_measurements) in aggregations.drain() {
+            for (dimension_position, mut measurements) in dimensioned_measurements.drain() {
+                let mut fields = Vec::new();
+                for (measurement_name, aggregation) in measurements.drain() {
+                    add_fields(
+                        &mut fields,
+                        self.tdigest_quantiles.as_deref(),
+                        measurement_name.as_str(),
+                        Cumulative::new(aggregation),
+                    );
+                }
+                if fields.is_empty() {
+                    continue;
+                }
+                // Sorted fields compress better and make the lines easier to read
+                fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+                batch.push(InfluxPoint {
+                    measurement: name.to_string(),
+                    tags: as_tags(&dimension_position),
+                    fields,
+                    timestamp_nanos,
+                });
+            }
+        }
+        batch
+    }
+}
+
+/// A window is a cumulative that has only seen 1 window
+fn add_fields(
+    fields: &mut Vec<(String, InfluxField)>,
+    tdigest_quantiles: Option<&[f64]>,
+    measurement: &str,
+    window: Cumulative,
+) {
+    match window {
+        Cumulative::Sum(sum) => add_integer(fields, measurement.to_string(), sum),
+        Cumulative::StatisticSet { window, .. } => {
+            add_integer(fields, format!("{measurement}_min"), window.min);
+            add_integer(fields, format!("{measurement}_max"), window.max);
+            add_integer(fields, format!("{measurement}_sum"), window.sum);
+            add_integer(fields, format!("{measurement}_count"), window.count as i64);
+        }
+        Cumulative::Histogram(buckets) => {
+            let mut total = 0;
+            for (bucket, count) in buckets {
+                total += count;
+                add_integer(
+                    fields,
+                    format!("{measurement}_bucket_{bucket}"),
+                    count as i64,
+                );
+            }
+            add_integer(fields, format!("{measurement}_count"), total as i64);
+        }
+        Cumulative::ExponentialHistogram(histogram) => {
+            let mut total = 0;
+            for (upper_bound, count) in histogram.buckets.upper_bound_counts() {
+                total += count;
+                add_integer(
+                    fields,
+                    format!("{measurement}_bucket_{upper_bound}"),
+                    count as i64,
+                );
+            }
+            add_integer(fields, format!("{measurement}_count"), total as i64);
+            if 0 < total {
+                add_float(fields, format!("{measurement}_sum"), histogram.sum);
+                add_float(fields, format!("{measurement}_min"), histogram.min);
+                add_float(fields, format!("{measurement}_max"), histogram.max);
+            }
+        }
+        Cumulative::TDigest(digest) => {
+            if digest.is_empty() {
+                return;
+            }
+            for quantile in tdigest_quantiles.unwrap_or(&[0.5, 0.9, 0.99, 0.999]) {
+                add_float(
+                    fields,
+                    // 0.999 -> p99.9, without the float noise
+                    format!("{measurement}_p{}", (quantile * 1000.0).round() / 10.0),
+                    digest.estimate_quantile(*quantile),
+                );
+            }
+            add_float(fields, format!("{measurement}_sum"), digest.sum());
+            add_integer(
+                fields,
+                format!("{measurement}_count"),
+                digest.count() as i64,
+            );
+        }
+    }
+}
+
+fn add_integer(fields: &mut Vec<(String, InfluxField)>, field: String, value: i64) {
+    fields.push((field, InfluxField::Integer(value)));
+}
+
+/// Line protocol has no NaN or infinity
+fn add_float(fields: &mut Vec<(String, InfluxField)>, field: String, value: f64) {
+    if value.is_finite() {
+        fields.push((field, InfluxField::Float(value)));
+    }
+}
+
+/// Influx does not accept empty tag values, so those are left off
+fn as_tags(dimension_position: &DimensionPosition) -> Vec<(String, String)> {
+    dimension_position
+        .iter()
+        .map(|(name, dimension)| (name.to_string(), dimension.to_string()))
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+#[derive(Debug)]
+enum Destination {
+    Http {
+        client: Box<HttpChannelType>,
+        uri: Uri,
+    },
+    File(PathBuf),
+}
+
+/// Writes InfluxDB line protocol, either to an InfluxDB 2.x style `/api/v2/write` endpoint or
+/// to the end of a local file, like for telegraf to tail.
+///
+/// Failed writes are retried according to the DeliveryConfiguration.
+/// ```no_run
+/// # use std::time::Duration;
+/// # use goodmetrics::default_gauge_factory;
+/// # use goodmetrics::downstream::{get_http_client, InfluxBatcher, InfluxDownstream, TlsConfiguration};
+/// # async fn example() {
+/// # let tls = TlsConfiguration::with_roots(tokio_rustls::rustls::RootCertStore::empty());
+/// let downstream = InfluxDownstream::http(
+///     get_http_client(&tls).expect("can make a client"),
+///     "http://localhost:8086",
+///     "my-org",
+///     "my-bucket",
+/// )
+/// .expect("valid endpoint")
+/// .with_header(
+///     hyper::header::AUTHORIZATION,
+///     hyper::header::HeaderValue::from_static("Token my-token"),
+/// );
+/// let (sender, receiver) = tokio::sync::mpsc::channel(128);
+/// tokio::spawn(downstream.send_batches_forever(receiver));
+/// tokio::spawn(default_gauge_factory().clone().report_gauges_forever(
+///     Duration::from_secs(10),
+///     sender,
+///     InfluxBatcher::default(),
+/// ));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct InfluxDownstream {
+    destination: Destination,
+    headers: HeaderMap,
+    shared_tags: Vec<(String, String)>,
+    delivery_configuration: DeliveryConfiguration,
+}
+
+impl InfluxDownstream {
+    /// Write to an InfluxDB at endpoint, like `http://localhost:8086`.
+    /// `/api/v2/write` is added for you, with the org and bucket to write to.
+    pub fn http(
+        client: HttpChannelType,
+        endpoint: &str,
+        org: &str,
+        bucket: &str,
+    ) -> Result<Self, StdError> {
+        let uri = Uri::from_str(&format!(
+            "{}/api/v2/write?org={}&bucket={}&precision=ns",
+            endpoint.trim_end_matches('/'),
+            query_escape(org),
+            query_escape(bucket),
+        ))?;
+        Ok(Self::new(Destination::Http {
+            client: Box::new(client),
+            uri,
+        }))
+    }
+
+    /// Append lines to a file. It is created if it does not exist.
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::new(Destination::File(path.into()))
+    }
+
+    fn new(destination: Destination) -> Self {
+        Self {
+            destination,
+            headers: Default::default(),
+            shared_tags: Default::default(),
+            delivery_configuration: Default::default(),
+        }
+    }
+
+    /// Add a header to every request, like `Authorization: Token <your token>`
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Tag every point written through this downstream with these dimensions
+    pub fn with_shared_dimensions(
+        mut self,
+        shared_dimensions: impl IntoIterator<Item = (impl Into<Name>, impl Into<Dimension>)>,
+    ) -> Self {
+        self.shared_tags = as_tags(
+            &shared_dimensions
+                .into_iter()
+                .map(|(n, d)| (n.into(), d.into()))
+                .collect(),
+        );
+        self
+    }
+
+    /// Customize how failed batches are retried. By default, batches are retried for up to 2 minutes.
+    pub fn with_delivery_configuration(
+        mut self,
+        delivery_configuration: DeliveryConfiguration,
+    ) -> Self {
+        self.delivery_configuration = delivery_configuration;
+        self
+    }
+
+    /// Spawn this on a tokio runtime to write your metrics
+    pub async fn send_batches_forever(self, receiver: mpsc::Receiver<Vec<InfluxPoint>>) {
+        self.send_points_stream_forever(ReceiverStream::new(receiver))
+            .await;
+    }
+
+    /// Spawn this on a tokio runtime to write your metrics
+    pub async fn send_points_stream_forever(
+        self,
+        receiver: impl Stream<Item = Vec<InfluxPoint>> + Unpin,
+    ) {
+        DeliveryQueue::new(self.delivery_configuration.clone())
+            .deliver_forever(self, receiver)
+            .await
+    }
+
+    /// `measurement,tag=value,tag=value field=1i,field=2.5 timestamp`, with shared tags merged in
+    fn write_line(&self, out: &mut String, point: &InfluxPoint) {
+        out.push_str(&escape(&point.measurement, &[',', ' ']));
+        let mut tags: Vec<&(String, String)> = point
+            .tags
+            .iter()
+            .chain(
+                self.shared_tags
+                    .iter()
+                    .filter(|(shared, _)| point.tags.iter().all(|(tag, _)| tag != shared)),
+            )
+            .collect();
+        // Influx asks for tags sorted by key
+        tags.sort();
+        for (tag, value) in tags {
+            out.push(',');
+            out.push_str(&escape(tag, &[',', '=', ' ']));
+            out.push('=');
+            out.push_str(&escape(value, &[',', '=', ' ']));
+        }
+        for (i, (field, value)) in point.fields.iter().enumerate() {
+            out.push(if i == 0 { ' ' } else { ',' });
+            out.push_str(&escape(field, &[',', '=', ' ']));
+            let _ = match value {
+                InfluxField::Integer(i) => write!(out, "={i}i"),
+                InfluxField::Float(f) => write!(out, "={f}"),
+            };
+        }
+        let _ = writeln!(out, " {}", point.timestamp_nanos);
+    }
+}
+
+impl Delivery for InfluxDownstream {
+    type Batch = Vec<InfluxPoint>;
+    type Request = Bytes;
+
+    fn prepare(&mut self, batch: Vec<InfluxPoint>) -> Vec<Bytes> {
+        let mut lines = String::new();
+        for point in &batch {
+            self.write_line(&mut lines, point);
+        }
+        vec![lines.into()]
+    }
+
+    async fn send(&mut self, request: Bytes) -> Result<(), DeliveryFailure> {
+        let (client, uri) = match &self.destination {
+            Destination::Http { client, uri } => (client, uri),
+            Destination::File(path) => {
+                let result = async {
+                    let mut file = tokio::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .await?;
+                    file.write_all(&request).await?;
+                    // A dropped tokio File finishes its last write in the background
+                    file.flush().await
+                }
+                .await;
+                return result.map_err(|e| {
+                    log::error!("failed to write metrics to {path:?}: {e}");
+                    DeliveryFailure::retryable()
+                });
+            }
+        };
+        let mut http_request = hyper::Request::new(Full::new(request));
+        *http_request.method_mut() = Method::POST;
+        *http_request.uri_mut() = uri.clone();
+        *http_request.headers_mut() = self.headers.clone();
+        http_request.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+
+        let response = match client.request(http_request).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("failed to send metrics: {e:?}");
+                return Err(DeliveryFailure::retryable());
+            }
+        };
+        let status = response.status();
+        if status.is_success() {
+            log::debug!("sent metrics: {status}");
+            return Ok(());
+        }
+        let retry_after = retry_after(response.headers());
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map(|body| body.to_bytes())
+            .unwrap_or_default();
+        log::error!(
+            "failed to send metrics: {status}: {}",
+            String::from_utf8_lossy(&body)
+        );
+        match status {
+            StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => {
+                Err(DeliveryFailure::retryable().with_retry_after(retry_after))
+            }
+            _ => Err(DeliveryFailure::permanent()),
+        }
+    }
+}
+
+/// Backslash the special characters, and backslashes so they don't escape what follows them.
+/// Line protocol cannot escape a newline, so it becomes a literal `\n`.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c if special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode everything but the unreserved characters
+fn query_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                escaped.push(byte as char)
+            }
+            byte => {
+                let _ = write!(escaped, "%{byte:02X}");
+            }
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{service::service_fn, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::RootCertStore;
+
+    use crate::{
+        aggregation::{Aggregation, Histogram, StatisticSet, Sum},
+        downstream::{get_http_client, DeliveryConfiguration, TlsConfiguration},
+        pipeline::{AggregatedMetricsMap, AggregationBatcher},
+        types::{Dimension, Name},
+    };
+
+    use super::{InfluxBatcher, InfluxDownstream, InfluxPoint};
+
+    fn window(
+        dimensions: Vec<(&'static str, Dimension)>,
+        measurements: Vec<(&'static str, Aggregation)>,
+    ) -> AggregatedMetricsMap {
+        HashMap::from([(
+            Name::from("api, v2"),
+            HashMap::from([(
+                dimensions
+                    .into_iter()
+                    .map(|(name, dimension)| (Name::from(name), dimension))
+                    .collect::<BTreeMap<_, _>>(),
+                measurements
+                    .into_iter()
+                    .map(|(name, aggregation)| (Name::from(name), aggregation))
+                    .collect(),
+            )]),
+        )])
+    }
+
+    fn batch(window: &mut AggregatedMetricsMap) -> Vec<InfluxPoint> {
+        InfluxBatcher::default().batch_aggregations(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            Duration::from_secs(1),
+            window,
+        )
+    }
+
+    fn lines(downstream: &InfluxDownstream, batch: &[InfluxPoint]) -> String {
+        let mut out = String::new();
+        for point in batch {
+            downstream.write_line(&mut out, point);
+        }
+        out
+    }
+
+    #[test_log::test]
+    fn aggregations_become_fields() {
+        let mut histogram = Histogram::default();
+        histogram.accumulate(5);
+        histogram.accumulate(5);
+        histogram.accumulate(100);
+        let points = batch(&mut window(
+            vec![("host", "a".into()), ("empty", "".into())],
+            vec![
+                ("requests", Aggregation::Sum(Sum { sum: 3 })),
+                ("latency", Aggregation::Histogram(histogram)),
+                (
+                    "bytes",
+                    Aggregation::StatisticSet(StatisticSet {
+                        min: 1,
+                        max: 4,
+                        sum: 5,
+                        count: 2,
+                    }),
+                ),
+            ],
+        ));
+        let downstream =
+            InfluxDownstream::file("unused").with_shared_dimensions([("service", "test")]);
+        assert_eq!(
+            "api\\,\\ v2,host=a,service=test bytes_count=2i,bytes_max=4i,bytes_min=1i,bytes_sum=5i,\
+            latency_bucket_100=1i,latency_bucket_5=2i,latency_count=3i,requests=3i 1000000000\n",
+            lines(&downstream, &points)
+        );
+    }
+
+    #[test_log::test]
+    fn tags_and_fields_are_escaped() {
+        let points = batch(&mut window(
+            vec![
+                ("a key", "x=1,y=2".into()),
+                ("b\nkey", "line 1\nline 2".into()),
+                ("c", "a\\,b".into()),
+                ("path", "C:\\dir\\".into()),
+            ],
+            vec![("a,b=c d", Aggregation::Sum(Sum { sum: -1 }))],
+        ));
+        assert_eq!(
+            "api\\,\\ v2,a\\ key=x\\=1\\,y\\=2,b\\nkey=line\\ 1\\nline\\ 2,c=a\\\\\\,b,path=C:\\\\dir\\\\ a\\,b\\=c\\ d=-1i 1000000000\n",
+            lines(&InfluxDownstream::file("unused"), &points)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn lines_append_to_a_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("metrics.lp");
+        let batches = [
+            batch(&mut window(
+                vec![],
+                vec![("requests", Aggregation::Sum(Sum { sum: 1 }))],
+            )),
+            batch(&mut window(
+                vec![],
+                vec![("requests", Aggregation::Sum(Sum { sum: 2 }))],
+            )),
+        ];
+        InfluxDownstream::file(&path)
+            .send_points_stream_forever(futures::stream::iter(batches))
+            .await;
+
+        assert_eq!(
+            "api\\,\\ v2 requests=1i 1000000000\napi\\,\\ v2 requests=2i 1000000000\n",
+            std::fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn http_writes_are_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(
+            vec![StatusCode::SERVICE_UNAVAILABLE, StatusCode::NO_CONTENT].into_iter(),
+        ));
+        let record = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let record = record.clone();
+                let statuses = statuses.clone();
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
+                        let record = record.clone();
+                        let status = statuses.lock().unwrap().next().unwrap_or(StatusCode::OK);
+                        async move {
+                            let uri = request.uri().to_string();
+                            let authorization = request.headers()["authorization"]
+                                .to_str()
+                                .unwrap()
+                                .to_string();
+                            let body = request.into_body().collect().await.unwrap().to_bytes();
+                            record.lock().unwrap().push((uri, authorization, body));
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .header("retry-after", "0")
+                                    .body(Full::new(Bytes::new()))
+                                    .unwrap(),
+                            )
+                        }
+                    }),
+                ));
+            }
+        });
+
+        let mut configuration = DeliveryConfiguration::default();
+        configuration.initial_backoff(Duration::from_millis(1));
+        let downstream = InfluxDownstream::http(
+            get_http_client(&TlsConfiguration::with_roots(RootCertStore::empty())).unwrap(),
+            &format!("http://{address}/"),
+            "my org",
+            "metrics",
+        )
+        .unwrap()
+        .with_header(
+            hyper::header::AUTHORIZATION,
+            hyper::header::HeaderValue::from_static("Token secret"),
+        )
+        .with_delivery_configuration(configuration);
+        downstream
+            .send_points_stream_forever(futures::stream::iter([batch(&mut window(
+                vec![("host", "a".into())],
+                vec![("requests", Aggregation::Sum(Sum { sum: 1 }))],
+            ))]))
+            .await;
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(2, received.len(), "503 should be retried");
+        let (uri, authorization, body) = &received[1];
+        assert_eq!(
+            "/api/v2/write?org=my%20org&bucket=metrics&precision=ns",
+            uri
+        );
+        assert_eq!("Token secret", authorization);
+        assert_eq!(
+            "api\\,\\ v2,host=a requests=1i 1000000000\n",
+            String::from_utf8_lossy(body)
+        );
+    }
+}
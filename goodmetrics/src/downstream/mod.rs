@@ -3,12 +3,41 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
 mod channel_connection;
+mod compression;
+mod delivery;
+#[cfg(feature = "introspect")]
+mod delivery_introspection;
+mod dogstatsd_downstream;
 mod goodmetrics_downstream;
+mod header_provider;
+mod influx_downstream;
 mod opentelemetry_downstream;
+mod otlp_http_downstream;
+mod otlp_resource;
+mod prometheus_downstream;
+mod request_limits;
+mod spool;
+mod tls;
 
-pub use channel_connection::{get_client, ChannelType};
+pub use channel_connection::{get_client, get_http_client, ChannelType, HttpChannelType};
+pub use delivery::{DeliveryConfiguration, OverflowPolicy};
+pub use dogstatsd_downstream::{DogstatsdBatcher, DogstatsdDownstream, DogstatsdMetric};
 pub use goodmetrics_downstream::{GoodmetricsBatcher, GoodmetricsDownstream};
-pub use opentelemetry_downstream::{OpenTelemetryDownstream, OpentelemetryBatcher};
+pub use header_provider::HeaderProvider;
+pub use influx_downstream::{InfluxBatcher, InfluxDownstream, InfluxPoint};
+pub use opentelemetry_downstream::{
+    ConfiguredOpentelemetryBatcher, OpenTelemetryDownstream, OpentelemetryBatcher, PartialSuccess,
+    ScopedOpentelemetryBatcher, TDigestRepresentation, Temporality,
+};
+pub use otlp_http_downstream::{OtlpHttpDownstream, OtlpHttpEncoding};
+pub use otlp_resource::OtlpResource;
+pub use prometheus_downstream::{
+    ExpositionFormat, PrometheusBatcher, PrometheusDownstream, PrometheusSeries,
+    StatisticSetRepresentation,
+};
+pub use request_limits::RequestLimits;
+pub use spool::Spool;
+pub use tls::TlsConfiguration;
 
 pub(crate) type StdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 
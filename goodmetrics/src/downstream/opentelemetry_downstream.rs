@@ -1,60 +1,128 @@
 use std::{
     cmp::Reverse,
-    collections::BinaryHeap,
-    pin::pin,
+    collections::{hash_map, BinaryHeap, HashMap},
+    sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use exponential_histogram::ExponentialHistogram;
 use futures::{Stream, StreamExt};
+use prost::Message;
 use tokio::sync::mpsc;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue};
-
-use crate::{
-    aggregation::Histogram,
-    pipeline::AggregatedMetricsMap,
-    proto::opentelemetry::metrics::v1::{
-        exponential_histogram_data_point::Buckets, ExponentialHistogramDataPoint,
-    },
+use tonic::{
+    codec::CompressionEncoding,
+    metadata::{AsciiMetadataKey, AsciiMetadataValue},
 };
+
 use crate::{
-    aggregation::Sum,
-    pipeline::AggregationBatcher,
-    proto::opentelemetry::{metrics::v1::Gauge, resource::v1::Resource},
+    aggregation::Sum, pipeline::AggregationBatcher, proto::opentelemetry::metrics::v1::Gauge,
 };
 use crate::{
     aggregation::{bucket_10_below_2_sigfigs, Aggregation, StatisticSet},
-    pipeline::{DimensionPosition, DimensionedMeasurementsMap},
+    pipeline::{DimensionPosition, DimensionedMeasurementsMap, ExemplarsMap},
     proto::opentelemetry::{
         self,
         collector::metrics::v1::{
-            metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest,
+            metrics_service_client::MetricsServiceClient, ExportMetricsPartialSuccess,
+            ExportMetricsServiceRequest, ExportMetricsServiceResponse,
         },
         common::v1::{any_value::Value, AnyValue, InstrumentationScope, KeyValue},
         metrics::v1::{
-            AggregationTemporality, DataPointFlags, HistogramDataPoint, Metric, ResourceMetrics,
-            ScopeMetrics,
+            metric::Data, AggregationTemporality, DataPointFlags, HistogramDataPoint, Metric,
+            ResourceMetrics, ScopeMetrics,
         },
     },
     types::{Dimension, Name},
+    MetadataRegistry, MetricMetadata,
+};
+use crate::{
+    aggregation::{
+        Cumulative, Exemplar, ExemplarReservoir, ExponentialBuckets, Histogram, TDigest,
+    },
+    pipeline::AggregatedMetricsMap,
+    proto::opentelemetry::metrics::v1::{
+        exponential_histogram_data_point::Buckets, summary_data_point::ValueAtQuantile,
+        ExponentialHistogramDataPoint, SummaryDataPoint,
+    },
+};
+
+use super::{
+    compression::log_request_size,
+    delivery::{Delivery, DeliveryFailure, DeliveryQueue},
+    header_provider::DynamicHeaders,
+    spool::SpoolPosition,
+    DeliveryConfiguration, EpochTime, HeaderProvider, OtlpResource, RequestLimits, Spool, StdError,
 };
 
-use super::{EpochTime, StdError};
+#[cfg(feature = "introspect")]
+use super::delivery_introspection::DeliveryIntrospection;
 
-/// Goodmetrics only records "delta" data as that word is understood by opentelemetry.
-/// Goodmetrics records windows of aggregation_width and submits whatever was recorded
-/// during each window. While there are some possibly-legitimate cases for something
-/// marked as cumulative, goodmetrics is oriented instead toward high-cardinality
-/// actions rather than system counters.
-const THE_ACTUAL_TEMPORALITY: i32 = AggregationTemporality::Delta as i32;
+#[cfg(feature = "introspect")]
+static INTROSPECTION_REJECTED_DATA_POINTS: crate::introspect::LazySumGauge =
+    crate::introspect::LazySumGauge::new(Name::Str("otlp_rejected_data_points"));
+#[cfg(feature = "introspect")]
+static INTROSPECTION: DeliveryIntrospection = DeliveryIntrospection::new(&[("downstream", "otlp")]);
+
+const DELTA: i32 = AggregationTemporality::Delta as i32;
+const CUMULATIVE: i32 = AggregationTemporality::Cumulative as i32;
 
 /// Compatibility adapter downstream for OTLP. No dependency on opentelemetry code,
-/// only their protos. All your measurements will be Delta temporality.
+/// only their protos. Your measurements will be Delta temporality unless you configure
+/// your OpentelemetryBatcher for Cumulative.
 pub struct OpenTelemetryDownstream<TChannel> {
     client: MetricsServiceClient<TChannel>,
     header: Option<(AsciiMetadataKey, AsciiMetadataValue)>,
-    shared_dimensions: Option<Vec<KeyValue>>,
+    header_provider: Option<DynamicHeaders>,
+    resource: OtlpResource,
+    scope: InstrumentationScope,
+    delivery_configuration: DeliveryConfiguration,
+    request_limits: RequestLimits,
+    compression: Option<CompressionEncoding>,
+    on_partial_success: Option<PartialSuccessCallback>,
+}
+
+/// What a collector said it could not accept, from an export that otherwise went through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialSuccess {
+    /// How many data points were thrown away. 0 with an error message is a warning.
+    pub rejected_data_points: i64,
+    /// Why, according to the collector
+    pub error_message: String,
+}
+
+impl From<ExportMetricsPartialSuccess> for PartialSuccess {
+    fn from(partial: ExportMetricsPartialSuccess) -> Self {
+        Self {
+            rejected_data_points: partial.rejected_data_points,
+            error_message: partial.error_message,
+        }
+    }
+}
+
+pub(crate) type PartialSuccessCallback = Arc<dyn Fn(&PartialSuccess) + Send + Sync>;
+
+/// Log a partial success, count it for introspection and tell the callback about it
+pub(crate) fn report_partial_success(
+    partial: PartialSuccess,
+    on_partial_success: Option<&PartialSuccessCallback>,
+) {
+    // Per otlp, an empty partial success is a full success
+    if partial.rejected_data_points == 0 && partial.error_message.is_empty() {
+        return;
+    }
+    log::warn!(
+        "collector rejected {} data points: {}",
+        partial.rejected_data_points,
+        partial.error_message
+    );
+    #[cfg(feature = "introspect")]
+    if let Some(gauge) = INTROSPECTION_REJECTED_DATA_POINTS.gauge() {
+        gauge.observe(partial.rejected_data_points)
+    }
+    if let Some(on_partial_success) = on_partial_success {
+        on_partial_success(&partial)
+    }
 }
 
 const VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
@@ -74,7 +142,13 @@ where
         OpenTelemetryDownstream {
             client,
             header: header.map(|(k, v)| (k.into().parse().expect("header name must be valid"), v)),
-            shared_dimensions: None,
+            header_provider: None,
+            resource: OtlpResource::default(),
+            scope: default_scope(),
+            delivery_configuration: Default::default(),
+            request_limits: Default::default(),
+            compression: None,
+            on_partial_success: None,
         }
     }
 
@@ -88,15 +162,78 @@ where
         OpenTelemetryDownstream {
             client,
             header: header.map(|(k, v)| (k.into().parse().expect("header name must be valid"), v)),
-            shared_dimensions: Some(as_otel_dimensions(
-                shared_dimensions
-                    .into_iter()
-                    .map(|(n, d)| (n.into(), d.into()))
-                    .collect::<DimensionPosition>(),
-            )),
+            header_provider: None,
+            resource: shared_dimensions.into_iter().collect(),
+            scope: default_scope(),
+            delivery_configuration: Default::default(),
+            request_limits: Default::default(),
+            compression: None,
+            on_partial_success: None,
         }
     }
 
+    /// Customize how failed batches are retried. By default, batches are retried for up to 2 minutes.
+    pub fn with_delivery_configuration(
+        mut self,
+        delivery_configuration: DeliveryConfiguration,
+    ) -> Self {
+        self.delivery_configuration = delivery_configuration;
+        self
+    }
+
+    /// Split batches into requests of at most this size. By default requests stay under 4 MiB.
+    pub fn with_request_limits(mut self, request_limits: RequestLimits) -> Self {
+        self.request_limits = request_limits;
+        self
+    }
+
+    /// Ask a provider for headers before each request, like for short-lived tokens. Its headers
+    /// replace the static header when they have the same name.
+    pub fn with_header_provider(mut self, header_provider: impl HeaderProvider) -> Self {
+        self.header_provider = Some(DynamicHeaders::new(header_provider));
+        self
+    }
+
+    /// Describe what is sending these metrics, like its `service.name`. Attributes are added to
+    /// the shared dimensions from new_with_dimensions, and win when they have the same name.
+    pub fn with_resource(mut self, resource: OtlpResource) -> Self {
+        self.resource.extend(resource);
+        self
+    }
+
+    /// Report metrics under this instrumentation scope, rather than goodmetrics and its version.
+    /// Batches from a ScopedOpentelemetryBatcher with a scope of their own keep theirs.
+    pub fn with_instrumentation_scope(
+        mut self,
+        name: impl Into<String>,
+        version: impl Into<String>,
+    ) -> Self {
+        self.scope = InstrumentationScope {
+            name: name.into(),
+            version: version.into(),
+        };
+        self
+    }
+
+    /// Hear about data points the collector rejected, like to count them on your dashboards.
+    /// They are logged either way, and counted in `gm_introspect` with the introspect feature.
+    pub fn with_partial_success_callback(
+        mut self,
+        on_partial_success: impl Fn(&PartialSuccess) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_partial_success = Some(Arc::new(on_partial_success));
+        self
+    }
+
+    /// Compress requests, like with `CompressionEncoding::Gzip`. Enable the matching goodmetrics
+    /// feature, `gzip` or `zstd`, and make sure your collector accepts it.
+    /// Request sizes before and after compression are logged at debug level.
+    pub fn with_compression(mut self, compression: CompressionEncoding) -> Self {
+        self.client = self.client.send_compressed(compression);
+        self.compression = Some(compression);
+        self
+    }
+
     /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
     pub async fn send_batches_forever(self, receiver: mpsc::Receiver<Vec<Metric>>) {
         self.send_metrics_stream_forever(ReceiverStream::new(receiver))
@@ -105,51 +242,27 @@ where
 
     /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
     pub async fn send_metrics_stream_forever(
-        mut self,
-        mut receiver: impl Stream<Item = Vec<Metric>> + Unpin,
+        self,
+        receiver: impl Stream<Item = Vec<Metric>> + Unpin,
     ) {
-        let mut interval = tokio::time::interval(Duration::from_millis(500));
-        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-        loop {
-            interval.tick().await;
-            // Send as quickly as possible while there are more batches
-            while let Some(batch) = pin!(&mut receiver).next().await {
-                let result = self
-                    .client
-                    .export(self.request(ExportMetricsServiceRequest {
-                        resource_metrics: vec![ResourceMetrics {
-                            resource: self.shared_dimensions.as_ref().map(|dimensions| Resource {
-                                attributes: dimensions.clone(),
-                                dropped_attributes_count: 0,
-                            }),
-                            schema_url: "".to_string(),
-                            scope_metrics: vec![ScopeMetrics {
-                                scope: Some(InstrumentationScope {
-                                    name: "goodmetrics".to_string(),
-                                    version: VERSION.unwrap_or("unknown").to_string(),
-                                }),
-                                schema_url: "".to_string(),
-                                metrics: batch,
-                            }],
-                        }],
-                    }))
-                    .await;
-                match result {
-                    Ok(success) => {
-                        log::debug!("sent metrics: {success:?}");
-                    }
-                    Err(err) => {
-                        if !err.metadata().is_empty() {
-                            log::error!(
-                                "failed to send metrics: {err}. Metadata: {:?}",
-                                err.metadata()
-                            );
-                        }
-                        log::error!("failed to send metrics: {err:?}")
-                    }
-                };
-            }
-        }
+        self.send_scoped_metrics_stream_forever(receiver.map(unscoped))
+            .await
+    }
+
+    /// Like send_batches_forever, for batches from ScopedOpentelemetryBatchers
+    pub async fn send_scoped_batches_forever(self, receiver: mpsc::Receiver<ScopeMetrics>) {
+        self.send_scoped_metrics_stream_forever(ReceiverStream::new(receiver))
+            .await;
+    }
+
+    /// Like send_metrics_stream_forever, for batches from ScopedOpentelemetryBatchers
+    pub async fn send_scoped_metrics_stream_forever(
+        self,
+        receiver: impl Stream<Item = ScopeMetrics> + Unpin,
+    ) {
+        DeliveryQueue::new(self.delivery_configuration.clone())
+            .deliver_forever(self, receiver)
+            .await
     }
 
     fn request<T>(&self, request: T) -> tonic::Request<T> {
@@ -159,11 +272,241 @@ where
         }
         request
     }
+
+    /// 1 attempt, with headers from the provider
+    async fn send_once(
+        &mut self,
+        request: ExportMetricsServiceRequest,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        #[cfg(feature = "introspect")]
+        let started = INTROSPECTION.request(
+            request.encoded_len(),
+            request
+                .resource_metrics
+                .iter()
+                .flat_map(|resource| &resource.scope_metrics)
+                .flat_map(|scope| &scope.metrics)
+                .map(datapoints)
+                .sum(),
+        );
+        let mut request = self.request(request);
+        let result = match &mut self.header_provider {
+            Some(header_provider) => header_provider.apply(&mut request).await,
+            None => Ok(()),
+        };
+        let result = match result {
+            Ok(()) => self.client.export(request).await,
+            Err(status) => Err(status),
+        };
+        #[cfg(feature = "introspect")]
+        INTROSPECTION.response(
+            started,
+            result
+                .as_ref()
+                .map_or_else(|status| status.code(), |_| tonic::Code::Ok),
+        );
+        result
+    }
+}
+
+impl<TChannel> Delivery for OpenTelemetryDownstream<TChannel>
+where
+    TChannel: tonic::client::GrpcService<tonic::body::Body>,
+    TChannel::Error: Into<StdError>,
+    TChannel::ResponseBody: http_body::Body<Data = bytes::Bytes> + Send + 'static,
+    <TChannel::ResponseBody as http_body::Body>::Error: Into<StdError> + Send,
+{
+    type Batch = ScopeMetrics;
+    type Request = ExportMetricsServiceRequest;
+
+    #[cfg(feature = "introspect")]
+    fn introspection(&self) -> Option<&'static DeliveryIntrospection> {
+        Some(&INTROSPECTION)
+    }
+
+    fn prepare(&mut self, batch: ScopeMetrics) -> Vec<ExportMetricsServiceRequest> {
+        let ScopeMetrics {
+            scope,
+            schema_url,
+            metrics,
+        } = batch;
+        let scoped = |metrics| ScopeMetrics {
+            scope: Some(scope.clone().unwrap_or_else(|| self.scope.clone())),
+            schema_url: schema_url.clone(),
+            metrics,
+        };
+        let overhead = export_request(&self.resource, scoped(vec![])).encoded_len();
+        self.request_limits
+            .split(metrics, overhead, datapoints)
+            .into_iter()
+            .map(|metrics| {
+                let request = export_request(&self.resource, scoped(metrics));
+                log_request_size(self.compression, &request);
+                request
+            })
+            .collect()
+    }
+
+    fn unspool_batch(&self, spool: &Spool) -> Option<(SpoolPosition, ScopeMetrics)> {
+        spool.peek()
+    }
+
+    async fn send(&mut self, request: ExportMetricsServiceRequest) -> Result<(), DeliveryFailure> {
+        let retry = self.header_provider.is_some().then(|| request.clone());
+        let mut result = self.send_once(request).await;
+        if let (Err(status), Some(retry)) = (&result, retry) {
+            if status.code() == tonic::Code::Unauthenticated {
+                log::warn!("collector said unauthenticated - trying again with refreshed headers");
+                if let Some(header_provider) = &mut self.header_provider {
+                    header_provider.refresh();
+                }
+                result = self.send_once(retry).await;
+            }
+        }
+        match result {
+            Ok(success) => {
+                log::debug!("sent metrics: {success:?}");
+                if let Some(partial) = success.into_inner().partial_success {
+                    report_partial_success(partial.into(), self.on_partial_success.as_ref());
+                }
+                Ok(())
+            }
+            Err(err) => {
+                if !err.metadata().is_empty() {
+                    log::error!(
+                        "failed to send metrics: {err}. Metadata: {:?}",
+                        err.metadata()
+                    );
+                }
+                log::error!("failed to send metrics: {err:?}");
+                Err((&err).into())
+            }
+        }
+    }
+}
+
+/// How OpentelemetryBatcher represents t-digests. Otlp has no sketch type, so a digest has to
+/// become something a collector understands.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TDigestRepresentation {
+    /// A Summary data point with these quantiles estimated from the digest.
+    /// Quantile 0 is the minimum and 1 is the maximum, per otlp.
+    Summary {
+        /// Quantiles to report, between 0 and 1
+        quantiles: Vec<f64>,
+    },
+    /// An exponential histogram rebuilt from the digest's centroids. Each centroid lands in
+    /// the bucket of its mean, so the buckets are only as precise as the digest.
+    ExponentialHistogram {
+        /// Maximum number of buckets. The histogram scales down to fit.
+        max_buckets: u16,
+        /// Starting scale. 8 is a fine choice.
+        desired_scale: u8,
+    },
+}
+
+impl Default for TDigestRepresentation {
+    fn default() -> Self {
+        Self::Summary {
+            quantiles: vec![0.0, 0.5, 0.9, 0.99, 0.999, 1.0],
+        }
+    }
+}
+
+/// Which aggregation temporality OpentelemetryBatcher reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Temporality {
+    /// Goodmetrics records windows of aggregation_width and submits whatever was recorded
+    /// during each window. That is "delta" data as opentelemetry understands it, and it
+    /// costs nothing to remember between windows.
+    #[default]
+    Delta,
+    /// Keep running totals for each series, and report them every window with the time
+    /// the series started. Some backends, like Prometheus behind a collector, only
+    /// understand cumulative sums and histograms.
+    ///
+    /// The totals cost memory per series. Series that are not recorded for idle_series_expiry
+    /// are forgotten; if they come back, they start over at 0 with a new start time.
+    Cumulative {
+        /// How long a series may go without new data before it is dropped
+        idle_series_expiry: Duration,
+    },
+}
+
+/// Wrap a batch of metrics up for an otlp collector
+pub(crate) fn export_request(
+    resource: &OtlpResource,
+    scope_metrics: ScopeMetrics,
+) -> ExportMetricsServiceRequest {
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: resource.resource(),
+            schema_url: resource.schema_url().to_string(),
+            scope_metrics: vec![scope_metrics],
+        }],
+    }
+}
+
+/// The scope downstreams report when they are not told otherwise
+pub(crate) fn default_scope() -> InstrumentationScope {
+    InstrumentationScope {
+        name: "goodmetrics".to_string(),
+        version: VERSION.unwrap_or("unknown").to_string(),
+    }
+}
+
+/// A batch that gets the downstream's scope
+pub(crate) fn unscoped(metrics: Vec<Metric>) -> ScopeMetrics {
+    ScopeMetrics {
+        scope: None,
+        schema_url: String::new(),
+        metrics,
+    }
+}
+
+fn datapoints(metric: &Metric) -> usize {
+    match &metric.data {
+        Some(Data::Gauge(gauge)) => gauge.data_points.len(),
+        Some(Data::Sum(sum)) => sum.data_points.len(),
+        Some(Data::Histogram(histogram)) => histogram.data_points.len(),
+        Some(Data::ExponentialHistogram(histogram)) => histogram.data_points.len(),
+        Some(Data::Summary(summary)) => summary.data_points.len(),
+        None => 0,
+    }
 }
 
 /// The default mapping from in-memory representation to opentelemetry metrics wire representation
+#[derive(Debug, Clone, Copy, Default)]
 pub struct OpentelemetryBatcher;
 
+impl OpentelemetryBatcher {
+    /// Choose delta or cumulative temporality. By default it is Delta.
+    pub fn with_temporality(self, temporality: Temporality) -> ConfiguredOpentelemetryBatcher {
+        ConfiguredOpentelemetryBatcher::default().with_temporality(temporality)
+    }
+
+    /// Choose how t-digests are sent. By default they are Summary data points.
+    pub fn with_tdigest_representation(
+        self,
+        tdigest_representation: TDigestRepresentation,
+    ) -> ConfiguredOpentelemetryBatcher {
+        ConfiguredOpentelemetryBatcher::default()
+            .with_tdigest_representation(tdigest_representation)
+    }
+
+    /// Send units and descriptions from this registry. Get it from your MetricsFactory or
+    /// GaugeFactory. Measurements that are not described are sent with unit "1".
+    pub fn with_metadata(self, metadata: MetadataRegistry) -> ConfiguredOpentelemetryBatcher {
+        ConfiguredOpentelemetryBatcher::default().with_metadata(metadata)
+    }
+
+    /// Tag each batch with the instrumentation scope, like ConfiguredOpentelemetryBatcher::scoped.
+    /// Without a MetadataRegistry there is no scope, so the downstream's scope is used.
+    pub fn scoped(self) -> ScopedOpentelemetryBatcher {
+        ConfiguredOpentelemetryBatcher::default().scoped()
+    }
+}
+
 impl AggregationBatcher for OpentelemetryBatcher {
     type TBatch = Vec<Metric>;
 
@@ -173,16 +516,278 @@ impl AggregationBatcher for OpentelemetryBatcher {
         covered_time: Duration,
         aggregations: &mut AggregatedMetricsMap,
     ) -> Self::TBatch {
-        aggregations
-            .drain()
-            .flat_map(|(name, dimensioned_measurements)| {
-                as_metrics(name, now, covered_time, dimensioned_measurements)
-            })
-            .collect()
+        ConfiguredOpentelemetryBatcher::default().batch_aggregations(
+            now,
+            covered_time,
+            aggregations,
+        )
+    }
+
+    fn batch_aggregations_with_exemplars(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) -> Self::TBatch {
+        ConfiguredOpentelemetryBatcher::default().batch_aggregations_with_exemplars(
+            now,
+            covered_time,
+            aggregations,
+            exemplars,
+        )
+    }
+
+    /// Spooled as ScopeMetrics without a scope, like send_batches_forever sends it
+    fn spool_batch(&self, spool: &Spool, batch: Vec<Metric>) -> bool {
+        spool.push(&unscoped(batch));
+        true
+    }
+}
+
+/// An OpentelemetryBatcher with options. Start from `OpentelemetryBatcher.with_*()`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfiguredOpentelemetryBatcher {
+    tdigest_representation: TDigestRepresentation,
+    temporality: Temporality,
+    metadata: MetadataRegistry,
+    totals: HashMap<(Name, DimensionPosition, Name), CumulativeSeries>,
+}
+
+#[derive(Debug, Clone)]
+struct CumulativeSeries {
+    start_time: SystemTime,
+    last_updated: SystemTime,
+    cumulative: Cumulative,
+    /// From the latest window
+    exemplars: Vec<Exemplar>,
+}
+
+impl ConfiguredOpentelemetryBatcher {
+    /// Choose delta or cumulative temporality. By default it is Delta.
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = temporality;
+        self
+    }
+
+    /// Choose how t-digests are sent. By default they are Summary data points.
+    pub fn with_tdigest_representation(
+        mut self,
+        tdigest_representation: TDigestRepresentation,
+    ) -> Self {
+        self.tdigest_representation = tdigest_representation;
+        self
+    }
+
+    /// Send units and descriptions from this registry. Get it from your MetricsFactory or
+    /// GaugeFactory. Measurements that are not described are sent with unit "1".
+    pub fn with_metadata(mut self, metadata: MetadataRegistry) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Tag each batch with the instrumentation scope from the metadata registry, so metrics
+    /// from several MetricsFactories can share 1 downstream and still be told apart.
+    /// Send these batches with `send_scoped_batches_forever`.
+    pub fn scoped(self) -> ScopedOpentelemetryBatcher {
+        ScopedOpentelemetryBatcher { batcher: self }
+    }
+}
+
+/// An OpentelemetryBatcher whose batches carry the instrumentation scope set on its
+/// MetadataRegistry. Without one, the downstream's scope is used.
+/// ```
+/// # use goodmetrics::{downstream::OpentelemetryBatcher, MetadataRegistry};
+/// let library_metadata = MetadataRegistry::default();
+/// library_metadata.set_instrumentation_scope("my_http_client", "0.4.1");
+/// let batcher = OpentelemetryBatcher
+///     .with_metadata(library_metadata)
+///     .scoped();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ScopedOpentelemetryBatcher {
+    batcher: ConfiguredOpentelemetryBatcher,
+}
+
+impl ScopedOpentelemetryBatcher {
+    fn scoped(&self, metrics: Vec<Metric>) -> ScopeMetrics {
+        ScopeMetrics {
+            scope: self.batcher.metadata.instrumentation_scope().map(|scope| {
+                InstrumentationScope {
+                    name: scope.name,
+                    version: scope.version,
+                }
+            }),
+            schema_url: String::new(),
+            metrics,
+        }
+    }
+}
+
+impl AggregationBatcher for ScopedOpentelemetryBatcher {
+    type TBatch = ScopeMetrics;
+
+    fn batch_aggregations(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+    ) -> Self::TBatch {
+        let metrics = self
+            .batcher
+            .batch_aggregations(now, covered_time, aggregations);
+        self.scoped(metrics)
+    }
+
+    fn batch_aggregations_with_exemplars(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) -> Self::TBatch {
+        let metrics = self.batcher.batch_aggregations_with_exemplars(
+            now,
+            covered_time,
+            aggregations,
+            exemplars,
+        );
+        self.scoped(metrics)
+    }
+
+    fn spool_batch(&self, spool: &Spool, batch: ScopeMetrics) -> bool {
+        spool.push(&batch);
+        true
+    }
+}
+
+impl AggregationBatcher for ConfiguredOpentelemetryBatcher {
+    type TBatch = Vec<Metric>;
+
+    fn batch_aggregations(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+    ) -> Self::TBatch {
+        self.batch_aggregations_with_exemplars(
+            now,
+            covered_time,
+            aggregations,
+            &mut ExemplarsMap::default(),
+        )
+    }
+
+    fn batch_aggregations_with_exemplars(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) -> Self::TBatch {
+        match self.temporality {
+            Temporality::Delta => aggregations
+                .drain()
+                .flat_map(|(name, dimensioned_measurements)| {
+                    as_metrics(
+                        &self.tdigest_representation,
+                        &self.metadata,
+                        exemplars.remove(&name).unwrap_or_default(),
+                        name,
+                        now,
+                        covered_time,
+                        dimensioned_measurements,
+                    )
+                })
+                .collect(),
+            Temporality::Cumulative { idle_series_expiry } => {
+                self.accumulate(now, covered_time, aggregations, exemplars);
+                self.totals.retain(|_, series| {
+                    now.duration_since(series.last_updated).unwrap_or_default() < idle_series_expiry
+                });
+                self.totals
+                    .iter_mut()
+                    .flat_map(|((name, dimension_position, measurement_name), series)| {
+                        with_exemplars(
+                            as_cumulative_metrics(
+                                &self.tdigest_representation,
+                                self.metadata
+                                    .get(name.as_str(), measurement_name.as_str())
+                                    .as_ref(),
+                                format!("{name}_{measurement_name}"),
+                                now,
+                                series,
+                                as_otel_dimensions(dimension_position.clone()),
+                            ),
+                            // Exemplars go out with the window they were recorded in
+                            std::mem::take(&mut series.exemplars),
+                        )
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Spooled as ScopeMetrics without a scope, like send_batches_forever sends it
+    fn spool_batch(&self, spool: &Spool, batch: Vec<Metric>) -> bool {
+        spool.push(&unscoped(batch));
+        true
+    }
+}
+
+impl ConfiguredOpentelemetryBatcher {
+    fn accumulate(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        let window_start = now.checked_sub(covered_time).unwrap_or(now);
+        for (name, mut dimensioned_measurements) in aggregations.drain() {
+            let mut exemplars = exemplars.remove(&name).unwrap_or_default();
+            for (dimension_position, mut measurements) in dimensioned_measurements.drain() {
+                let mut exemplars = exemplars.remove(&dimension_position).unwrap_or_default();
+                for (measurement_name, aggregation) in measurements.drain() {
+                    let exemplars = exemplars
+                        .remove(&measurement_name)
+                        .map(ExemplarReservoir::into_exemplars)
+                        .unwrap_or_default();
+                    match self.totals.entry((
+                        name.clone(),
+                        dimension_position.clone(),
+                        measurement_name,
+                    )) {
+                        hash_map::Entry::Occupied(occupied) => {
+                            let series = occupied.into_mut();
+                            let kind = std::mem::discriminant(&series.cumulative);
+                            series.cumulative.accumulate(aggregation);
+                            if kind != std::mem::discriminant(&series.cumulative) {
+                                // The measurement changed type, so the totals started over
+                                series.start_time = window_start;
+                            }
+                            series.last_updated = now;
+                            series.exemplars = exemplars;
+                        }
+                        hash_map::Entry::Vacant(vacant) => {
+                            vacant.insert(CumulativeSeries {
+                                start_time: window_start,
+                                last_updated: now,
+                                cumulative: Cumulative::new(aggregation),
+                                exemplars,
+                            });
+                        }
+                    }
+                }
+            }
+        }
     }
 }
 
 fn as_metrics(
+    tdigest_representation: &TDigestRepresentation,
+    metadata: &MetadataRegistry,
+    mut exemplars: HashMap<DimensionPosition, HashMap<Name, ExemplarReservoir>>,
     name: Name,
     timestamp: SystemTime,
     duration: Duration,
@@ -191,61 +796,267 @@ fn as_metrics(
     dimensioned_measurements
         .drain()
         .flat_map(|(dimension_position, mut measurements)| {
+            let mut exemplars = exemplars.remove(&dimension_position).unwrap_or_default();
             let otel_dimensions = as_otel_dimensions(dimension_position);
             measurements
                 .drain()
-                .flat_map(|(measurement_name, aggregation)| match aggregation {
-                    Aggregation::ExponentialHistogram(eh) => {
-                        vec![Metric {
-                            name: format!("{name}_{measurement_name}"),
-                            description: "".into(),
-                            unit: "1".into(),
-                            data: Some(
-                                opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(
-                                    as_otel_exponential_histogram(
-                                        eh,
-                                        timestamp,
-                                        duration,
-                                        otel_dimensions.clone(),
-                                    ),
-                                ),
-                            ),
-                        }]
-                    }
-                    Aggregation::Histogram(h) => {
-                        vec![Metric {
-                            name: format!("{name}_{measurement_name}"),
-                            data: Some(opentelemetry::metrics::v1::metric::Data::Histogram(
-                                as_otel_histogram(h, timestamp, duration, otel_dimensions.clone()),
-                            )),
-                            description: "".into(),
-                            unit: "1".into(),
-                        }]
-                    }
-                    Aggregation::StatisticSet(s) => as_otel_statistic_set(
-                        s,
-                        &format!("{name}_{measurement_name}"),
-                        timestamp,
-                        duration,
-                        &otel_dimensions,
-                    ),
-                    Aggregation::Sum(s) => vec![as_otel_sum(
-                        s,
-                        &format!("{name}_{measurement_name}"),
-                        timestamp,
-                        duration,
-                        &otel_dimensions,
-                    )],
-                    Aggregation::TDigest(_) => {
-                        unimplemented!("tdigest for opentelemetry is not implemented")
-                    }
+                .flat_map(|(measurement_name, aggregation)| {
+                    let exemplars = exemplars
+                        .remove(&measurement_name)
+                        .map(ExemplarReservoir::into_exemplars)
+                        .unwrap_or_default();
+                    with_exemplars(
+                        as_delta_metrics(
+                            tdigest_representation,
+                            metadata
+                                .get(name.as_str(), measurement_name.as_str())
+                                .as_ref(),
+                            format!("{name}_{measurement_name}"),
+                            timestamp,
+                            duration,
+                            &otel_dimensions,
+                            aggregation,
+                        ),
+                        exemplars,
+                    )
                 })
                 .collect::<Vec<Metric>>()
         })
         .collect()
 }
 
-fn as_otel_dimensions(dimension_position: DimensionPosition) -> Vec<KeyValue> {
+fn as_delta_metrics(
+    tdigest_representation: &TDigestRepresentation,
+    metadata: Option<&MetricMetadata>,
+    full_measurement_name: String,
+    timestamp: SystemTime,
+    duration: Duration,
+    otel_dimensions: &[KeyValue],
+    aggregation: Aggregation,
+) -> Vec<Metric> {
+    let data = match aggregation {
+        Aggregation::ExponentialHistogram(eh) => {
+            opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(
+                as_otel_exponential_histogram(eh, timestamp, duration, otel_dimensions.to_vec()),
+            )
+        }
+        Aggregation::Histogram(h) => opentelemetry::metrics::v1::metric::Data::Histogram(
+            as_otel_histogram(h, DELTA, timestamp, duration, otel_dimensions.to_vec()),
+        ),
+        Aggregation::StatisticSet(s) => {
+            return as_otel_statistic_set(
+                s,
+                DELTA,
+                &full_measurement_name,
+                timestamp,
+                duration,
+                otel_dimensions,
+                metadata,
+            )
+        }
+        Aggregation::Sum(s) => {
+            return vec![described(
+                as_otel_sum(
+                    s,
+                    DELTA,
+                    &full_measurement_name,
+                    timestamp,
+                    duration,
+                    otel_dimensions,
+                ),
+                metadata,
+            )]
+        }
+        Aggregation::TDigest(mut t) => {
+            let digest = t.reset_mut();
+            match tdigest_representation {
+                TDigestRepresentation::Summary { quantiles } => {
+                    opentelemetry::metrics::v1::metric::Data::Summary(as_otel_summary(
+                        &digest,
+                        quantiles,
+                        timestamp,
+                        duration,
+                        otel_dimensions.to_vec(),
+                    ))
+                }
+                TDigestRepresentation::ExponentialHistogram {
+                    max_buckets,
+                    desired_scale,
+                } => opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(
+                    tdigest_as_otel_exponential_histogram(
+                        digest,
+                        ExponentialBuckets::new(*max_buckets, *desired_scale),
+                        DELTA,
+                        timestamp,
+                        duration,
+                        otel_dimensions.to_vec(),
+                    ),
+                ),
+            }
+        }
+    };
+    vec![described(
+        Metric {
+            name: full_measurement_name,
+            data: Some(data),
+            description: "".into(),
+            unit: "1".into(),
+        },
+        metadata,
+    )]
+}
+
+fn as_cumulative_metrics(
+    tdigest_representation: &TDigestRepresentation,
+    metadata: Option<&MetricMetadata>,
+    full_measurement_name: String,
+    now: SystemTime,
+    series: &CumulativeSeries,
+    attributes: Vec<KeyValue>,
+) -> Vec<Metric> {
+    let duration = now.duration_since(series.start_time).unwrap_or_default();
+    let data = match &series.cumulative {
+        Cumulative::Sum(sum) => {
+            return vec![described(
+                as_otel_sum(
+                    Sum { sum: *sum },
+                    CUMULATIVE,
+                    &full_measurement_name,
+                    now,
+                    duration,
+                    &attributes,
+                ),
+                metadata,
+            )]
+        }
+        Cumulative::StatisticSet { window, sum, count } => {
+            // Min and max are from the latest window. They don't add up.
+            let mut metrics = as_otel_statistic_set(
+                StatisticSet {
+                    sum: *sum,
+                    count: *count,
+                    ..*window
+                },
+                CUMULATIVE,
+                &full_measurement_name,
+                now,
+                duration,
+                &attributes,
+                metadata,
+            );
+            if series.last_updated != now {
+                // Idle this window, so there is no current min or max - only the running totals
+                metrics.retain(|metric| !matches!(metric.data, Some(Data::Gauge(_))));
+            }
+            return metrics;
+        }
+        Cumulative::Histogram(buckets) => {
+            opentelemetry::metrics::v1::metric::Data::Histogram(as_otel_histogram(
+                Histogram {
+                    histogram: buckets.iter().map(|(b, c)| (*b, *c)).collect(),
+                },
+                CUMULATIVE,
+                now,
+                duration,
+                attributes,
+            ))
+        }
+        Cumulative::ExponentialHistogram(histogram) => {
+            opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(
+                exponential_buckets_as_otel(
+                    histogram.buckets.clone(),
+                    histogram.sum,
+                    histogram.min,
+                    histogram.max,
+                    CUMULATIVE,
+                    now,
+                    duration,
+                    attributes,
+                ),
+            )
+        }
+        Cumulative::TDigest(digest) => match tdigest_representation {
+            // Otlp summaries are always cumulative
+            TDigestRepresentation::Summary { quantiles } => {
+                opentelemetry::metrics::v1::metric::Data::Summary(as_otel_summary(
+                    digest, quantiles, now, duration, attributes,
+                ))
+            }
+            TDigestRepresentation::ExponentialHistogram {
+                max_buckets,
+                desired_scale,
+            } => opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(
+                tdigest_as_otel_exponential_histogram(
+                    digest.clone(),
+                    ExponentialBuckets::new(*max_buckets, *desired_scale),
+                    CUMULATIVE,
+                    now,
+                    duration,
+                    attributes,
+                ),
+            ),
+        },
+    };
+    vec![described(
+        Metric {
+            name: full_measurement_name,
+            data: Some(data),
+            description: "".into(),
+            unit: "1".into(),
+        },
+        metadata,
+    )]
+}
+
+/// Put exemplars on the histogram data points. Other kinds of points have no use for them.
+fn with_exemplars(mut metrics: Vec<Metric>, exemplars: Vec<Exemplar>) -> Vec<Metric> {
+    if exemplars.is_empty() {
+        return metrics;
+    }
+    let exemplars: Vec<opentelemetry::metrics::v1::Exemplar> =
+        exemplars.into_iter().map(Into::into).collect();
+    for metric in &mut metrics {
+        match &mut metric.data {
+            Some(opentelemetry::metrics::v1::metric::Data::Histogram(histogram)) => {
+                for point in &mut histogram.data_points {
+                    point.exemplars.clone_from(&exemplars);
+                }
+            }
+            Some(opentelemetry::metrics::v1::metric::Data::ExponentialHistogram(histogram)) => {
+                for point in &mut histogram.data_points {
+                    point.exemplars.clone_from(&exemplars);
+                }
+            }
+            _ => (),
+        }
+    }
+    metrics
+}
+
+impl From<Exemplar> for opentelemetry::metrics::v1::Exemplar {
+    fn from(exemplar: Exemplar) -> Self {
+        Self {
+            filtered_attributes: vec![],
+            time_unix_nano: exemplar.time.nanos_since_epoch(),
+            span_id: exemplar.span_id.to_vec(),
+            trace_id: exemplar.trace_id.to_vec(),
+            value: Some(opentelemetry::metrics::v1::exemplar::Value::AsDouble(
+                exemplar.value,
+            )),
+        }
+    }
+}
+
+/// Fill in the unit and description, if the measurement was described
+fn described(mut metric: Metric, metadata: Option<&MetricMetadata>) -> Metric {
+    if let Some(metadata) = metadata {
+        metric.unit.clone_from(&metadata.unit);
+        metric.description.clone_from(&metadata.description);
+    }
+    metric
+}
+
+pub(crate) fn as_otel_dimensions(dimension_position: DimensionPosition) -> Vec<KeyValue> {
     dimension_position
         .into_iter()
         .map(|(dimension, value)| KeyValue {
@@ -274,54 +1085,74 @@ impl From<Dimension> for AnyValue {
 
 fn as_otel_statistic_set(
     statistic_set: StatisticSet,
+    temporality: i32,
     full_measurement_name: &str,
     timestamp: SystemTime,
     duration: Duration,
     attributes: &[KeyValue],
+    metadata: Option<&MetricMetadata>,
 ) -> Vec<opentelemetry::metrics::v1::Metric> {
     let timestamp_nanos = timestamp
         .duration_since(UNIX_EPOCH)
         .expect("could not get system time")
         .as_nanos() as u64;
     let start_nanos = timestamp_nanos - duration.as_nanos() as u64;
-    vec![
-        statistic_set_gauge_component(
-            full_measurement_name,
-            timestamp_nanos,
-            start_nanos,
-            attributes,
-            "min",
-            statistic_set.min.into(),
-        ),
-        statistic_set_gauge_component(
-            full_measurement_name,
-            timestamp_nanos,
-            start_nanos,
-            attributes,
-            "max",
-            statistic_set.max.into(),
-        ),
-        statistic_set_counter_component(
-            full_measurement_name,
-            timestamp_nanos,
-            start_nanos,
-            attributes,
-            "sum",
-            statistic_set.sum.into(),
-        ),
+    let mut count = described(
         statistic_set_counter_component(
             full_measurement_name,
+            temporality,
             timestamp_nanos,
             start_nanos,
             attributes,
             "count",
             statistic_set.count.into(),
         ),
+        metadata,
+    );
+    // The count is how many observations there were, whatever unit they are in
+    count.unit = "1".into();
+    vec![
+        described(
+            statistic_set_gauge_component(
+                full_measurement_name,
+                timestamp_nanos,
+                start_nanos,
+                attributes,
+                "min",
+                statistic_set.min.into(),
+            ),
+            metadata,
+        ),
+        described(
+            statistic_set_gauge_component(
+                full_measurement_name,
+                timestamp_nanos,
+                start_nanos,
+                attributes,
+                "max",
+                statistic_set.max.into(),
+            ),
+            metadata,
+        ),
+        described(
+            statistic_set_counter_component(
+                full_measurement_name,
+                temporality,
+                timestamp_nanos,
+                start_nanos,
+                attributes,
+                "sum",
+                statistic_set.sum.into(),
+            ),
+            metadata,
+        ),
+        count,
     ]
 }
 
 fn as_otel_sum(
     sum: Sum,
+    temporality: i32,
     full_measurement_name: &str,
     timestamp: SystemTime,
     duration: Duration,
@@ -337,8 +1168,8 @@ fn as_otel_sum(
         name: full_measurement_name.to_string(),
         data: Some(opentelemetry::metrics::v1::metric::Data::Sum(
             opentelemetry::metrics::v1::Sum {
-                aggregation_temporality: THE_ACTUAL_TEMPORALITY,
-                // This resets every aggregation interval.
+                aggregation_temporality: temporality,
+                // With delta temporality this resets every aggregation interval.
                 // It might not play very nicely with prometheus, but goodmetrics does not limit
                 // to the lowest common monitoring denominator.
                 is_monotonic: true,
@@ -376,6 +1207,7 @@ impl From<f64> for opentelemetry::metrics::v1::number_data_point::Value {
 /// For numbers that sum up per reporting window
 fn statistic_set_counter_component(
     full_measurement_name: &str,
+    temporality: i32,
     unix_nanos: u64,
     start_time_unix_nanos: u64,
     attributes: &[KeyValue],
@@ -386,8 +1218,8 @@ fn statistic_set_counter_component(
         name: format!("{full_measurement_name}_{component}"),
         data: Some(opentelemetry::metrics::v1::metric::Data::Sum(
             opentelemetry::metrics::v1::Sum {
-                aggregation_temporality: THE_ACTUAL_TEMPORALITY,
-                // This resets every aggregation interval.
+                aggregation_temporality: temporality,
+                // With delta temporality this resets every aggregation interval.
                 // It might not play very nicely with prometheus, but goodmetrics does not limit
                 // to the lowest common monitoring denominator.
                 is_monotonic: true,
@@ -446,6 +1278,7 @@ fn new_number_data_point(
 
 fn as_otel_histogram(
     histogram: Histogram,
+    temporality: i32,
     timestamp: SystemTime,
     duration: Duration,
     attributes: Vec<KeyValue>,
@@ -490,7 +1323,7 @@ fn as_otel_histogram(
     sorted_counts.push(0);
 
     opentelemetry::metrics::v1::Histogram {
-        aggregation_temporality: THE_ACTUAL_TEMPORALITY,
+        aggregation_temporality: temporality,
         data_points: vec![HistogramDataPoint {
             attributes,
             start_time_unix_nano: timestamp_nanos - duration.as_nanos() as u64,
@@ -498,7 +1331,7 @@ fn as_otel_histogram(
             count: bucket_values_count,
             explicit_bounds: sorted_bounds,
             bucket_counts: sorted_counts,
-            exemplars: vec![],                      // just no.
+            exemplars: vec![],                      // with_exemplars() adds these
             flags: DataPointFlags::FlagNone as u32, // i don't send useless buckets
             min: bucket_values_min,                 // just use the histogram...
             max: bucket_values_max,                 // just use the histogram...
@@ -526,14 +1359,14 @@ fn as_otel_exponential_histogram(
     let (positives, negatives) = exponential_histogram.take_counts();
 
     opentelemetry::metrics::v1::ExponentialHistogram {
-        aggregation_temporality: THE_ACTUAL_TEMPORALITY,
+        aggregation_temporality: DELTA,
         data_points: vec![ExponentialHistogramDataPoint {
             attributes,
             start_time_unix_nano: timestamp_nanos - duration.as_nanos() as u64,
             time_unix_nano: timestamp_nanos,
             count,
             sum,
-            exemplars: vec![],                      // just no.
+            exemplars: vec![],                      // with_exemplars() adds these
             flags: DataPointFlags::FlagNone as u32, // i don't send useless buckets
             min,
             max,
@@ -551,31 +1384,450 @@ fn as_otel_exponential_histogram(
     }
 }
 
+fn as_otel_summary(
+    digest: &TDigest,
+    quantiles: &[f64],
+    timestamp: SystemTime,
+    duration: Duration,
+    attributes: Vec<KeyValue>,
+) -> opentelemetry::metrics::v1::Summary {
+    let timestamp_nanos = timestamp.nanos_since_epoch();
+    let quantile_values = if digest.is_empty() {
+        // An empty digest has no quantiles to speak of
+        vec![]
+    } else {
+        quantiles
+            .iter()
+            .map(|quantile| ValueAtQuantile {
+                quantile: *quantile,
+                value: if *quantile <= 0.0 {
+                    digest.min()
+                } else if 1.0 <= *quantile {
+                    digest.max()
+                } else {
+                    digest.estimate_quantile(*quantile)
+                },
+            })
+            .collect()
+    };
+
+    opentelemetry::metrics::v1::Summary {
+        data_points: vec![SummaryDataPoint {
+            attributes,
+            start_time_unix_nano: timestamp_nanos - duration.as_nanos() as u64,
+            time_unix_nano: timestamp_nanos,
+            count: digest.count() as u64,
+            sum: digest.sum(),
+            quantile_values,
+            flags: DataPointFlags::FlagNone as u32,
+        }],
+    }
+}
+
+fn tdigest_as_otel_exponential_histogram(
+    mut digest: TDigest,
+    mut buckets: ExponentialBuckets,
+    temporality: i32,
+    timestamp: SystemTime,
+    duration: Duration,
+    attributes: Vec<KeyValue>,
+) -> opentelemetry::metrics::v1::ExponentialHistogram {
+    let (sum, min, max) = if digest.is_empty() {
+        (0.0, 0.0, 0.0)
+    } else {
+        (digest.sum(), digest.min(), digest.max())
+    };
+    for centroid in digest.drain_centroids() {
+        buckets.record(centroid.mean(), centroid.weight().round() as u64);
+    }
+    exponential_buckets_as_otel(
+        buckets,
+        sum,
+        min,
+        max,
+        temporality,
+        timestamp,
+        duration,
+        attributes,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn exponential_buckets_as_otel(
+    buckets: ExponentialBuckets,
+    sum: f64,
+    min: f64,
+    max: f64,
+    temporality: i32,
+    timestamp: SystemTime,
+    duration: Duration,
+    attributes: Vec<KeyValue>,
+) -> opentelemetry::metrics::v1::ExponentialHistogram {
+    let timestamp_nanos = timestamp.nanos_since_epoch();
+    let count = buckets.count();
+    let scale = buckets.scale();
+    let zero_count = buckets.zero_count();
+    let (positive, negative) = buckets.take_buckets();
+
+    opentelemetry::metrics::v1::ExponentialHistogram {
+        aggregation_temporality: temporality,
+        data_points: vec![ExponentialHistogramDataPoint {
+            attributes,
+            start_time_unix_nano: timestamp_nanos - duration.as_nanos() as u64,
+            time_unix_nano: timestamp_nanos,
+            count,
+            sum,
+            exemplars: vec![],
+            flags: DataPointFlags::FlagNone as u32,
+            min,
+            max,
+            scale,
+            zero_count,
+            positive: Some(Buckets {
+                offset: positive.offset(),
+                bucket_counts: positive.into_counts(),
+            }),
+            negative: Some(Buckets {
+                offset: negative.offset(),
+                bucket_counts: negative.into_counts(),
+            }),
+        }],
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
 
+    use exponential_histogram::ExponentialHistogram;
     use tokio::sync::mpsc;
     use tonic::metadata::AsciiMetadataValue;
 
     use crate::{
+        aggregation::{Aggregation, Exemplar, ExemplarReservoir, Histogram, StatisticSet, Sum},
         downstream::{
             channel_connection::get_client,
-            opentelemetry_downstream::{OpenTelemetryDownstream, OpentelemetryBatcher},
+            delivery::Delivery,
+            opentelemetry_downstream::{
+                unscoped, ConfiguredOpentelemetryBatcher, OpenTelemetryDownstream,
+                OpentelemetryBatcher, Temporality, CUMULATIVE,
+            },
+            EpochTime, OtlpResource, PartialSuccess, RequestLimits, TlsConfiguration,
         },
         metrics::Metrics,
-        pipeline::{Aggregator, DistributionMode, StreamSink},
-        proto::opentelemetry::collector::metrics::v1::metrics_service_client::MetricsServiceClient,
+        pipeline::{
+            AggregatedMetricsMap, AggregationBatcher, Aggregator, DistributionMode, ExemplarsMap,
+            StreamSink,
+        },
+        proto::opentelemetry::{
+            collector::metrics::v1::{
+                metrics_service_client::MetricsServiceClient,
+                metrics_service_server::{MetricsService, MetricsServiceServer},
+                ExportMetricsPartialSuccess, ExportMetricsServiceRequest,
+                ExportMetricsServiceResponse,
+            },
+            metrics::v1::{metric::Data, number_data_point, Gauge, Metric},
+        },
+        types::{Dimension, Name},
+        MetadataRegistry,
     };
 
+    fn window(aggregation: Aggregation) -> AggregatedMetricsMap {
+        HashMap::from([(
+            Name::from("api"),
+            HashMap::from([(
+                BTreeMap::from([(Name::from("host"), Dimension::from("a"))]),
+                HashMap::from([(Name::from("requests"), aggregation)]),
+            )]),
+        )])
+    }
+
+    fn cumulative_batcher() -> ConfiguredOpentelemetryBatcher {
+        OpentelemetryBatcher.with_temporality(Temporality::Cumulative {
+            idle_series_expiry: Duration::from_secs(60),
+        })
+    }
+
+    fn only_metric(mut batch: Vec<Metric>) -> Data {
+        assert_eq!(1, batch.len(), "{batch:?}");
+        let metric = batch.pop().expect("there is 1 metric");
+        assert_eq!("api_requests", metric.name);
+        metric.data.expect("metrics have data")
+    }
+
+    #[test_log::test]
+    fn cumulative_sums_keep_running() {
+        let mut batcher = cumulative_batcher();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let window_width = Duration::from_secs(10);
+
+        let _ = batcher.batch_aggregations(
+            start + window_width,
+            window_width,
+            &mut window(Aggregation::Sum(Sum { sum: 2 })),
+        );
+        let now = start + window_width * 2;
+        let batch = batcher.batch_aggregations(
+            now,
+            window_width,
+            &mut window(Aggregation::Sum(Sum { sum: 3 })),
+        );
+
+        let Data::Sum(sum) = only_metric(batch) else {
+            panic!("sums stay sums")
+        };
+        assert_eq!(CUMULATIVE, sum.aggregation_temporality);
+        assert!(sum.is_monotonic);
+        let point = &sum.data_points[0];
+        assert_eq!(Some(number_data_point::Value::AsInt(5)), point.value);
+        assert_eq!(start.nanos_since_epoch(), point.start_time_unix_nano);
+        assert_eq!(now.nanos_since_epoch(), point.time_unix_nano);
+    }
+
+    #[test_log::test]
+    fn cumulative_exponential_histograms_merge_across_scales() {
+        let mut batcher = cumulative_batcher();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let window_width = Duration::from_secs(10);
+
+        let mut fine = ExponentialHistogram::new(8);
+        fine.accumulate(3);
+        fine.accumulate(4);
+        let _ = batcher.batch_aggregations(
+            start + window_width,
+            window_width,
+            &mut window(Aggregation::ExponentialHistogram(fine)),
+        );
+        let mut coarse = ExponentialHistogram::new(2);
+        coarse.accumulate(300);
+        let batch = batcher.batch_aggregations(
+            start + window_width * 2,
+            window_width,
+            &mut window(Aggregation::ExponentialHistogram(coarse)),
+        );
+
+        let Data::ExponentialHistogram(histogram) = only_metric(batch) else {
+            panic!("exponential histograms stay exponential histograms")
+        };
+        assert_eq!(CUMULATIVE, histogram.aggregation_temporality);
+        let point = &histogram.data_points[0];
+        assert_eq!(3, point.count);
+        // The exponential_histogram crate estimates sum, min and max from its buckets
+        assert!(200.0 < point.sum && point.sum < 400.0, "{}", point.sum);
+        assert!(point.min < 4.0 && 200.0 < point.max, "{point:?}");
+        assert!(point.scale <= 2, "merged at the coarser scale");
+        let positive = point.positive.as_ref().expect("there are positive buckets");
+        assert_eq!(3, positive.bucket_counts.iter().sum::<u64>());
+        assert_eq!(start.nanos_since_epoch(), point.start_time_unix_nano);
+    }
+
+    #[test_log::test]
+    fn idle_cumulative_series_expire() {
+        let mut batcher = cumulative_batcher();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let window_width = Duration::from_secs(10);
+
+        let _ = batcher.batch_aggregations(
+            start + window_width,
+            window_width,
+            &mut window(Aggregation::Sum(Sum { sum: 2 })),
+        );
+        let batch = batcher.batch_aggregations(
+            start + window_width * 2,
+            window_width,
+            &mut Default::default(),
+        );
+        assert!(
+            matches!(only_metric(batch), Data::Sum(_)),
+            "idle series are reported until they expire"
+        );
+
+        let batch = batcher.batch_aggregations(
+            start + window_width + Duration::from_secs(60),
+            window_width,
+            &mut Default::default(),
+        );
+        assert_eq!(
+            Vec::<Metric>::new(),
+            batch,
+            "series expire after 60 idle seconds"
+        );
+
+        let now = start + Duration::from_secs(100);
+        let batch = batcher.batch_aggregations(
+            now,
+            window_width,
+            &mut window(Aggregation::Sum(Sum { sum: 3 })),
+        );
+        let Data::Sum(sum) = only_metric(batch) else {
+            panic!("sums stay sums")
+        };
+        let point = &sum.data_points[0];
+        assert_eq!(
+            Some(number_data_point::Value::AsInt(3)),
+            point.value,
+            "expired series start over"
+        );
+        assert_eq!(
+            (now - window_width).nanos_since_epoch(),
+            point.start_time_unix_nano
+        );
+    }
+
+    #[test_log::test]
+    fn idle_cumulative_statistic_sets_leave_out_min_and_max() {
+        let mut batcher = cumulative_batcher();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let window_width = Duration::from_secs(10);
+        let statistic_set = Aggregation::StatisticSet(StatisticSet {
+            min: 1,
+            max: 3,
+            sum: 4,
+            count: 2,
+        });
+
+        let batch = batcher.batch_aggregations(
+            start + window_width,
+            window_width,
+            &mut window(statistic_set),
+        );
+        let mut names: Vec<_> = batch.iter().map(|metric| metric.name.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            vec![
+                "api_requests_count",
+                "api_requests_max",
+                "api_requests_min",
+                "api_requests_sum"
+            ],
+            names
+        );
+
+        let batch = batcher.batch_aggregations(
+            start + window_width * 2,
+            window_width,
+            &mut Default::default(),
+        );
+        let mut names: Vec<_> = batch.iter().map(|metric| metric.name.as_str()).collect();
+        names.sort();
+        assert_eq!(vec!["api_requests_count", "api_requests_sum"], names);
+    }
+
+    #[test_log::test]
+    fn cumulative_exemplars_go_out_once() {
+        let mut batcher = cumulative_batcher();
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let window_width = Duration::from_secs(10);
+        let mut histogram = Histogram::default();
+        histogram.accumulate(5);
+        let mut reservoir = ExemplarReservoir::new(1);
+        reservoir.offer(Exemplar {
+            trace_id: [1; 16],
+            span_id: [2; 8],
+            value: 5.0,
+            time: start,
+        });
+        let mut exemplars = ExemplarsMap::from([(
+            Name::from("api"),
+            HashMap::from([(
+                BTreeMap::from([(Name::from("host"), Dimension::from("a"))]),
+                HashMap::from([(Name::from("requests"), reservoir)]),
+            )]),
+        )]);
+
+        let batch = batcher.batch_aggregations_with_exemplars(
+            start + window_width,
+            window_width,
+            &mut window(Aggregation::Histogram(histogram.clone())),
+            &mut exemplars,
+        );
+        let Data::Histogram(first) = only_metric(batch) else {
+            panic!("histograms stay histograms")
+        };
+        assert_eq!(1, first.data_points[0].exemplars.len());
+        assert_eq!(vec![1; 16], first.data_points[0].exemplars[0].trace_id);
+
+        let batch = batcher.batch_aggregations(
+            start + window_width * 2,
+            window_width,
+            &mut window(Aggregation::Histogram(histogram)),
+        );
+        let Data::Histogram(second) = only_metric(batch) else {
+            panic!("histograms stay histograms")
+        };
+        assert_eq!(2, second.data_points[0].count);
+        assert!(
+            second.data_points[0].exemplars.is_empty(),
+            "exemplars are for the window they were recorded in"
+        );
+    }
+
+    #[test_log::test]
+    fn described_measurements_have_units() {
+        let metadata = MetadataRegistry::default();
+        metadata.describe("api", "requests", "ms", "request latency");
+        let mut batcher = OpentelemetryBatcher.with_metadata(metadata);
+
+        let batch = batcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(10),
+            &mut window(Aggregation::StatisticSet(StatisticSet {
+                min: 1,
+                max: 3,
+                sum: 4,
+                count: 2,
+            })),
+        );
+        let units: BTreeMap<String, (String, String)> = batch
+            .into_iter()
+            .map(|metric| (metric.name, (metric.unit, metric.description)))
+            .collect();
+        let described = |unit: &str| (unit.to_string(), "request latency".to_string());
+        assert_eq!(
+            BTreeMap::from([
+                ("api_requests_count".to_string(), described("1")),
+                ("api_requests_max".to_string(), described("ms")),
+                ("api_requests_min".to_string(), described("ms")),
+                ("api_requests_sum".to_string(), described("ms")),
+            ]),
+            units
+        );
+
+        let batch = batcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(10),
+            &mut window(Aggregation::Sum(Sum { sum: 1 })),
+        );
+        assert_eq!("ms", batch[0].unit);
+
+        let mut undescribed = OpentelemetryBatcher;
+        let batch = undescribed.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(10),
+            &mut window(Aggregation::Sum(Sum { sum: 1 })),
+        );
+        assert_eq!(
+            ("1", ""),
+            (batch[0].unit.as_str(), batch[0].description.as_str())
+        );
+    }
+
     #[test_log::test(tokio::test)]
     async fn downstream_is_runnable() {
         let (_sink, receiver) = StreamSink::<Box<Metrics>>::new();
         let aggregator = Aggregator::new(receiver, DistributionMode::Histogram);
         let (batch_sender, batch_receiver) = mpsc::channel(128);
 
-        let client = get_client("localhost:6379", || None, MetricsServiceClient::with_origin)
-            .expect("I can make ");
+        let client = get_client(
+            "localhost:6379",
+            &TlsConfiguration::dangerously_accept_any_certificate(),
+            MetricsServiceClient::with_origin,
+        )
+        .expect("I can make ");
 
         let downstream =
             OpenTelemetryDownstream::new(client, Option::<(&str, AsciiMetadataValue)>::None);
@@ -594,4 +1846,180 @@ mod test {
         downstream_joiner.abort();
         metrics_tasks.await;
     }
+
+    #[test_log::test(tokio::test)]
+    async fn datapoint_limits_split_requests() {
+        let client = get_client(
+            "http://localhost:6379",
+            &TlsConfiguration::dangerously_accept_any_certificate(),
+            MetricsServiceClient::with_origin,
+        )
+        .expect("I can make a client");
+        let mut request_limits = RequestLimits::default();
+        request_limits.max_datapoints(3);
+        let mut downstream = OpenTelemetryDownstream::new_with_dimensions(
+            client,
+            Option::<(&str, AsciiMetadataValue)>::None,
+            [("service.name", "test")],
+        )
+        .with_request_limits(request_limits);
+
+        let batch: Vec<Metric> = (0..10)
+            .map(|i| Metric {
+                name: format!("metric_{i}"),
+                data: Some(Data::Gauge(Gauge {
+                    data_points: vec![Default::default()],
+                })),
+                ..Default::default()
+            })
+            .collect();
+        let requests = Delivery::prepare(&mut downstream, unscoped(batch));
+
+        assert_eq!(
+            vec![3, 3, 3, 1],
+            requests
+                .iter()
+                .map(|request| request.resource_metrics[0].scope_metrics[0].metrics.len())
+                .collect::<Vec<_>>()
+        );
+        for request in &requests {
+            assert_eq!(
+                Some("service.name"),
+                request.resource_metrics[0]
+                    .resource
+                    .as_ref()
+                    .map(|resource| resource.attributes[0].key.as_str())
+            );
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn resource_and_scope_are_configurable() {
+        let client = get_client(
+            "http://localhost:6379",
+            &TlsConfiguration::dangerously_accept_any_certificate(),
+            MetricsServiceClient::with_origin,
+        )
+        .expect("I can make a client");
+        let mut downstream = OpenTelemetryDownstream::new_with_dimensions(
+            client,
+            Option::<(&str, AsciiMetadataValue)>::None,
+            [("host.name", "a"), ("service.name", "replaced")],
+        )
+        .with_resource(
+            OtlpResource::default()
+                .with_service_name("checkout")
+                .with_deployment_environment("production")
+                .with_schema_url("https://opentelemetry.io/schemas/1.26.0"),
+        )
+        .with_instrumentation_scope("checkout_app", "2.0.0");
+
+        let request = Delivery::prepare(&mut downstream, unscoped(vec![])).remove(0);
+        let resource_metrics = &request.resource_metrics[0];
+        assert_eq!(
+            "https://opentelemetry.io/schemas/1.26.0",
+            resource_metrics.schema_url
+        );
+        let attributes: Vec<_> = resource_metrics
+            .resource
+            .as_ref()
+            .expect("there is a resource")
+            .attributes
+            .iter()
+            .map(|attribute| (attribute.key.as_str(), attribute.value.clone()))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "deployment.environment",
+                    Some(Dimension::from("production").into())
+                ),
+                ("host.name", Some(Dimension::from("a").into())),
+                ("service.name", Some(Dimension::from("checkout").into())),
+            ],
+            attributes
+        );
+        let scope = resource_metrics.scope_metrics[0].scope.as_ref();
+        assert_eq!(Some("checkout_app"), scope.map(|scope| scope.name.as_str()));
+
+        let library_metadata = MetadataRegistry::default();
+        library_metadata.set_instrumentation_scope("http_client", "0.4.1");
+        let mut library_batcher = OpentelemetryBatcher
+            .with_metadata(library_metadata)
+            .scoped();
+        let batch = library_batcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(1),
+            &mut AggregatedMetricsMap::default(),
+        );
+        let request = Delivery::prepare(&mut downstream, batch).remove(0);
+        let scope = request.resource_metrics[0].scope_metrics[0].scope.as_ref();
+        assert_eq!(
+            Some(("http_client", "0.4.1")),
+            scope.map(|scope| (scope.name.as_str(), scope.version.as_str()))
+        );
+    }
+
+    /// Accepts everything but 2 data points
+    struct PickyCollector;
+
+    #[tonic::async_trait]
+    impl MetricsService for PickyCollector {
+        async fn export(
+            &self,
+            _request: tonic::Request<ExportMetricsServiceRequest>,
+        ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+            Ok(tonic::Response::new(ExportMetricsServiceResponse {
+                partial_success: Some(ExportMetricsPartialSuccess {
+                    rejected_data_points: 2,
+                    error_message: "too old".to_string(),
+                }),
+            }))
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn partial_success_is_reported() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("can bind a local port");
+        let address = listener.local_addr().expect("bound address");
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(MetricsServiceServer::new(PickyCollector))
+                .serve_with_incoming(tonic::transport::server::TcpIncoming::from(listener)),
+        );
+
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let record = reported.clone();
+        let downstream = OpenTelemetryDownstream::new(
+            get_client(
+                &format!("http://{address}"),
+                &TlsConfiguration::dangerously_accept_any_certificate(),
+                MetricsServiceClient::with_origin,
+            )
+            .expect("I can make a client"),
+            Option::<(&str, AsciiMetadataValue)>::None,
+        )
+        .with_partial_success_callback(move |partial| {
+            record.lock().expect("local mutex").push(partial.clone())
+        });
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([vec![Metric {
+                name: "a".to_string(),
+                ..Default::default()
+            }]])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+
+        assert_eq!(
+            vec![PartialSuccess {
+                rejected_data_points: 2,
+                error_message: "too old".to_string(),
+            }],
+            *reported.lock().expect("local mutex")
+        );
+    }
 }
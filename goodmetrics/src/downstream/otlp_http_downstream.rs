@@ -0,0 +1,487 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    header::{HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER},
+    HeaderMap, Method, StatusCode, Uri,
+};
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::{
+    proto::opentelemetry::{
+        collector::metrics::v1::{ExportMetricsServiceRequest, ExportMetricsServiceResponse},
+        common::v1::InstrumentationScope,
+        metrics::v1::{Metric, ScopeMetrics},
+    },
+    types::{Dimension, Name},
+};
+
+use super::{
+    delivery::{Delivery, DeliveryFailure, DeliveryQueue},
+    opentelemetry_downstream::{
+        default_scope, export_request, report_partial_success, unscoped, PartialSuccess,
+        PartialSuccessCallback,
+    },
+    spool::SpoolPosition,
+    DeliveryConfiguration, HttpChannelType, OtlpResource, Spool, StdError,
+};
+
+/// How to put OTLP on the wire over http
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpHttpEncoding {
+    /// `application/x-protobuf`. Smaller and cheaper; use this unless you can't.
+    Protobuf,
+    /// `application/json`, the OTLP json mapping of the same protos.
+    Json,
+}
+
+impl OtlpHttpEncoding {
+    fn content_type(&self) -> HeaderValue {
+        match self {
+            OtlpHttpEncoding::Protobuf => HeaderValue::from_static("application/x-protobuf"),
+            OtlpHttpEncoding::Json => HeaderValue::from_static("application/json"),
+        }
+    }
+}
+
+/// OTLP over plain http, for collectors that are only reachable on the OTLP/HTTP port (usually 4318).
+/// Takes the same batches as OpenTelemetryDownstream, from OpentelemetryBatcher.
+pub struct OtlpHttpDownstream {
+    client: HttpChannelType,
+    uri: Uri,
+    encoding: OtlpHttpEncoding,
+    headers: HeaderMap,
+    resource: OtlpResource,
+    scope: InstrumentationScope,
+    delivery_configuration: DeliveryConfiguration,
+    on_partial_success: Option<PartialSuccessCallback>,
+}
+
+impl OtlpHttpDownstream {
+    /// Create a new OTLP/HTTP metrics sender. The endpoint is the collector's base url,
+    /// like `http://localhost:4318`; `/v1/metrics` is added for you.
+    pub fn new(
+        client: HttpChannelType,
+        endpoint: &str,
+        encoding: OtlpHttpEncoding,
+    ) -> Result<Self, StdError> {
+        Ok(Self {
+            client,
+            uri: Uri::from_str(&format!("{}/v1/metrics", endpoint.trim_end_matches('/')))?,
+            encoding,
+            headers: Default::default(),
+            resource: OtlpResource::default(),
+            scope: default_scope(),
+            delivery_configuration: Default::default(),
+            on_partial_success: None,
+        })
+    }
+
+    /// Create a new OTLP/HTTP metrics sender, with a set of dimensions applied to all metrics sent through it.
+    pub fn new_with_dimensions(
+        client: HttpChannelType,
+        endpoint: &str,
+        encoding: OtlpHttpEncoding,
+        shared_dimensions: impl IntoIterator<Item = (impl Into<Name>, impl Into<Dimension>)>,
+    ) -> Result<Self, StdError> {
+        let mut downstream = Self::new(client, endpoint, encoding)?;
+        downstream.resource = shared_dimensions.into_iter().collect();
+        Ok(downstream)
+    }
+
+    /// Add a header to every request, like an api key
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Describe what is sending these metrics, like its `service.name`. Attributes are added to
+    /// the shared dimensions from new_with_dimensions, and win when they have the same name.
+    pub fn with_resource(mut self, resource: OtlpResource) -> Self {
+        self.resource.extend(resource);
+        self
+    }
+
+    /// Report metrics under this instrumentation scope, rather than goodmetrics and its version.
+    /// Batches from a ScopedOpentelemetryBatcher with a scope of their own keep theirs.
+    pub fn with_instrumentation_scope(
+        mut self,
+        name: impl Into<String>,
+        version: impl Into<String>,
+    ) -> Self {
+        self.scope = InstrumentationScope {
+            name: name.into(),
+            version: version.into(),
+        };
+        self
+    }
+
+    /// Customize how failed batches are retried. By default, batches are retried for up to 2 minutes.
+    pub fn with_delivery_configuration(
+        mut self,
+        delivery_configuration: DeliveryConfiguration,
+    ) -> Self {
+        self.delivery_configuration = delivery_configuration;
+        self
+    }
+
+    /// Hear about data points the collector rejected, like to count them on your dashboards.
+    /// They are logged either way, and counted in `gm_introspect` with the introspect feature.
+    pub fn with_partial_success_callback(
+        mut self,
+        on_partial_success: impl Fn(&PartialSuccess) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_partial_success = Some(Arc::new(on_partial_success));
+        self
+    }
+
+    /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
+    pub async fn send_batches_forever(self, receiver: mpsc::Receiver<Vec<Metric>>) {
+        self.send_metrics_stream_forever(ReceiverStream::new(receiver))
+            .await;
+    }
+
+    /// Spawn this on a tokio runtime to send your metrics to your downstream receiver
+    pub async fn send_metrics_stream_forever(
+        self,
+        receiver: impl Stream<Item = Vec<Metric>> + Unpin,
+    ) {
+        self.send_scoped_metrics_stream_forever(receiver.map(unscoped))
+            .await
+    }
+
+    /// Like send_batches_forever, for batches from ScopedOpentelemetryBatchers
+    pub async fn send_scoped_batches_forever(self, receiver: mpsc::Receiver<ScopeMetrics>) {
+        self.send_scoped_metrics_stream_forever(ReceiverStream::new(receiver))
+            .await;
+    }
+
+    /// Like send_metrics_stream_forever, for batches from ScopedOpentelemetryBatchers
+    pub async fn send_scoped_metrics_stream_forever(
+        self,
+        receiver: impl Stream<Item = ScopeMetrics> + Unpin,
+    ) {
+        DeliveryQueue::new(self.delivery_configuration.clone())
+            .deliver_forever(self, receiver)
+            .await
+    }
+
+    fn encode(&self, request: &ExportMetricsServiceRequest) -> Result<Bytes, serde_json::Error> {
+        Ok(match self.encoding {
+            OtlpHttpEncoding::Protobuf => request.encode_to_vec().into(),
+            OtlpHttpEncoding::Json => serde_json::to_vec(request)?.into(),
+        })
+    }
+
+    /// Report what the collector said it could not accept
+    fn report_partial_success(&self, body: &[u8]) {
+        let (rejected_data_points, error_message) = match self.encoding {
+            OtlpHttpEncoding::Protobuf => match ExportMetricsServiceResponse::decode(body) {
+                Ok(response) => match response.partial_success {
+                    Some(partial) => (partial.rejected_data_points, partial.error_message),
+                    None => return,
+                },
+                Err(e) => {
+                    log::debug!("could not decode export response: {e}");
+                    return;
+                }
+            },
+            OtlpHttpEncoding::Json => {
+                let Ok(response) = serde_json::from_slice::<serde_json::Value>(body) else {
+                    log::debug!("could not decode export response json");
+                    return;
+                };
+                let partial = &response["partialSuccess"];
+                // int64 may come as a number or as a string in otlp json
+                let rejected = &partial["rejectedDataPoints"];
+                (
+                    rejected
+                        .as_i64()
+                        .or_else(|| rejected.as_str().and_then(|r| r.parse().ok()))
+                        .unwrap_or_default(),
+                    partial["errorMessage"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                )
+            }
+        };
+        report_partial_success(
+            PartialSuccess {
+                rejected_data_points,
+                error_message,
+            },
+            self.on_partial_success.as_ref(),
+        );
+    }
+}
+
+impl Delivery for OtlpHttpDownstream {
+    type Batch = ScopeMetrics;
+    type Request = Bytes;
+
+    fn prepare(&mut self, mut batch: ScopeMetrics) -> Vec<Bytes> {
+        batch.scope.get_or_insert_with(|| self.scope.clone());
+        match self.encode(&export_request(&self.resource, batch)) {
+            Ok(request) => vec![request],
+            Err(e) => {
+                // Retrying would not encode any better, and an empty body could be accepted
+                log::error!("failed to encode metrics as json: {e} - dropping the batch");
+                vec![]
+            }
+        }
+    }
+
+    fn unspool_batch(&self, spool: &Spool) -> Option<(SpoolPosition, ScopeMetrics)> {
+        spool.peek()
+    }
+
+    async fn send(&mut self, request: Bytes) -> Result<(), DeliveryFailure> {
+        let mut http_request = hyper::Request::new(Full::new(request));
+        *http_request.method_mut() = Method::POST;
+        *http_request.uri_mut() = self.uri.clone();
+        *http_request.headers_mut() = self.headers.clone();
+        http_request
+            .headers_mut()
+            .insert(CONTENT_TYPE, self.encoding.content_type());
+
+        let response = match self.client.request(http_request).await {
+            Ok(response) => response,
+            Err(e) => {
+                log::error!("failed to send metrics: {e:?}");
+                return Err(DeliveryFailure::retryable());
+            }
+        };
+        let status = response.status();
+        let retry_after = retry_after(response.headers());
+        let body = match response.into_body().collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => {
+                log::warn!("failed to read export response: {e}");
+                Bytes::new()
+            }
+        };
+
+        if status.is_success() {
+            log::debug!("sent metrics: {status}");
+            self.report_partial_success(&body);
+            return Ok(());
+        }
+        log::error!(
+            "failed to send metrics: {status}: {}",
+            String::from_utf8_lossy(&body)
+        );
+        // Per the otlp spec, only these are worth trying again
+        match status {
+            StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => {
+                Err(DeliveryFailure::retryable().with_retry_after(retry_after))
+            }
+            _ => Err(DeliveryFailure::permanent()),
+        }
+    }
+}
+
+/// Only the delay-seconds form. Collectors don't send http dates in practice.
+pub(super) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Full};
+    use hyper::{service::service_fn, Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+    use prost::Message;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::RootCertStore;
+
+    use crate::{
+        downstream::{get_http_client, DeliveryConfiguration, TlsConfiguration},
+        proto::opentelemetry::{
+            collector::metrics::v1::{
+                ExportMetricsPartialSuccess, ExportMetricsServiceRequest,
+                ExportMetricsServiceResponse,
+            },
+            metrics::v1::{metric::Data, Gauge, Metric, NumberDataPoint},
+        },
+    };
+
+    use super::{OtlpHttpDownstream, OtlpHttpEncoding};
+
+    #[derive(Debug, Clone)]
+    struct Received {
+        path: String,
+        content_type: String,
+        body: Bytes,
+    }
+
+    /// Answers each request with the next status, recording what it received
+    async fn serve(statuses: Vec<StatusCode>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let statuses = Arc::new(Mutex::new(statuses.into_iter()));
+        let record = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let record = record.clone();
+                let statuses = statuses.clone();
+                tokio::spawn(hyper::server::conn::http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(move |request: hyper::Request<hyper::body::Incoming>| {
+                        let record = record.clone();
+                        let status = statuses.lock().unwrap().next().unwrap_or(StatusCode::OK);
+                        async move {
+                            let path = request.uri().path().to_string();
+                            let content_type = request.headers()["content-type"]
+                                .to_str()
+                                .unwrap()
+                                .to_string();
+                            let body = request.into_body().collect().await.unwrap().to_bytes();
+                            record.lock().unwrap().push(Received {
+                                path,
+                                content_type,
+                                body,
+                            });
+                            let body = if status == StatusCode::OK {
+                                ExportMetricsServiceResponse {
+                                    partial_success: Some(ExportMetricsPartialSuccess {
+                                        rejected_data_points: 1,
+                                        error_message: "nope".to_string(),
+                                    }),
+                                }
+                                .encode_to_vec()
+                            } else {
+                                vec![]
+                            };
+                            Ok::<_, Infallible>(
+                                Response::builder()
+                                    .status(status)
+                                    .header("retry-after", "0")
+                                    .body(Full::new(Bytes::from(body)))
+                                    .unwrap(),
+                            )
+                        }
+                    }),
+                ));
+            }
+        });
+        (format!("http://{address}"), received)
+    }
+
+    fn batch() -> Vec<Metric> {
+        vec![Metric {
+            name: "a_gauge".to_string(),
+            description: "".to_string(),
+            unit: "1".to_string(),
+            data: Some(Data::Gauge(Gauge {
+                data_points: vec![NumberDataPoint {
+                    time_unix_nano: 1,
+                    value: Some(3_i64.into()),
+                    ..Default::default()
+                }],
+            })),
+        }]
+    }
+
+    fn fast_retries() -> DeliveryConfiguration {
+        let mut configuration = DeliveryConfiguration::default();
+        configuration.initial_backoff(Duration::from_millis(1));
+        configuration
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn protobuf_retries_unavailable() {
+        let (endpoint, received) = serve(vec![StatusCode::SERVICE_UNAVAILABLE]).await;
+        let downstream = OtlpHttpDownstream::new(
+            get_http_client(&TlsConfiguration::with_roots(RootCertStore::empty())).unwrap(),
+            &endpoint,
+            OtlpHttpEncoding::Protobuf,
+        )
+        .unwrap()
+        .with_delivery_configuration(fast_retries());
+        downstream
+            .send_metrics_stream_forever(futures::stream::iter([batch()]))
+            .await;
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!(2, received.len(), "503 should be retried");
+        assert_eq!("/v1/metrics", received[1].path);
+        assert_eq!("application/x-protobuf", received[1].content_type);
+        let request = ExportMetricsServiceRequest::decode(received[1].body.clone()).unwrap();
+        assert_eq!(
+            batch(),
+            request.resource_metrics[0].scope_metrics[0].metrics
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn bad_requests_are_not_retried() {
+        let (endpoint, received) = serve(vec![StatusCode::BAD_REQUEST]).await;
+        let downstream = OtlpHttpDownstream::new(
+            get_http_client(&TlsConfiguration::with_roots(RootCertStore::empty())).unwrap(),
+            &endpoint,
+            OtlpHttpEncoding::Protobuf,
+        )
+        .unwrap()
+        .with_delivery_configuration(fast_retries());
+        downstream
+            .send_metrics_stream_forever(futures::stream::iter([batch()]))
+            .await;
+
+        assert_eq!(1, received.lock().unwrap().len());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn json() {
+        let (endpoint, received) = serve(vec![]).await;
+        let downstream = OtlpHttpDownstream::new_with_dimensions(
+            get_http_client(&TlsConfiguration::with_roots(RootCertStore::empty())).unwrap(),
+            &format!("{endpoint}/"),
+            OtlpHttpEncoding::Json,
+            [("service.name", "test")],
+        )
+        .unwrap();
+        downstream
+            .send_metrics_stream_forever(futures::stream::iter([batch()]))
+            .await;
+
+        let received = received.lock().unwrap().clone();
+        assert_eq!("/v1/metrics", received[0].path);
+        assert_eq!("application/json", received[0].content_type);
+        let json: serde_json::Value = serde_json::from_slice(&received[0].body).unwrap();
+        let resource_metrics = &json["resourceMetrics"][0];
+        assert_eq!(
+            serde_json::json!({"key": "service.name", "value": {"stringValue": "test"}}),
+            resource_metrics["resource"]["attributes"][0]
+        );
+        let metric = &resource_metrics["scopeMetrics"][0]["metrics"][0];
+        assert_eq!("a_gauge", metric["name"]);
+        let point = &metric["gauge"]["dataPoints"][0];
+        assert_eq!(1, point["timeUnixNano"]);
+        assert_eq!(3, point["asInt"]);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{
+    pipeline::DimensionPosition,
+    proto::opentelemetry::resource::v1::Resource,
+    types::{Dimension, Name},
+};
+
+use super::opentelemetry_downstream::as_otel_dimensions;
+
+/// What is producing your metrics, for otlp downstreams: attributes like `service.name`,
+/// and the schema url those attributes follow.
+/// ```
+/// # use goodmetrics::downstream::OtlpResource;
+/// let resource = OtlpResource::default()
+///     .with_service_name("checkout")
+///     .with_service_version("1.2.3")
+///     .with_deployment_environment("production")
+///     .with_attribute("host.name", "checkout-7")
+///     .with_schema_url("https://opentelemetry.io/schemas/1.26.0");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OtlpResource {
+    attributes: DimensionPosition,
+    schema_url: String,
+}
+
+impl OtlpResource {
+    /// Set `service.name`
+    pub fn with_service_name(self, service_name: impl Into<String>) -> Self {
+        self.with_attribute("service.name", service_name.into())
+    }
+
+    /// Set `service.version`
+    pub fn with_service_version(self, service_version: impl Into<String>) -> Self {
+        self.with_attribute("service.version", service_version.into())
+    }
+
+    /// Set `deployment.environment`, like "production"
+    pub fn with_deployment_environment(self, environment: impl Into<String>) -> Self {
+        self.with_attribute("deployment.environment", environment.into())
+    }
+
+    /// Set any resource attribute
+    pub fn with_attribute(mut self, name: impl Into<Name>, value: impl Into<Dimension>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    /// Set the schema url the attributes follow, like "https://opentelemetry.io/schemas/1.26.0"
+    pub fn with_schema_url(mut self, schema_url: impl Into<String>) -> Self {
+        self.schema_url = schema_url.into();
+        self
+    }
+
+    /// Take other's attributes, and its schema url if it has one
+    pub(crate) fn extend(&mut self, other: OtlpResource) {
+        self.attributes.extend(other.attributes);
+        if !other.schema_url.is_empty() {
+            self.schema_url = other.schema_url;
+        }
+    }
+
+    /// The resource to put on the wire, if there is anything to say
+    pub(crate) fn resource(&self) -> Option<Resource> {
+        if self.attributes.is_empty() {
+            return None;
+        }
+        Some(Resource {
+            attributes: as_otel_dimensions(self.attributes.clone()),
+            dropped_attributes_count: 0,
+        })
+    }
+
+    pub(crate) fn schema_url(&self) -> &str {
+        &self.schema_url
+    }
+}
+
+impl<TName, TDimension> FromIterator<(TName, TDimension)> for OtlpResource
+where
+    TName: Into<Name>,
+    TDimension: Into<Dimension>,
+{
+    fn from_iter<T: IntoIterator<Item = (TName, TDimension)>>(iter: T) -> Self {
+        Self {
+            attributes: iter
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into()))
+                .collect(),
+            schema_url: String::new(),
+        }
+    }
+}
@@ -0,0 +1,806 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    header::{ACCEPT, CONTENT_TYPE},
+    service::service_fn,
+    Method, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::{
+    aggregation::Cumulative,
+    pipeline::{AggregatedMetricsMap, AggregationBatcher, DimensionPosition},
+    types::{Dimension, Name},
+};
+
+/// How PrometheusBatcher exposes StatisticSets, like from measurements and statistic set gauges
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatisticSetRepresentation {
+    /// A summary with no quantiles: `_sum` and `_count` that only go up.
+    #[default]
+    Summary,
+    /// `_min`, `_max`, `_sum` and `_count` gauges for the latest window.
+    Gauges,
+}
+
+/// One series for a Prometheus scrape: a metric name, its labels and its current value.
+/// These come from PrometheusBatcher and go to PrometheusDownstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrometheusSeries {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: PrometheusValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PrometheusValue {
+    Counter(f64),
+    Gauge(f64),
+    Summary {
+        quantiles: Vec<(f64, f64)>,
+        sum: f64,
+        count: u64,
+    },
+    /// Bucket counts are cumulative, ascending by upper bound, and do not include +Inf
+    Histogram {
+        buckets: Vec<(f64, u64)>,
+        sum: f64,
+        count: u64,
+    },
+    /// The series went idle and expired. The downstream forgets it instead of storing this.
+    Expired,
+}
+
+impl PrometheusValue {
+    fn type_name(&self) -> &'static str {
+        match self {
+            PrometheusValue::Counter(_) => "counter",
+            PrometheusValue::Gauge(_) => "gauge",
+            PrometheusValue::Summary { .. } => "summary",
+            PrometheusValue::Histogram { .. } => "histogram",
+            PrometheusValue::Expired => "unknown",
+        }
+    }
+}
+
+/// Maps aggregations to Prometheus series. Prometheus expects values that only go up,
+/// so this keeps running totals across windows: Sums become counters, distributions
+/// become histograms or summaries, and StatisticSets become summaries or gauges.
+///
+/// The totals cost memory per series. Series that are not recorded for idle_series_expiry
+/// (default 10 minutes) are forgotten, here and in the PrometheusDownstream; if they come
+/// back, they start over at 0.
+///
+/// Use 1 per Aggregator or GaugeFactory, and point them all at the same PrometheusDownstream.
+#[derive(Debug)]
+pub struct PrometheusBatcher {
+    statistic_set_representation: StatisticSetRepresentation,
+    tdigest_quantiles: Option<Vec<f64>>,
+    idle_series_expiry: Duration,
+    totals: HashMap<(Name, DimensionPosition, Name), PrometheusTotal>,
+}
+
+impl Default for PrometheusBatcher {
+    fn default() -> Self {
+        Self {
+            statistic_set_representation: Default::default(),
+            tdigest_quantiles: None,
+            idle_series_expiry: Duration::from_secs(600),
+            totals: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PrometheusTotal {
+    cumulative: Cumulative,
+    last_updated: SystemTime,
+}
+
+impl PrometheusBatcher {
+    /// Choose how StatisticSets are exposed. By default they are summaries.
+    pub fn with_statistic_set_representation(
+        mut self,
+        statistic_set_representation: StatisticSetRepresentation,
+    ) -> Self {
+        self.statistic_set_representation = statistic_set_representation;
+        self
+    }
+
+    /// Choose the quantiles reported for t-digests (default 0.5, 0.9, 0.99, 0.999)
+    pub fn with_tdigest_quantiles(mut self, quantiles: Vec<f64>) -> Self {
+        self.tdigest_quantiles = Some(quantiles);
+        self
+    }
+
+    /// How long a series may go without new data before it is dropped (default 10 minutes)
+    pub fn with_idle_series_expiry(mut self, idle_series_expiry: Duration) -> Self {
+        self.idle_series_expiry = idle_series_expiry;
+        self
+    }
+}
+
+impl AggregationBatcher for PrometheusBatcher {
+    type TBatch = Vec<PrometheusSeries>;
+
+    fn batch_aggregations(
+        &mut self,
+        now: SystemTime,
+        _covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+    ) -> Self::TBatch {
+        let Self {
+            statistic_set_representation,
+            tdigest_quantiles,
+            idle_series_expiry,
+            totals,
+        } = self;
+        let mut batch = Vec::new();
+        for (name, mut dimensioned_measurements) in aggregations.drain() {
+            for (dimension_position, mut measurements) in dimensioned_measurements.drain() {
+                let labels = as_labels(&dimension_position);
+                for (measurement_name, aggregation) in measurements.drain() {
+                    let series_name = metric_name(&format!("{name}_{measurement_name}"));
+                    let total = match totals.entry((
+                        name.clone(),
+                        dimension_position.clone(),
+                        measurement_name,
+                    )) {
+                        std::collections::hash_map::Entry::Occupied(mut occupied) => {
+                            let total = occupied.get_mut();
+                            total.cumulative.accumulate(aggregation);
+                            total.last_updated = now;
+                            occupied.into_mut()
+                        }
+                        std::collections::hash_map::Entry::Vacant(vacant) => {
+                            vacant.insert(PrometheusTotal {
+                                cumulative: Cumulative::new(aggregation),
+                                last_updated: now,
+                            })
+                        }
+                    };
+                    batch.extend(series(
+                        *statistic_set_representation,
+                        tdigest_quantiles.as_deref(),
+                        &series_name,
+                        labels.clone(),
+                        &total.cumulative,
+                    ));
+                }
+            }
+        }
+        totals.retain(|(name, dimension_position, measurement_name), total| {
+            if now.duration_since(total.last_updated).unwrap_or_default() < *idle_series_expiry {
+                return true;
+            }
+            // Tell the downstream to forget every series this total was exposed as
+            batch.extend(
+                series(
+                    *statistic_set_representation,
+                    tdigest_quantiles.as_deref(),
+                    &metric_name(&format!("{name}_{measurement_name}")),
+                    as_labels(dimension_position),
+                    &total.cumulative,
+                )
+                .into_iter()
+                .map(|expired| PrometheusSeries {
+                    value: PrometheusValue::Expired,
+                    ..expired
+                }),
+            );
+            false
+        });
+        batch
+    }
+}
+
+/// Which text format a scraper gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpositionFormat {
+    /// The classic Prometheus text format, version 0.0.4
+    Prometheus,
+    /// OpenMetrics 1.0 text
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            ExpositionFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+}
+
+type Families = BTreeMap<String, BTreeMap<Vec<(String, String)>, PrometheusValue>>;
+
+/// A pull-mode downstream: it holds the latest value of every series and serves them to scrapers.
+///
+/// Feed it from as many PrometheusBatchers as you like with send_batches_forever, and
+/// serve_forever on a listener for Prometheus to scrape `/metrics`.
+/// ```no_run
+/// # use std::time::Duration;
+/// # use goodmetrics::default_gauge_factory;
+/// # use goodmetrics::downstream::{PrometheusBatcher, PrometheusDownstream};
+/// # async fn example() {
+/// let downstream = PrometheusDownstream::default();
+/// let (sender, receiver) = tokio::sync::mpsc::channel(128);
+/// tokio::spawn(downstream.clone().send_batches_forever(receiver));
+/// tokio::spawn(default_gauge_factory().clone().report_gauges_forever(
+///     Duration::from_secs(10),
+///     sender.clone(),
+///     PrometheusBatcher::default(),
+/// ));
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:9090").await.expect("can bind");
+/// tokio::spawn(downstream.serve_forever(listener));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusDownstream {
+    families: Arc<Mutex<Families>>,
+}
+
+impl PrometheusDownstream {
+    /// Spawn this on a tokio runtime to keep the scrape endpoint up to date
+    pub async fn send_batches_forever(self, mut receiver: mpsc::Receiver<Vec<PrometheusSeries>>) {
+        while let Some(batch) = receiver.recv().await {
+            self.update(batch);
+        }
+    }
+
+    fn update(&self, batch: Vec<PrometheusSeries>) {
+        let mut families = self
+            .families
+            .lock()
+            .expect("local mutex should not be poisoned");
+        for series in batch {
+            if series.value == PrometheusValue::Expired {
+                if let Some(family) = families.get_mut(&series.name) {
+                    family.remove(&series.labels);
+                    if family.is_empty() {
+                        families.remove(&series.name);
+                    }
+                }
+                continue;
+            }
+            families
+                .entry(series.name)
+                .or_default()
+                .insert(series.labels, series.value);
+        }
+    }
+
+    /// Render everything in a scrape format
+    pub fn render(&self, format: ExpositionFormat) -> String {
+        let families = self
+            .families
+            .lock()
+            .expect("local mutex should not be poisoned");
+        render(&families, format)
+    }
+
+    /// Spawn this on a tokio runtime to serve scrapes on `/metrics`.
+    /// OpenMetrics is served when the scraper asks for it.
+    pub async fn serve_forever(self, listener: TcpListener) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    log::error!("failed to accept scrape connection: {e}");
+                    continue;
+                }
+            };
+            let downstream = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(
+                        TokioIo::new(stream),
+                        service_fn(move |request| {
+                            let response = downstream.scrape(&request);
+                            async move { Ok::<_, Infallible>(response) }
+                        }),
+                    )
+                    .await
+                {
+                    log::debug!("scrape connection ended: {e}");
+                }
+            });
+        }
+    }
+
+    fn scrape<T>(&self, request: &hyper::Request<T>) -> Response<Full<Bytes>> {
+        let mut response = Response::new(Full::default());
+        if request.method() != Method::GET || request.uri().path() != "/metrics" {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return response;
+        }
+        let format = match request.headers().get(ACCEPT).and_then(|a| a.to_str().ok()) {
+            Some(accept) if accept.contains("application/openmetrics-text") => {
+                ExpositionFormat::OpenMetrics
+            }
+            _ => ExpositionFormat::Prometheus,
+        };
+        *response.body_mut() = Full::new(self.render(format).into());
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static(format.content_type()),
+        );
+        response
+    }
+}
+
+fn render(families: &Families, format: ExpositionFormat) -> String {
+    let mut out = String::new();
+    for (name, series) in families {
+        let Some(first) = series.values().next() else {
+            continue;
+        };
+        let type_name = first.type_name();
+        // OpenMetrics names counter families without the _total, and the classic format names them with it
+        let family_name = match (first, format) {
+            (PrometheusValue::Counter(_), ExpositionFormat::Prometheus) => format!("{name}_total"),
+            _ => name.clone(),
+        };
+        let _ = writeln!(out, "# TYPE {family_name} {type_name}");
+        for (labels, value) in series {
+            if value.type_name() != type_name {
+                log::debug!("skipping {name} series that changed type");
+                continue;
+            }
+            match value {
+                PrometheusValue::Counter(v) => {
+                    write_sample(&mut out, name, "_total", labels, None, *v)
+                }
+                PrometheusValue::Gauge(v) => write_sample(&mut out, name, "", labels, None, *v),
+                PrometheusValue::Summary {
+                    quantiles,
+                    sum,
+                    count,
+                } => {
+                    for (quantile, v) in quantiles {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "",
+                            labels,
+                            Some(("quantile", *quantile)),
+                            *v,
+                        );
+                    }
+                    write_sample(&mut out, name, "_sum", labels, None, *sum);
+                    write_sample(&mut out, name, "_count", labels, None, *count as f64);
+                }
+                PrometheusValue::Histogram {
+                    buckets,
+                    sum,
+                    count,
+                } => {
+                    for (upper_bound, running_count) in buckets {
+                        write_sample(
+                            &mut out,
+                            name,
+                            "_bucket",
+                            labels,
+                            Some(("le", *upper_bound)),
+                            *running_count as f64,
+                        );
+                    }
+                    write_sample(
+                        &mut out,
+                        name,
+                        "_bucket",
+                        labels,
+                        Some(("le", f64::INFINITY)),
+                        *count as f64,
+                    );
+                    write_sample(&mut out, name, "_sum", labels, None, *sum);
+                    write_sample(&mut out, name, "_count", labels, None, *count as f64);
+                }
+                PrometheusValue::Expired => (),
+            }
+        }
+    }
+    if format == ExpositionFormat::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    suffix: &str,
+    labels: &[(String, String)],
+    extra_label: Option<(&str, f64)>,
+    value: f64,
+) {
+    let _ = write!(out, "{name}{suffix}");
+    if !labels.is_empty() || extra_label.is_some() {
+        out.push('{');
+        let mut first = true;
+        for (label, label_value) in labels {
+            if !first {
+                out.push(',');
+            }
+            first = false;
+            let _ = write!(out, "{label}=\"{}\"", escape_label_value(label_value));
+        }
+        if let Some((label, label_value)) = extra_label {
+            if !first {
+                out.push(',');
+            }
+            let _ = write!(out, "{label}=\"{}\"", format_float(label_value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {}", format_float(value));
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Prometheus names are `[a-zA-Z_:][a-zA-Z0-9_:]*`. Anything else becomes _
+fn metric_name(name: &str) -> String {
+    sanitize(name, true)
+}
+
+fn sanitize(name: &str, allow_colon: bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || (allow_colon && c == ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if sanitized.starts_with(|c: char| c.is_ascii_digit()) || sanitized.is_empty() {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn as_labels(dimension_position: &DimensionPosition) -> Vec<(String, String)> {
+    dimension_position
+        .iter()
+        .map(|(name, dimension)| (sanitize(name.as_str(), false), label_value(dimension)))
+        .collect()
+}
+
+fn label_value(dimension: &Dimension) -> String {
+    match dimension {
+        Dimension::Str(s) => s.to_string(),
+        Dimension::String(s) => s.clone(),
+        Dimension::Shared(s) => s.to_string(),
+        Dimension::Number(n) => n.to_string(),
+        Dimension::Boolean(b) => b.to_string(),
+    }
+}
+
+fn series(
+    statistic_set_representation: StatisticSetRepresentation,
+    tdigest_quantiles: Option<&[f64]>,
+    name: &str,
+    labels: Vec<(String, String)>,
+    cumulative: &Cumulative,
+) -> Vec<PrometheusSeries> {
+    let value = match cumulative {
+        Cumulative::Sum(sum) => PrometheusValue::Counter(*sum as f64),
+        Cumulative::StatisticSet { window, sum, count } => match statistic_set_representation {
+            StatisticSetRepresentation::Summary => PrometheusValue::Summary {
+                quantiles: vec![],
+                sum: *sum as f64,
+                count: *count,
+            },
+            StatisticSetRepresentation::Gauges => {
+                return [
+                    ("min", window.min as f64),
+                    ("max", window.max as f64),
+                    ("sum", window.sum as f64),
+                    ("count", window.count as f64),
+                ]
+                .into_iter()
+                .map(|(component, value)| PrometheusSeries {
+                    name: format!("{name}_{component}"),
+                    labels: labels.clone(),
+                    value: PrometheusValue::Gauge(value),
+                })
+                .collect();
+            }
+        },
+        Cumulative::Histogram(buckets) => {
+            let mut running_count = 0;
+            let mut sum = 0.0;
+            let buckets = buckets
+                .iter()
+                .map(|(bucket, count)| {
+                    running_count += count;
+                    // Goodmetrics histograms don't keep a sum. The bucket is close enough.
+                    sum += (*bucket * *count as i64) as f64;
+                    (*bucket as f64, running_count)
+                })
+                .collect();
+            PrometheusValue::Histogram {
+                buckets,
+                sum,
+                count: running_count,
+            }
+        }
+        Cumulative::ExponentialHistogram(histogram) => {
+            let mut running_count = 0;
+            let buckets = histogram
+                .buckets
+                .upper_bound_counts()
+                .into_iter()
+                .map(|(upper_bound, count)| {
+                    running_count += count;
+                    (upper_bound, running_count)
+                })
+                .collect();
+            PrometheusValue::Histogram {
+                buckets,
+                sum: histogram.sum,
+                count: running_count,
+            }
+        }
+        Cumulative::TDigest(digest) => PrometheusValue::Summary {
+            quantiles: if digest.is_empty() {
+                vec![]
+            } else {
+                tdigest_quantiles
+                    .unwrap_or(&[0.5, 0.9, 0.99, 0.999])
+                    .iter()
+                    .map(|quantile| (*quantile, digest.estimate_quantile(*quantile)))
+                    .collect()
+            },
+            sum: digest.sum(),
+            count: digest.count() as u64,
+        },
+    };
+    vec![PrometheusSeries {
+        name: name.to_string(),
+        labels,
+        value,
+    }]
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::{Duration, SystemTime},
+    };
+
+    use http_body_util::{BodyExt, Empty};
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+    use tokio::net::TcpListener;
+
+    use crate::{
+        aggregation::{Aggregation, Histogram, StatisticSet, Sum},
+        pipeline::{AggregatedMetricsMap, AggregationBatcher},
+        types::{Dimension, Name},
+        GaugeFactory,
+    };
+
+    use super::{
+        ExpositionFormat, PrometheusBatcher, PrometheusDownstream, StatisticSetRepresentation,
+    };
+
+    fn window(measurements: Vec<(&'static str, Aggregation)>) -> AggregatedMetricsMap {
+        HashMap::from([(
+            Name::from("api"),
+            HashMap::from([(
+                BTreeMap::from([(Name::from("host"), Dimension::from("a\"b"))]),
+                measurements
+                    .into_iter()
+                    .map(|(name, aggregation)| (Name::from(name), aggregation))
+                    .collect(),
+            )]),
+        )])
+    }
+
+    fn batch(
+        batcher: &mut PrometheusBatcher,
+        measurements: Vec<(&'static str, Aggregation)>,
+    ) -> Vec<super::PrometheusSeries> {
+        batcher.batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(1),
+            &mut window(measurements),
+        )
+    }
+
+    #[test_log::test]
+    fn cumulative_exposition() {
+        let downstream = PrometheusDownstream::default();
+        let mut batcher = PrometheusBatcher::default();
+        let mut histogram = Histogram::default();
+        histogram.accumulate(5);
+        histogram.accumulate(20);
+        for _ in 0..2 {
+            downstream.update(batch(
+                &mut batcher,
+                vec![
+                    ("requests", Aggregation::Sum(Sum { sum: 2 })),
+                    (
+                        "size",
+                        Aggregation::StatisticSet(StatisticSet {
+                            min: 1,
+                            max: 3,
+                            sum: 4,
+                            count: 2,
+                        }),
+                    ),
+                    ("latency", Aggregation::Histogram(histogram.clone())),
+                ],
+            ));
+        }
+
+        assert_eq!(
+            "\
+# TYPE api_latency histogram
+api_latency_bucket{host=\"a\\\"b\",le=\"5\"} 2
+api_latency_bucket{host=\"a\\\"b\",le=\"20\"} 4
+api_latency_bucket{host=\"a\\\"b\",le=\"+Inf\"} 4
+api_latency_sum{host=\"a\\\"b\"} 50
+api_latency_count{host=\"a\\\"b\"} 4
+# TYPE api_requests_total counter
+api_requests_total{host=\"a\\\"b\"} 4
+# TYPE api_size summary
+api_size_sum{host=\"a\\\"b\"} 8
+api_size_count{host=\"a\\\"b\"} 4
+",
+            downstream.render(ExpositionFormat::Prometheus)
+        );
+        let open_metrics = downstream.render(ExpositionFormat::OpenMetrics);
+        assert!(
+            open_metrics.contains("# TYPE api_requests counter\napi_requests_total{"),
+            "{open_metrics}"
+        );
+        assert!(open_metrics.ends_with("# EOF\n"), "{open_metrics}");
+    }
+
+    #[test_log::test]
+    fn statistic_set_gauges() {
+        let downstream = PrometheusDownstream::default();
+        let mut batcher = PrometheusBatcher::default()
+            .with_statistic_set_representation(StatisticSetRepresentation::Gauges);
+        downstream.update(batch(
+            &mut batcher,
+            vec![(
+                "size",
+                Aggregation::StatisticSet(StatisticSet {
+                    min: 1,
+                    max: 3,
+                    sum: 4,
+                    count: 2,
+                }),
+            )],
+        ));
+
+        let rendered = downstream.render(ExpositionFormat::Prometheus);
+        assert!(
+            rendered.contains("# TYPE api_size_max gauge\napi_size_max{host=\"a\\\"b\"} 3\n"),
+            "{rendered}"
+        );
+    }
+
+    #[test_log::test]
+    fn idle_series_expire() {
+        let downstream = PrometheusDownstream::default();
+        let mut batcher = PrometheusBatcher::default()
+            .with_statistic_set_representation(StatisticSetRepresentation::Gauges)
+            .with_idle_series_expiry(Duration::from_secs(60));
+        let start = SystemTime::now();
+        downstream.update(batcher.batch_aggregations(
+            start,
+            Duration::from_secs(1),
+            &mut window(vec![
+                ("requests", Aggregation::Sum(Sum { sum: 2 })),
+                (
+                    "size",
+                    Aggregation::StatisticSet(StatisticSet {
+                        min: 1,
+                        max: 3,
+                        sum: 4,
+                        count: 2,
+                    }),
+                ),
+            ]),
+        ));
+        let mut active = HashMap::from([(
+            Name::from("other"),
+            HashMap::from([(
+                BTreeMap::new(),
+                HashMap::from([(Name::from("requests"), Aggregation::Sum(Sum { sum: 1 }))]),
+            )]),
+        )]);
+        downstream.update(batcher.batch_aggregations(
+            start + Duration::from_secs(30),
+            Duration::from_secs(1),
+            &mut active.clone(),
+        ));
+        let rendered = downstream.render(ExpositionFormat::Prometheus);
+        assert!(rendered.contains("api_requests_total"), "{rendered}");
+        assert!(rendered.contains("api_size_max"), "{rendered}");
+
+        downstream.update(batcher.batch_aggregations(
+            start + Duration::from_secs(61),
+            Duration::from_secs(1),
+            &mut active,
+        ));
+        assert_eq!(
+            "# TYPE other_requests_total counter\nother_requests_total 2\n",
+            downstream.render(ExpositionFormat::Prometheus)
+        );
+        assert_eq!(1, batcher.totals.len());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn scrape_gauges() {
+        let gauge_factory = GaugeFactory::default();
+        let connections =
+            gauge_factory.dimensioned_gauge_sum("service", "connections", Default::default());
+        connections.observe(3);
+        let downstream = PrometheusDownstream::default();
+        let mut gauges = gauge_factory.aggregate_and_reset();
+        downstream.update(PrometheusBatcher::default().batch_aggregations(
+            SystemTime::now(),
+            Duration::from_secs(1),
+            &mut gauges,
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(downstream.serve_forever(listener));
+
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<bytes::Bytes>>();
+        let response = client
+            .request(
+                hyper::Request::get(format!("http://{address}/metrics"))
+                    .header("accept", "application/openmetrics-text; version=1.0.0")
+                    .body(Empty::new())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            response.headers()["content-type"]
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            "# TYPE service_connections counter\nservice_connections_total 3\n# EOF\n",
+            String::from_utf8_lossy(&body)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use prost::Message;
+
+/// How big 1 request to a grpc downstream may be. Bigger batches are split into several
+/// requests, each with the downstream's shared dimensions or Resource.
+///
+/// The default keeps requests a little under the 4 MiB message limit that grpc servers
+/// usually have, with no limit on datapoints.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    max_bytes: usize,
+    max_datapoints: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: (4 << 20) - (64 << 10),
+            max_datapoints: usize::MAX,
+        }
+    }
+}
+
+impl RequestLimits {
+    /// Set the most encoded bytes per request (default 4 MiB - 64 KiB).
+    /// A single metric that is bigger than this still goes, by itself.
+    pub fn max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes
+    }
+
+    /// Set the most datapoints per request (default unlimited)
+    pub fn max_datapoints(&mut self, max_datapoints: usize) {
+        self.max_datapoints = max_datapoints.max(1)
+    }
+
+    /// Cut items into chunks that fit. `overhead` is the encoded size of a request with
+    /// no items in it.
+    pub(crate) fn split<T: Message>(
+        &self,
+        items: Vec<T>,
+        overhead: usize,
+        datapoints: impl Fn(&T) -> usize,
+    ) -> Vec<Vec<T>> {
+        // Each level of nesting around the items gets a longer length prefix as it grows
+        let overhead = overhead + 16;
+        let mut chunks = Vec::new();
+        let mut chunk = Vec::new();
+        let mut chunk_bytes = overhead;
+        let mut chunk_datapoints = 0;
+        for item in items {
+            let item_bytes = item.encoded_len();
+            // A repeated message field is a 1 byte tag, the length and the message
+            let item_bytes = 1 + prost::length_delimiter_len(item_bytes) + item_bytes;
+            let item_datapoints = datapoints(&item);
+            if !chunk.is_empty()
+                && (self.max_bytes < chunk_bytes + item_bytes
+                    || self.max_datapoints < chunk_datapoints + item_datapoints)
+            {
+                chunks.push(std::mem::take(&mut chunk));
+                chunk_bytes = overhead;
+                chunk_datapoints = 0;
+            }
+            if self.max_bytes < overhead + item_bytes {
+                log::warn!("sending a {item_bytes} byte metric that is over the request limit");
+            }
+            chunk.push(item);
+            chunk_bytes += item_bytes;
+            chunk_datapoints += item_datapoints;
+        }
+        if !chunk.is_empty() || chunks.is_empty() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RequestLimits;
+
+    #[test_log::test]
+    fn chunks_fit_the_byte_limit() {
+        let mut limits = RequestLimits::default();
+        limits.max_bytes(64);
+        let items: Vec<String> = (0..100).map(|i| format!("item {i}")).collect();
+        let chunks = limits.split(items.clone(), 10, |_| 1);
+
+        assert!(1 < chunks.len());
+        for chunk in &chunks {
+            let bytes: usize = chunk.iter().map(|item| 2 + item.len()).sum();
+            assert!(10 + bytes <= 64, "{chunk:?}");
+        }
+        assert_eq!(items, chunks.concat());
+    }
+
+    #[test_log::test]
+    fn big_items_go_alone() {
+        let mut limits = RequestLimits::default();
+        limits.max_bytes(32);
+        let chunks = limits.split(vec!["a".repeat(100), "b".to_string()], 0, |_| 1);
+        assert_eq!(vec![vec!["a".repeat(100)], vec!["b".to_string()]], chunks);
+    }
+
+    #[test_log::test]
+    fn empty_batches_are_still_1_request() {
+        let chunks = RequestLimits::default().split(Vec::<String>::new(), 0, |_| 1);
+        assert_eq!(vec![Vec::<String>::new()], chunks);
+    }
+}
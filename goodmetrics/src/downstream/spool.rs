@@ -0,0 +1,385 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+
+/// Segments are rolled at this size, or at an eighth of the spool's size if that is smaller
+const MAX_SEGMENT_BYTES: u64 = 1 << 20;
+
+/// A directory on disk for batches that could not be delivered, so they can be sent later -
+/// even by the next process, after a restart.
+///
+/// Batches are appended to segment files as length-prefixed protobuf records. When the spool
+/// is bigger than its size limit, the oldest segments are deleted to make room. A segment that
+/// was cut short, like by a crash, keeps the records that were written completely.
+///
+/// Give a spool to 1 downstream through its DeliveryConfiguration. Don't share a directory
+/// between downstreams or processes.
+///
+/// An Aggregator can spool windows that don't fit in its channel to a downstream, too. Give it
+/// a spool of its own with `Aggregator::with_spool`, and the same spool to the downstream with
+/// `DeliveryConfiguration::aggregator_spool`.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    files: Arc<Mutex<SpoolFiles>>,
+}
+
+/// Which spooled record is being replayed, so only that one is discarded when it is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SpoolPosition {
+    segment: u64,
+    record: usize,
+}
+
+#[derive(Debug)]
+struct Segment {
+    sequence: u64,
+    bytes: u64,
+}
+
+/// The oldest segment, read into memory for replay
+#[derive(Debug)]
+struct ReplayingSegment {
+    sequence: u64,
+    discarded: usize,
+    records: VecDeque<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct SpoolFiles {
+    directory: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    /// Oldest first
+    segments: VecDeque<Segment>,
+    size_bytes: u64,
+    next_sequence: u64,
+    /// Appends to the newest segment
+    writer: Option<File>,
+    replaying: Option<ReplayingSegment>,
+}
+
+impl Spool {
+    /// Open or create a spool in a directory, holding up to max_bytes.
+    /// Segments left by a previous process are replayed first.
+    pub fn open(directory: impl Into<PathBuf>, max_bytes: u64) -> std::io::Result<Self> {
+        let directory = directory.into();
+        std::fs::create_dir_all(&directory)?;
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(&directory)? {
+            let entry = entry?;
+            let Some(sequence) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".spool"))
+                .and_then(|sequence| sequence.parse().ok())
+            else {
+                continue;
+            };
+            segments.push(Segment {
+                sequence,
+                bytes: entry.metadata()?.len(),
+            });
+        }
+        segments.sort_by_key(|segment| segment.sequence);
+        let files = SpoolFiles {
+            directory,
+            max_bytes,
+            segment_bytes: (max_bytes / 8).clamp(1, MAX_SEGMENT_BYTES),
+            size_bytes: segments.iter().map(|segment| segment.bytes).sum(),
+            // Never append to an old segment: it might end with a partial record
+            next_sequence: segments.last().map_or(0, |segment| segment.sequence + 1),
+            segments: segments.into(),
+            writer: None,
+            replaying: None,
+        };
+        Ok(Self {
+            files: Arc::new(Mutex::new(files)),
+        })
+    }
+
+    /// How many bytes of segments are on disk
+    pub fn size_bytes(&self) -> u64 {
+        self.files().size_bytes
+    }
+
+    /// Whether there is nothing left to replay
+    pub fn is_empty(&self) -> bool {
+        self.files().segments.is_empty()
+    }
+
+    /// Add a record at the end
+    pub(crate) fn push(&self, message: &impl Message) {
+        self.files().push(message.encode_length_delimited_to_vec())
+    }
+
+    /// The oldest record, which stays in the spool until it is discarded
+    pub(crate) fn peek<M: Message + Default>(&self) -> Option<(SpoolPosition, M)> {
+        let mut files = self.files();
+        loop {
+            let (position, record) = files.front()?;
+            match M::decode(record.as_slice()) {
+                Ok(message) => return Some((position, message)),
+                Err(e) => {
+                    log::error!("discarding a spooled record that could not be decoded: {e}");
+                    files.discard(position);
+                }
+            }
+        }
+    }
+
+    /// Forget the oldest record, if it is still the one at position
+    pub(crate) fn discard(&self, position: SpoolPosition) {
+        self.files().discard(position)
+    }
+
+    fn files(&self) -> std::sync::MutexGuard<'_, SpoolFiles> {
+        self.files
+            .lock()
+            .expect("local mutex should not be poisoned")
+    }
+}
+
+impl SpoolFiles {
+    fn path(&self, sequence: u64) -> PathBuf {
+        segment_path(&self.directory, sequence)
+    }
+
+    fn push(&mut self, record: Vec<u8>) {
+        let length = record.len() as u64;
+        if self.max_bytes < length {
+            log::error!("dropping a {length} byte metrics batch that is bigger than the spool");
+            return;
+        }
+        while self.max_bytes < self.size_bytes + length {
+            self.drop_oldest_segment();
+        }
+
+        let writer = match (&mut self.writer, self.segments.back()) {
+            (Some(writer), Some(newest)) if newest.bytes + length <= self.segment_bytes => writer,
+            _ => {
+                let sequence = self.next_sequence;
+                self.next_sequence += 1;
+                match OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(self.path(sequence))
+                {
+                    Ok(file) => {
+                        self.segments.push_back(Segment { sequence, bytes: 0 });
+                        self.writer.insert(file)
+                    }
+                    Err(e) => {
+                        log::error!("failed to create a spool segment - dropping a batch: {e}");
+                        self.writer = None;
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(e) = writer.write_all(&record) {
+            log::error!("failed to write to the spool - dropping a batch: {e}");
+            // Whatever part of it made it to disk is a truncated record. Start fresh.
+            self.writer = None;
+        }
+        if let Some(newest) = self.segments.back_mut() {
+            newest.bytes += length;
+            self.size_bytes += length;
+        }
+    }
+
+    fn drop_oldest_segment(&mut self) {
+        let Some(oldest) = self.segments.pop_front() else {
+            return;
+        };
+        log::error!(
+            "metrics spool is full - dropping the oldest {} bytes of batches",
+            oldest.bytes
+        );
+        self.remove(oldest);
+    }
+
+    fn remove(&mut self, segment: Segment) {
+        if self
+            .replaying
+            .as_ref()
+            .is_some_and(|replaying| replaying.sequence == segment.sequence)
+        {
+            self.replaying = None;
+        }
+        if self.segments.is_empty() {
+            self.writer = None;
+        }
+        self.size_bytes -= segment.bytes;
+        if let Err(e) = std::fs::remove_file(self.path(segment.sequence)) {
+            log::warn!("failed to remove spool segment {}: {e}", segment.sequence);
+        }
+    }
+
+    fn front(&mut self) -> Option<(SpoolPosition, &Vec<u8>)> {
+        while self.replaying.is_none() {
+            let oldest = self.segments.front()?;
+            let sequence = oldest.sequence;
+            if self.segments.len() == 1 {
+                // Don't read a segment that is still being written
+                self.writer = None;
+            }
+            let records = match std::fs::read(self.path(sequence)) {
+                Ok(contents) => records(sequence, &contents),
+                Err(e) => {
+                    log::error!("failed to read spool segment {sequence}: {e}");
+                    VecDeque::new()
+                }
+            };
+            if records.is_empty() {
+                if let Some(oldest) = self.segments.pop_front() {
+                    self.remove(oldest);
+                }
+                continue;
+            }
+            self.replaying = Some(ReplayingSegment {
+                sequence,
+                discarded: 0,
+                records,
+            });
+        }
+        let replaying = self.replaying.as_ref()?;
+        Some((
+            SpoolPosition {
+                segment: replaying.sequence,
+                record: replaying.discarded,
+            },
+            replaying.records.front()?,
+        ))
+    }
+
+    fn discard(&mut self, position: SpoolPosition) {
+        let Some(replaying) = &mut self.replaying else {
+            return;
+        };
+        if replaying.sequence != position.segment || replaying.discarded != position.record {
+            return;
+        }
+        replaying.records.pop_front();
+        replaying.discarded += 1;
+        if replaying.records.is_empty() {
+            if let Some(oldest) = self.segments.pop_front() {
+                self.remove(oldest);
+            }
+        }
+    }
+}
+
+fn segment_path(directory: &Path, sequence: u64) -> PathBuf {
+    directory.join(format!("{sequence:020}.spool"))
+}
+
+/// Split a segment into its records. A record cut short at the end is left off.
+fn records(sequence: u64, mut contents: &[u8]) -> VecDeque<Vec<u8>> {
+    let mut records = VecDeque::new();
+    while !contents.is_empty() {
+        match prost::decode_length_delimiter(&mut contents) {
+            Ok(length) if length <= contents.len() => {
+                let (record, rest) = contents.split_at(length);
+                records.push_back(record.to_vec());
+                contents = rest;
+            }
+            _ => {
+                log::warn!(
+                    "spool segment {sequence} ends with a truncated record - replaying the {} before it",
+                    records.len()
+                );
+                break;
+            }
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::fs::OpenOptions;
+
+    use super::Spool;
+
+    fn drain(spool: &Spool) -> Vec<u32> {
+        let mut replayed = Vec::new();
+        while let Some((position, record)) = spool.peek::<u32>() {
+            assert_eq!(
+                Some((position, record)),
+                spool.peek::<u32>(),
+                "peek does not consume"
+            );
+            spool.discard(position);
+            replayed.push(record);
+        }
+        replayed
+    }
+
+    #[test_log::test]
+    fn replays_in_order_after_a_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = Spool::open(directory.path(), 1024).unwrap();
+        for i in 1..=100 {
+            spool.push(&i);
+        }
+        assert!(
+            1 < std::fs::read_dir(directory.path()).unwrap().count(),
+            "batches should roll over to new segments"
+        );
+        drop(spool);
+
+        let spool = Spool::open(directory.path(), 1024).unwrap();
+        assert!(!spool.is_empty());
+        spool.push(&101);
+        assert_eq!((1..=101).collect::<Vec<_>>(), drain(&spool));
+        assert!(spool.is_empty());
+        assert_eq!(0, spool.size_bytes());
+        assert_eq!(0, std::fs::read_dir(directory.path()).unwrap().count());
+    }
+
+    #[test_log::test]
+    fn truncated_segments_keep_whole_records() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = Spool::open(directory.path(), 1024).unwrap();
+        for i in [300, 301, 302] {
+            spool.push(&i);
+        }
+        drop(spool);
+        let segment = std::fs::read_dir(directory.path())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap()
+            .path();
+        let file = OpenOptions::new().write(true).open(&segment).unwrap();
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+
+        let spool = Spool::open(directory.path(), 1024).unwrap();
+        assert_eq!(vec![300, 301], drain(&spool));
+    }
+
+    #[test_log::test]
+    fn oldest_segments_make_room() {
+        let directory = tempfile::tempdir().unwrap();
+        let spool = Spool::open(directory.path(), 64).unwrap();
+        for i in 1..=100 {
+            spool.push(&i);
+            assert!(spool.size_bytes() <= 64, "{}", spool.size_bytes());
+        }
+
+        let replayed = drain(&spool);
+        assert!(replayed.len() < 100);
+        assert_eq!(Some(&100), replayed.last());
+        assert!(
+            replayed.windows(2).all(|pair| pair[0] + 1 == pair[1]),
+            "the newest batches are kept, in order: {replayed:?}"
+        );
+    }
+}
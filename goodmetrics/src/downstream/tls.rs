@@ -0,0 +1,556 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio_rustls::rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ResolvesClientCert, WebPkiServerVerifier,
+    },
+    crypto::{aws_lc_rs, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    sign::CertifiedKey,
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use super::StdError;
+
+/// How a downstream connection checks its collector's certificate, and how it identifies itself.
+///
+/// Start from the trust you want, then add a client certificate for mutual tls if your
+/// collector asks for one:
+/// ```no_run
+/// # use goodmetrics::downstream::TlsConfiguration;
+/// let mut tls = TlsConfiguration::with_ca_files(["/etc/metrics/ca.pem"]);
+/// tls.client_certificate_files("/etc/metrics/client.pem", "/etc/metrics/client.key");
+/// tls.server_name("collector.internal");
+/// ```
+/// Certificates and keys from files are re-read when the files change, so rotated
+/// certificates are picked up by new connections without a restart.
+#[derive(Debug, Clone)]
+pub struct TlsConfiguration {
+    trust: Trust,
+    client_identity: Option<ClientIdentity>,
+    server_name: Option<String>,
+    reload_interval: Duration,
+}
+
+#[derive(Debug, Clone)]
+enum Trust {
+    Roots(RootCertStore),
+    CaFiles(Vec<PathBuf>),
+    DangerouslyAcceptAnyCertificate,
+}
+
+#[derive(Debug, Clone)]
+enum ClientIdentity {
+    Files { certificate: PathBuf, key: PathBuf },
+    Pem { certificate: Vec<u8>, key: Vec<u8> },
+}
+
+impl TlsConfiguration {
+    fn new(trust: Trust) -> Self {
+        Self {
+            trust,
+            client_identity: None,
+            server_name: None,
+            reload_interval: Duration::from_secs(60),
+        }
+    }
+
+    /// Trust these roots, like `webpki_roots::TLS_SERVER_ROOTS`
+    pub fn with_roots(roots: RootCertStore) -> Self {
+        Self::new(Trust::Roots(roots))
+    }
+
+    /// Trust the certificates in these PEM files, like your private CA bundle
+    pub fn with_ca_files(ca_files: impl IntoIterator<Item = impl Into<PathBuf>>) -> Self {
+        Self::new(Trust::CaFiles(
+            ca_files.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Accept whatever certificate the collector offers, valid or not.
+    ///
+    /// Anyone between you and your collector can read and change your metrics. This is for
+    /// local testing, and it logs a warning every time a client is made with it.
+    pub fn dangerously_accept_any_certificate() -> Self {
+        Self::new(Trust::DangerouslyAcceptAnyCertificate)
+    }
+
+    /// Identify yourself to the collector with a PEM certificate chain and private key from files
+    pub fn client_certificate_files(
+        &mut self,
+        certificate: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) {
+        self.client_identity = Some(ClientIdentity::Files {
+            certificate: certificate.into(),
+            key: key.into(),
+        })
+    }
+
+    /// Identify yourself to the collector with an in-memory PEM certificate chain and private key
+    pub fn client_certificate_pem(
+        &mut self,
+        certificate: impl Into<Vec<u8>>,
+        key: impl Into<Vec<u8>>,
+    ) {
+        self.client_identity = Some(ClientIdentity::Pem {
+            certificate: certificate.into(),
+            key: key.into(),
+        })
+    }
+
+    /// Check the collector's certificate for this name rather than the endpoint's host.
+    /// This is also the SNI name sent to the collector.
+    pub fn server_name(&mut self, server_name: impl Into<String>) {
+        self.server_name = Some(server_name.into())
+    }
+
+    /// Set how often files are checked for changes (default 60s). Checks happen as
+    /// connections are made, so an idle client does not touch the disk.
+    pub fn reload_interval(&mut self, reload_interval: Duration) {
+        self.reload_interval = reload_interval
+    }
+
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, StdError> {
+        let provider = Arc::new(aws_lc_rs::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.trust {
+            Trust::Roots(roots) => builder.with_root_certificates(roots.clone()),
+            Trust::CaFiles(ca_files) => {
+                let loader_provider = provider.clone();
+                let verifier =
+                    Watched::new(ca_files.clone(), self.reload_interval, move |paths| {
+                        let mut roots = RootCertStore::empty();
+                        for path in paths {
+                            for certificate in CertificateDer::pem_slice_iter(&std::fs::read(path)?)
+                            {
+                                roots.add(certificate?)?;
+                            }
+                        }
+                        Ok(WebPkiServerVerifier::builder_with_provider(
+                            Arc::new(roots),
+                            loader_provider.clone(),
+                        )
+                        .build()?)
+                    })?;
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(ReloadingVerifier { verifier }))
+            }
+            Trust::DangerouslyAcceptAnyCertificate => {
+                log::warn!("tls certificate verification is off - anyone in the middle can read and change your metrics");
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(StupidVerifier { provider }))
+            }
+        };
+        Ok(match &self.client_identity {
+            None => builder.with_no_client_auth(),
+            Some(ClientIdentity::Pem { certificate, key }) => builder.with_client_auth_cert(
+                CertificateDer::pem_slice_iter(certificate).collect::<Result<_, _>>()?,
+                PrivateKeyDer::from_pem_slice(key)?,
+            )?,
+            Some(ClientIdentity::Files { certificate, key }) => {
+                let key_provider = aws_lc_rs::default_provider();
+                let certified_key = Watched::new(
+                    vec![certificate.clone(), key.clone()],
+                    self.reload_interval,
+                    move |paths| {
+                        let certificates =
+                            CertificateDer::pem_slice_iter(&std::fs::read(&paths[0])?)
+                                .collect::<Result<_, _>>()?;
+                        let key = PrivateKeyDer::from_pem_slice(&std::fs::read(&paths[1])?)?;
+                        Ok(Arc::new(CertifiedKey::new(
+                            certificates,
+                            key_provider.key_provider.load_private_key(key)?,
+                        )))
+                    },
+                )?;
+                builder.with_client_cert_resolver(Arc::new(ReloadingClientCertificate {
+                    certified_key,
+                }))
+            }
+        })
+    }
+
+    pub(crate) fn server_name_override(&self) -> Result<Option<ServerName<'static>>, StdError> {
+        Ok(match &self.server_name {
+            Some(server_name) => Some(ServerName::try_from(server_name.clone())?),
+            None => None,
+        })
+    }
+}
+
+type Loader<T> = Box<dyn Fn(&[PathBuf]) -> Result<T, StdError> + Send + Sync>;
+
+/// Something loaded from files, and loaded again when they change
+struct Watched<T> {
+    paths: Vec<PathBuf>,
+    reload_interval: Duration,
+    load: Loader<T>,
+    state: Mutex<WatchedState<T>>,
+}
+
+struct WatchedState<T> {
+    checked: Instant,
+    modified: Vec<Option<SystemTime>>,
+    value: T,
+}
+
+impl<T: Clone> Watched<T> {
+    /// The first load has to work
+    fn new(
+        paths: Vec<PathBuf>,
+        reload_interval: Duration,
+        load: impl Fn(&[PathBuf]) -> Result<T, StdError> + Send + Sync + 'static,
+    ) -> Result<Self, StdError> {
+        let modified = modified_times(&paths);
+        let value = load(&paths).map_err(|e| format!("could not load {paths:?}: {e}"))?;
+        Ok(Self {
+            state: Mutex::new(WatchedState {
+                checked: Instant::now(),
+                modified,
+                value,
+            }),
+            paths,
+            reload_interval,
+            load: Box::new(load),
+        })
+    }
+
+    fn get(&self) -> T {
+        let mut state = self
+            .state
+            .lock()
+            .expect("local mutex should not be poisoned");
+        if self.reload_interval <= state.checked.elapsed() {
+            state.checked = Instant::now();
+            let modified = modified_times(&self.paths);
+            if modified != state.modified {
+                match (self.load)(&self.paths) {
+                    Ok(value) => {
+                        log::info!("reloaded {:?}", self.paths);
+                        state.value = value;
+                        state.modified = modified;
+                    }
+                    // Files are often rotated 1 at a time. Keep what worked and try again next time.
+                    Err(e) => log::error!("could not reload {:?}: {e}", self.paths),
+                }
+            }
+        }
+        state.value.clone()
+    }
+}
+
+impl<T> std::fmt::Debug for Watched<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watched")
+            .field("paths", &self.paths)
+            .field("reload_interval", &self.reload_interval)
+            .finish()
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+#[derive(Debug)]
+struct ReloadingClientCertificate {
+    certified_key: Watched<Arc<CertifiedKey>>,
+}
+
+impl ResolvesClientCert for ReloadingClientCertificate {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key.get())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug)]
+struct ReloadingVerifier {
+    verifier: Watched<Arc<WebPkiServerVerifier>>,
+}
+
+impl ServerCertVerifier for ReloadingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        self.verifier.get().verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.verifier
+            .get()
+            .verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.verifier
+            .get()
+            .verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.get().supported_verify_schemes()
+    }
+}
+
+#[derive(Debug)]
+struct StupidVerifier {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for StupidVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        // roflmao
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        // roflmao
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        // roflmao
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::Path, sync::Arc, time::Duration};
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::{
+        rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            server::WebPkiClientVerifier,
+            RootCertStore, ServerConfig,
+        },
+        TlsAcceptor, TlsConnector,
+    };
+
+    use super::{TlsConfiguration, Watched};
+
+    struct Pki {
+        ca: String,
+        issuer: Issuer<'static, KeyPair>,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let ca = params.self_signed(&key).unwrap().pem();
+            Self {
+                ca,
+                issuer: Issuer::new(params, key),
+            }
+        }
+
+        /// (certificate pem, key pem)
+        fn issue(&self, name: &str) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(vec![name.to_string()])
+                .unwrap()
+                .signed_by(&key, &self.issuer)
+                .unwrap();
+            (certificate.pem(), key.serialize_pem())
+        }
+    }
+
+    /// Accepts 1 connection, requiring a client certificate from the pki. Returns whether the handshake worked.
+    async fn serve_once(pki: &Pki) -> (u16, tokio::task::JoinHandle<bool>) {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(pki.ca.as_bytes()).unwrap())
+            .unwrap();
+        let (certificate, key) = pki.issue("metrics.test");
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(
+                WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .unwrap(),
+            )
+            .with_single_cert(
+                vec![CertificateDer::from_pem_slice(certificate.as_bytes()).unwrap()],
+                PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+            )
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            acceptor.accept(stream).await.is_ok()
+        });
+        (port, server)
+    }
+
+    async fn connect(tls: &TlsConfiguration, port: u16) {
+        let connector = TlsConnector::from(Arc::new(tls.client_config().unwrap()));
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let server_name = tls
+            .server_name_override()
+            .unwrap()
+            .expect("tests set a server name");
+        // With tls 1.3 the client is done before the server checks its certificate, so the
+        // server says whether it worked.
+        let _ = connector.connect(server_name, stream).await;
+    }
+
+    fn write(directory: &Path, name: &str, contents: &str) -> std::path::PathBuf {
+        let path = directory.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn mutual_tls_with_files() {
+        let pki = Pki::new();
+        let directory = tempfile::tempdir().unwrap();
+        let (certificate, key) = pki.issue("client.test");
+
+        let mut tls = TlsConfiguration::with_ca_files([write(directory.path(), "ca.pem", &pki.ca)]);
+        tls.client_certificate_files(
+            write(directory.path(), "client.pem", &certificate),
+            write(directory.path(), "client.key", &key),
+        );
+        tls.server_name("metrics.test");
+
+        let (port, server) = serve_once(&pki).await;
+        connect(&tls, port).await;
+        assert!(server.await.unwrap(), "server should accept the client");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn client_certificate_is_required() {
+        let pki = Pki::new();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(pki.ca.as_bytes()).unwrap())
+            .unwrap();
+        let mut tls = TlsConfiguration::with_roots(roots);
+        tls.server_name("metrics.test");
+
+        let (port, server) = serve_once(&pki).await;
+        connect(&tls, port).await;
+        assert!(!server.await.unwrap(), "server should reject the client");
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn client_certificate_from_another_ca_is_rejected() {
+        let pki = Pki::new();
+        let (certificate, key) = Pki::new().issue("client.test");
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_slice(pki.ca.as_bytes()).unwrap())
+            .unwrap();
+        let mut tls = TlsConfiguration::with_roots(roots);
+        tls.client_certificate_pem(certificate, key);
+        tls.server_name("metrics.test");
+
+        let (port, server) = serve_once(&pki).await;
+        connect(&tls, port).await;
+        assert!(!server.await.unwrap(), "server should reject the client");
+    }
+
+    #[test_log::test]
+    fn missing_files_are_an_error() {
+        let tls = TlsConfiguration::with_ca_files(["/does/not/exist.pem"]);
+        assert!(tls.client_config().is_err());
+    }
+
+    #[test_log::test]
+    fn watched_files_reload() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = write(directory.path(), "value", "1");
+        let watched = Watched::new(vec![path.clone()], Duration::ZERO, |paths| {
+            Ok(std::fs::read_to_string(&paths[0])?.parse::<u32>()?)
+        })
+        .unwrap();
+        assert_eq!(1, watched.get());
+
+        std::fs::write(&path, "not a number").unwrap();
+        set_modified(&path, 1);
+        assert_eq!(1, watched.get(), "a bad reload keeps the last good value");
+
+        std::fs::write(&path, "2").unwrap();
+        set_modified(&path, 2);
+        assert_eq!(2, watched.get());
+    }
+
+    /// Filesystem timestamps can be coarse. Make sure each write looks like a change.
+    fn set_modified(path: &Path, seconds: u64) {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+    }
+}
@@ -1,15 +1,18 @@
 use std::{
     collections::HashMap,
+    future::Future,
+    pin::pin,
     sync::{Arc, LazyLock, Mutex},
-    time::{Duration, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
+use futures::future::{select, Either};
 use tokio::{sync::mpsc, time::MissedTickBehavior};
 
 use crate::{
     gauge::{Gauge, HistogramHandle, StatisticSetHandle, SumHandle},
     pipeline::{AggregatedMetricsMap, AggregationBatcher},
-    GaugeDimensions, GaugeGroup, Name,
+    GaugeDimensions, GaugeGroup, MetadataRegistry, Name,
 };
 
 /// The default gauge factory. You should use this unless you have some fancy multi-factory setup.
@@ -19,7 +22,7 @@ use crate::{
 /// Configuration:
 /// ```rust
 /// # use goodmetrics::{GaugeFactory, default_gauge_factory};
-/// # use goodmetrics::downstream::{get_client, OpenTelemetryDownstream, OpentelemetryBatcher};
+/// # use goodmetrics::downstream::{get_client, OpenTelemetryDownstream, OpentelemetryBatcher, TlsConfiguration};
 /// # use goodmetrics::proto::opentelemetry::collector::metrics::v1::metrics_service_client::MetricsServiceClient;
 /// # use tokio_rustls::rustls::RootCertStore;
 /// # use std::time::Duration;
@@ -29,7 +32,7 @@ use crate::{
 ///     let downstream = OpenTelemetryDownstream::new_with_dimensions(
 ///         get_client(
 ///             "https://ingest.example.com",
-///             || Some(RootCertStore {
+///             &TlsConfiguration::with_roots(RootCertStore {
 ///                 roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
 ///             }),
 ///             MetricsServiceClient::with_origin,
@@ -64,9 +67,16 @@ pub fn default_gauge_factory() -> &'static GaugeFactory {
 #[derive(Clone, Debug, Default)]
 pub struct GaugeFactory {
     gauge_groups: Arc<Mutex<HashMap<Name, GaugeGroup>>>,
+    metadata: MetadataRegistry,
 }
 
 impl GaugeFactory {
+    /// Units and descriptions for your gauges, by gauge group and gauge name.
+    /// Pass a clone to your batcher, like `OpentelemetryBatcher::with_metadata`, to send them along.
+    pub fn metadata(&self) -> &MetadataRegistry {
+        &self.metadata
+    }
+
     /// Get a gauge within a group, of a particular name.
     ///
     /// Gauges are aggregated as StatisticSet and passed to your downstream collector.
@@ -198,18 +208,51 @@ impl GaugeFactory {
     ///
     /// You can use a clone of self for this function.
     pub async fn report_gauges_forever<TAggregationBatcher>(
+        self,
+        period: Duration,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+        batcher: TAggregationBatcher,
+    ) where
+        TAggregationBatcher: AggregationBatcher,
+        TAggregationBatcher::TBatch: Send,
+    {
+        self.report_gauges_until(period, sender, batcher, std::future::pending())
+            .await
+    }
+
+    /// Like report_gauges_forever, but it returns after `shutdown` completes.
+    /// It reports 1 last time on the way out, covering the time since the last report.
+    pub async fn report_gauges_until<TAggregationBatcher>(
         self,
         period: Duration,
         sender: mpsc::Sender<TAggregationBatcher::TBatch>,
         mut batcher: TAggregationBatcher,
+        shutdown: impl Future<Output = ()>,
     ) where
         TAggregationBatcher: AggregationBatcher,
         TAggregationBatcher::TBatch: Send,
     {
+        let mut shutdown = pin!(shutdown);
         let mut interval = tokio::time::interval(period);
         interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut last_report = Instant::now();
         loop {
-            interval.tick().await;
+            if let Either::Right(_) = select(pin!(interval.tick()), shutdown.as_mut()).await {
+                let mut gauges: AggregatedMetricsMap = self.aggregate_and_reset();
+                if !gauges.is_empty() {
+                    let batch = batcher.batch_aggregations(
+                        SystemTime::now(),
+                        last_report.elapsed(),
+                        &mut gauges,
+                    );
+                    // This is the last one, so it is worth waiting for room in the channel
+                    if let Err(e) = sender.send(batch).await {
+                        log::error!("could not report final gauges: {e:?}")
+                    }
+                }
+                return;
+            }
+            last_report = Instant::now();
             let now = SystemTime::now();
             let mut gauges: AggregatedMetricsMap = self.aggregate_and_reset();
             match sender.try_send(batcher.batch_aggregations(now, period, &mut gauges)) {
@@ -251,6 +294,69 @@ mod test {
         Dimension, GaugeDimensions, GaugeFactory, Name,
     };
 
+    #[test_log::test(tokio::test)]
+    async fn shutdown_reports_gauges_once_more() {
+        struct CoveredTimeBatcher;
+        impl AggregationBatcher for CoveredTimeBatcher {
+            type TBatch = (Duration, AggregatedMetricsMap);
+
+            fn batch_aggregations(
+                &mut self,
+                _now: SystemTime,
+                covered_time: Duration,
+                aggregations: &mut AggregatedMetricsMap,
+            ) -> Self::TBatch {
+                (covered_time, std::mem::take(aggregations))
+            }
+        }
+
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(128);
+        let gauge_factory = GaugeFactory::default();
+        let gauge = gauge_factory.gauge_statistic_set("test_gauges", "gauge");
+        let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let period = Duration::from_secs(3600);
+        let reporting = tokio::task::spawn(gauge_factory.clone().report_gauges_until(
+            period,
+            sender,
+            CoveredTimeBatcher,
+            async move {
+                let _ = shutdown_receiver.await;
+            },
+        ));
+
+        // The first tick is immediate, and there is nothing to report yet
+        let (_, first) = receiver.recv().await.expect("should have reported");
+        assert!(first.is_empty());
+
+        gauge.observe(20);
+        shutdown.send(()).expect("reporter is alive");
+        tokio::time::timeout(Duration::from_secs(5), reporting)
+            .await
+            .expect("the reporter should stop promptly")
+            .expect("the reporter should not panic");
+
+        let (covered_time, last) = receiver
+            .recv()
+            .await
+            .expect("should have reported on shutdown");
+        assert!(covered_time < period, "{covered_time:?}");
+        assert_eq!(
+            Some(&Aggregation::StatisticSet(StatisticSet {
+                min: 20,
+                max: 20,
+                sum: 20,
+                count: 1,
+            })),
+            last.get(&Name::from("test_gauges"))
+                .and_then(|group| group.get(&BTreeMap::new()))
+                .and_then(|measurements| measurements.get(&Name::from("gauge")))
+        );
+        assert!(
+            receiver.recv().await.is_none(),
+            "the sender is dropped on shutdown"
+        );
+    }
+
     #[test_log::test(tokio::test)]
     async fn gauges() {
         struct BatchTaker;
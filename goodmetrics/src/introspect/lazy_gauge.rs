@@ -1,37 +1,116 @@
-use crate::{gauge::SumHandle, Name};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{
+    gauge::{HistogramHandle, StatisticSetHandle, SumHandle},
+    GaugeDimensions, GaugeFactory, Name,
+};
 
 use super::introspection_factory;
 
-pub(crate) struct LazySumGauge {
+/// A kind of gauge handle that introspection can vend
+pub(crate) trait IntrospectionHandle: Clone {
+    fn make(factory: &GaugeFactory, name: Name, dimensions: GaugeDimensions) -> Self;
+}
+
+impl IntrospectionHandle for SumHandle {
+    fn make(factory: &GaugeFactory, name: Name, dimensions: GaugeDimensions) -> Self {
+        factory.dimensioned_gauge_sum("gm_introspect", name, dimensions)
+    }
+}
+
+impl IntrospectionHandle for HistogramHandle {
+    fn make(factory: &GaugeFactory, name: Name, dimensions: GaugeDimensions) -> Self {
+        factory.dimensioned_gauge_histogram("gm_introspect", name, dimensions)
+    }
+}
+
+impl IntrospectionHandle for StatisticSetHandle {
+    fn make(factory: &GaugeFactory, name: Name, dimensions: GaugeDimensions) -> Self {
+        factory.dimensioned_gauge_statistic_set("gm_introspect", name, dimensions)
+    }
+}
+
+pub(crate) struct LazyGauge<THandle> {
     name: Name,
-    gauge: std::sync::OnceLock<Option<SumHandle>>,
+    dimensions: &'static [(&'static str, &'static str)],
+    gauge: OnceLock<Option<THandle>>,
 }
 
-impl LazySumGauge {
-    pub(crate) const fn new(name: Name) -> LazySumGauge {
+pub(crate) type LazySumGauge = LazyGauge<SumHandle>;
+pub(crate) type LazyHistogramGauge = LazyGauge<HistogramHandle>;
+pub(crate) type LazyStatisticSetGauge = LazyGauge<StatisticSetHandle>;
+
+impl<THandle: IntrospectionHandle> LazyGauge<THandle> {
+    pub(crate) const fn new(name: Name) -> Self {
+        Self::new_with_dimensions(name, &[])
+    }
+
+    pub(crate) const fn new_with_dimensions(
+        name: Name,
+        dimensions: &'static [(&'static str, &'static str)],
+    ) -> Self {
         Self {
             name,
-            gauge: std::sync::OnceLock::new(),
+            dimensions,
+            gauge: OnceLock::new(),
         }
     }
-}
 
-impl LazySumGauge {
-    pub(crate) fn gauge(&self) -> &Option<SumHandle> {
+    pub(crate) fn gauge(&self) -> &Option<THandle> {
         self.gauge.get_or_init(|| {
             let factory_guard = introspection_factory();
-            match factory_guard.as_ref() {
-                Some(factory) => {
-                    // Though this store races, the factory vends the same gauge via a mutex.
-                    let gauge = factory.dimensioned_gauge_sum(
-                        "gm_introspect",
-                        self.name.clone(),
-                        Default::default(),
-                    );
-                    Some(gauge)
-                }
-                None => None,
-            }
+            // Though this store races, the factory vends the same gauge via a mutex.
+            factory_guard.as_ref().map(|factory| {
+                THandle::make(
+                    factory,
+                    self.name.clone(),
+                    GaugeDimensions::new(self.dimensions.iter().copied()),
+                )
+            })
         })
     }
 }
+
+/// Gauges that differ by the value of 1 dimension, like a status code.
+/// Each is made the first time its value is used.
+pub(crate) struct LazyGaugeFamily<THandle> {
+    name: Name,
+    dimensions: &'static [(&'static str, &'static str)],
+    dimension: &'static str,
+    gauges: Mutex<BTreeMap<String, THandle>>,
+}
+
+impl<THandle: IntrospectionHandle> LazyGaugeFamily<THandle> {
+    pub(crate) const fn new_with_dimensions(
+        name: Name,
+        dimensions: &'static [(&'static str, &'static str)],
+        dimension: &'static str,
+    ) -> Self {
+        Self {
+            name,
+            dimensions,
+            dimension,
+            gauges: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn gauge(&self, value: &str) -> Option<THandle> {
+        let mut gauges = self
+            .gauges
+            .lock()
+            .expect("local mutex should not be poisoned");
+        if let Some(gauge) = gauges.get(value) {
+            return Some(gauge.clone());
+        }
+        let factory_guard = introspection_factory();
+        let factory = factory_guard.as_ref()?;
+        let mut dimensions = GaugeDimensions::new(self.dimensions.iter().copied());
+        dimensions.insert(self.dimension, value.to_string());
+        let gauge = THandle::make(factory, self.name.clone(), dimensions);
+        gauges.insert(value.to_string(), gauge.clone());
+        Some(gauge)
+    }
+}
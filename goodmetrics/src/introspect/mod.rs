@@ -1,5 +1,9 @@
 //! The `introspect` feature.
 //! This records and reports metrics about your metrics pipeline.
+//!
+//! The grpc downstreams report on themselves in the `gm_introspect` group, with a `downstream`
+//! dimension: `downstream_requests` by `status` code, `downstream_latency` in nanoseconds,
+//! `downstream_request_bytes`, `downstream_request_datapoints` and `downstream_queue_depth`.
 
 mod introspection_factory;
 mod lazy_gauge;
@@ -8,4 +12,6 @@ pub use introspection_factory::run_introspection_metrics;
 pub use introspection_factory::IntrospectionConfiguration;
 
 pub(crate) use introspection_factory::introspection_factory;
-pub(crate) use lazy_gauge::LazySumGauge;
+pub(crate) use lazy_gauge::{
+    LazyGaugeFamily, LazyHistogramGauge, LazyStatisticSetGauge, LazySumGauge,
+};
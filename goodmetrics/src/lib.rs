@@ -34,17 +34,23 @@ mod gauge_group;
 #[deny(missing_docs)]
 pub mod introspect;
 #[deny(missing_docs)]
+mod metadata;
+#[deny(missing_docs)]
 mod metrics;
 #[deny(missing_docs)]
 mod metrics_factory;
 #[deny(missing_docs)]
 pub mod pipeline;
+#[cfg(feature = "testing")]
+#[deny(missing_docs)]
+pub mod testing;
 #[deny(missing_docs)]
 mod types;
 
 pub use gauge::{GaugeDimensions, HistogramHandle, StatisticSetHandle, SumHandle, TimeGuard};
 pub use gauge_factory::{default_gauge_factory, GaugeFactory};
 pub use gauge_group::GaugeGroup;
+pub use metadata::{MetadataRegistry, MetricMetadata, ScopeMetadata};
 pub use metrics::{DimensionGuard, Metrics, MetricsBehavior, Timer};
 pub use metrics_factory::MetricsFactory;
 pub use types::{Dimension, Distribution, Measurement, Name, Observation};
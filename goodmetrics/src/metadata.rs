@@ -0,0 +1,167 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::Name;
+
+/// The unit and description of a measurement
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricMetadata {
+    /// A ucum unit, like "ns", "By" or "1"
+    pub unit: String,
+    /// What the measurement means, for the humans reading your dashboards
+    pub description: String,
+}
+
+/// The library or application that records a set of metrics, like an otlp InstrumentationScope
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeMetadata {
+    /// Name of the library or application, like "my_http_client"
+    pub name: String,
+    /// Its version, like "1.2.3"
+    pub version: String,
+}
+
+/// Units and descriptions for your measurements, for downstreams that can carry them.
+///
+/// This is a cheap handle to shared state. Get it from your MetricsFactory or GaugeFactory,
+/// give a clone to your batcher, and describe measurements whenever you like:
+/// ```
+/// # use goodmetrics::{GaugeFactory, downstream::OpentelemetryBatcher};
+/// let gauge_factory = GaugeFactory::default();
+/// gauge_factory
+///     .metadata()
+///     .describe("connections", "open", "1", "connections open right now");
+/// let batcher = OpentelemetryBatcher.with_metadata(gauge_factory.metadata().clone());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MetadataRegistry {
+    descriptions: Arc<RwLock<Descriptions>>,
+}
+
+#[derive(Debug, Default)]
+struct Descriptions {
+    /// metric name -> measurement name -> metadata. Keyed by string, because Name::Str("a")
+    /// and Name::String("a") hash differently.
+    measurements: HashMap<String, HashMap<String, MetricMetadata>>,
+    /// measurement name -> metadata, for any metric
+    any_metric: HashMap<String, MetricMetadata>,
+    scope: Option<ScopeMetadata>,
+}
+
+impl MetadataRegistry {
+    /// Describe 1 measurement of 1 metric. For gauges, the metric is the gauge group
+    /// and the measurement is the gauge name.
+    pub fn describe(
+        &self,
+        metric: impl Into<Name>,
+        measurement: impl Into<Name>,
+        unit: impl Into<String>,
+        description: impl Into<String>,
+    ) {
+        self.descriptions
+            .write()
+            .expect("local rwlock should not be poisoned")
+            .measurements
+            .entry(String::from(metric.into()))
+            .or_default()
+            .insert(
+                String::from(measurement.into()),
+                MetricMetadata {
+                    unit: unit.into(),
+                    description: description.into(),
+                },
+            );
+    }
+
+    /// Describe a measurement name wherever it shows up, like a timer you record in
+    /// every scope. A description for a specific metric wins over this.
+    pub fn describe_measurement(
+        &self,
+        measurement: impl Into<Name>,
+        unit: impl Into<String>,
+        description: impl Into<String>,
+    ) {
+        self.descriptions
+            .write()
+            .expect("local rwlock should not be poisoned")
+            .any_metric
+            .insert(
+                String::from(measurement.into()),
+                MetricMetadata {
+                    unit: unit.into(),
+                    description: description.into(),
+                },
+            );
+    }
+
+    /// Say which library or application these metrics come from. Give each MetricsFactory
+    /// its own scope to keep, say, a library's metrics apart from yours in otlp.
+    /// It is sent by OTLP downstreams, for batches from a ScopedOpentelemetryBatcher.
+    pub fn set_instrumentation_scope(&self, name: impl Into<String>, version: impl Into<String>) {
+        self.descriptions
+            .write()
+            .expect("local rwlock should not be poisoned")
+            .scope = Some(ScopeMetadata {
+            name: name.into(),
+            version: version.into(),
+        });
+    }
+
+    /// The instrumentation scope, if it was set
+    pub fn instrumentation_scope(&self) -> Option<ScopeMetadata> {
+        self.descriptions
+            .read()
+            .expect("local rwlock should not be poisoned")
+            .scope
+            .clone()
+    }
+
+    /// Look up the metadata for a measurement of a metric, if it was described
+    pub fn get(&self, metric: &str, measurement: &str) -> Option<MetricMetadata> {
+        let descriptions = self
+            .descriptions
+            .read()
+            .expect("local rwlock should not be poisoned");
+        descriptions
+            .measurements
+            .get(metric)
+            .and_then(|measurements| measurements.get(measurement))
+            .or_else(|| descriptions.any_metric.get(measurement))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MetadataRegistry, MetricMetadata};
+
+    #[test_log::test]
+    fn specific_descriptions_win() {
+        let registry = MetadataRegistry::default();
+        registry.describe_measurement("totaltime", "ns", "how long it took");
+        registry.describe(
+            "slow_thing",
+            "totaltime",
+            "ms",
+            "how long the slow thing took",
+        );
+
+        let shared = registry.clone();
+        assert_eq!(
+            Some(MetricMetadata {
+                unit: "ms".into(),
+                description: "how long the slow thing took".into()
+            }),
+            shared.get("slow_thing", "totaltime")
+        );
+        assert_eq!(
+            Some("ns".to_string()),
+            shared
+                .get("fast_thing", "totaltime")
+                .map(|metadata| metadata.unit)
+        );
+        assert_eq!(None, shared.get("slow_thing", "bytes"));
+    }
+}
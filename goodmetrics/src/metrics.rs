@@ -50,9 +50,18 @@ pub struct Metrics<TBuildHasher = Hasher> {
     dimensions: HashMap<Name, Dimension, TBuildHasher>,
     measurements: HashMap<Name, Measurement, TBuildHasher>,
     dimension_guards: Vec<OverrideDimension>,
+    exemplars: Vec<ExemplarTrace>,
     pub(crate) behaviors: u32,
 }
 
+/// The trace a distribution measurement was recorded in
+#[derive(Debug)]
+pub(crate) struct ExemplarTrace {
+    pub(crate) measurement: Name,
+    pub(crate) trace_id: [u8; 16],
+    pub(crate) span_id: [u8; 8],
+}
+
 /// A drop guard for a dimension, so you can know what happened, e.g., in an async
 /// workflow that timed out & dropped.
 #[derive(Debug)]
@@ -159,6 +168,25 @@ where
             .insert(name.into(), Measurement::Distribution(value.into()));
     }
 
+    /// Like distribution, but also offer the value as an exemplar for its trace.
+    /// Downstreams that support exemplars can link from the distribution to the trace.
+    /// For a Collection, the largest value is the exemplar.
+    #[inline]
+    pub fn distribution_with_exemplar(
+        &mut self,
+        name: impl Into<Name>,
+        value: impl Into<Distribution>,
+        trace_id: [u8; 16],
+        span_id: [u8; 8],
+    ) {
+        if self.has_behavior(MetricsBehavior::Suppress) {
+            return;
+        }
+        let name = name.into();
+        self.set_exemplar(name.clone(), trace_id, span_id);
+        self.distribution(name, value);
+    }
+
     /// Record a sum. Repeated reports add together in this object.
     ///
     /// Aggregation: Locally summed per report period.
@@ -198,6 +226,32 @@ where
         Timer::new(timer)
     }
 
+    /// Like time, but also offer the time as an exemplar for its trace.
+    #[inline]
+    pub fn time_with_exemplar(
+        &mut self,
+        timer_name: impl Into<Name>,
+        trace_id: [u8; 16],
+        span_id: [u8; 8],
+    ) -> Timer {
+        let timer_name = timer_name.into();
+        if !self.has_behavior(MetricsBehavior::Suppress) {
+            self.set_exemplar(timer_name.clone(), trace_id, span_id);
+        }
+        self.time(timer_name)
+    }
+
+    /// Last write wins, like the measurement it goes with
+    fn set_exemplar(&mut self, measurement: Name, trace_id: [u8; 16], span_id: [u8; 8]) {
+        self.exemplars
+            .retain(|exemplar| exemplar.measurement != measurement);
+        self.exemplars.push(ExemplarTrace {
+            measurement,
+            trace_id,
+            span_id,
+        });
+    }
+
     /// A dimension that you set a default for in case you drop early or something.
     pub fn guarded_dimension(
         &mut self,
@@ -224,6 +278,7 @@ where
         self.dimensions.clear();
         self.measurements.clear();
         self.dimension_guards.clear();
+        self.exemplars.clear();
     }
 
     /// Do not report this metrics instance.
@@ -281,6 +336,7 @@ where
             measurements,
             behaviors,
             dimension_guards,
+            exemplars: Vec::new(),
         }
     }
 
@@ -298,6 +354,11 @@ where
         }
         (&mut self.dimensions, &mut self.measurements)
     }
+
+    /// Move the exemplar traces out, for the measurements that have them
+    pub(crate) fn take_exemplars(&mut self) -> Vec<ExemplarTrace> {
+        std::mem::take(&mut self.exemplars)
+    }
 }
 
 /// Scope guard for recording nanoseconds into a Metrics.
@@ -5,6 +5,7 @@ use crate::{
     metrics::MetricsBehavior,
     pipeline::Sink,
     types::Name,
+    MetadataRegistry,
 };
 
 /// Example complete preaggregated metrics pipeline setup, with gauge support:
@@ -16,6 +17,7 @@ use crate::{
 /// use goodmetrics::downstream::get_client;
 /// use goodmetrics::downstream::GoodmetricsBatcher;
 /// use goodmetrics::downstream::GoodmetricsDownstream;
+/// use goodmetrics::downstream::TlsConfiguration;
 /// use goodmetrics::GaugeFactory;
 /// use goodmetrics::Metrics;
 /// use goodmetrics::MetricsFactory;
@@ -33,7 +35,9 @@ use crate::{
 /// let downstream = GoodmetricsDownstream::new(
 ///     get_client(
 ///         "https://ingest.example.com",
-///         || None,
+///         &TlsConfiguration::with_roots(tokio_rustls::rustls::RootCertStore {
+///             roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+///         }),
 ///         goodmetrics::proto::goodmetrics::metrics_client::MetricsClient::with_origin,
 ///     ).expect("i can make a channel to goodmetrics"),
 ///     Some(("authorization", "token".parse().expect("must be able to parse header"))),
@@ -72,6 +76,7 @@ pub struct MetricsFactory<TMetricsAllocator, TSink> {
     default_metrics_behavior: u32,
     sink: TSink,
     disabled: bool,
+    metadata: MetadataRegistry,
 }
 
 impl<TMetricsAllocator, TSink> Clone for MetricsFactory<TMetricsAllocator, TSink>
@@ -87,6 +92,7 @@ where
             default_metrics_behavior: self.default_metrics_behavior,
             sink: self.sink.clone(),
             disabled: self.disabled,
+            metadata: self.metadata.clone(),
         }
     }
 }
@@ -203,6 +209,13 @@ impl<TMetricsAllocator, TSink> MetricsFactory<TMetricsAllocator, TSink> {
     pub fn disable(&mut self) {
         self.disabled = true
     }
+
+    /// Units and descriptions for the metrics from this factory. `totaltime` is described
+    /// as nanoseconds for you. Pass a clone to your batcher, like
+    /// `OpentelemetryBatcher::with_metadata`, to send them along.
+    pub fn metadata(&self) -> &MetadataRegistry {
+        &self.metadata
+    }
 }
 
 impl<TMetricsAllocator, TSink> MetricsFactory<TMetricsAllocator, TSink>
@@ -227,6 +240,12 @@ impl<TMetricsAllocator, TSink> MetricsFactory<TMetricsAllocator, TSink> {
         behaviors: &[MetricsBehavior],
         allocator: TMetricsAllocator,
     ) -> Self {
+        let metadata = MetadataRegistry::default();
+        metadata.describe_measurement(
+            "totaltime",
+            "ns",
+            "time from the start of the scope until it completed",
+        );
         MetricsFactory {
             allocator,
             default_metrics_behavior: behaviors
@@ -234,6 +253,7 @@ impl<TMetricsAllocator, TSink> MetricsFactory<TMetricsAllocator, TSink> {
                 .fold(0, |i, behavior| i | (*behavior as u32)),
             sink,
             disabled: false,
+            metadata,
         }
     }
 }
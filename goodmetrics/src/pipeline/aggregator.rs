@@ -1,18 +1,27 @@
 use std::{
-    cmp::min,
     collections::{BTreeMap, HashMap},
     fmt::Display,
+    future::Future,
     mem::replace,
+    pin::pin,
     time::{Duration, Instant, SystemTime},
 };
 
 use exponential_histogram::ExponentialHistogram;
+use futures::future::{select, Either};
 use tokio::sync::mpsc;
 
+use super::{
+    cardinality_limits::{overflow_position, CardinalityLimits},
+    sharded::ShardDrain,
+    DimensionRules, DistributionRules, FanOut, MetricsReceiver,
+};
 use crate::{
-    aggregation::Sum,
+    aggregation::{Exemplar, ExemplarReservoir, Sum},
     allocator::MetricsRef,
-    types::{self, Dimension, Measurement, Name},
+    downstream::Spool,
+    metrics::ExemplarTrace,
+    types::{self, Dimension, Distribution, Measurement, Name},
 };
 
 use crate::aggregation::{AbsorbDistribution, Aggregation, Histogram, OnlineTdigest, StatisticSet};
@@ -27,6 +36,20 @@ pub type DimensionedMeasurementsMap = HashMap<DimensionPosition, MeasurementAggr
 pub type DimensionPosition = BTreeMap<Name, Dimension>;
 /// Within the dimension position there is a collection of named measurements; we'll store the aggregated view of these
 pub type MeasurementAggregationMap = HashMap<Name, Aggregation>;
+/// Exemplars, laid out like AggregatedMetricsMap: metric name -> dimension position -> measurement name
+pub type ExemplarsMap = HashMap<Name, HashMap<DimensionPosition, HashMap<Name, ExemplarReservoir>>>;
+
+/// Exemplars kept per distribution per reporting window, unless you choose otherwise
+const DEFAULT_EXEMPLAR_RESERVOIR_SIZE: usize = 4;
+
+#[cfg(feature = "introspect")]
+static INTROSPECTION_CARDINALITY_OVERFLOWS: crate::introspect::LazyGaugeFamily<
+    crate::gauge::SumHandle,
+> = crate::introspect::LazyGaugeFamily::new_with_dimensions(
+    Name::Str("aggregator_cardinality_overflows"),
+    &[],
+    "metric",
+);
 
 /// Strategies for recording the distribution of observations within each reporting window.
 #[derive(Debug, Clone, Copy)]
@@ -45,8 +68,9 @@ pub enum DistributionMode {
     },
     /// Less space-efficient, less performant, but easy to understand.
     Histogram,
-    /// Fancy sparse sketch distributions. Currently only compatible with
-    /// Goodmetrics downstream, and timescaledb via timescaledb_toolkit.
+    /// Fancy sparse sketch distributions. Goodmetrics downstream sends these natively, for
+    /// timescaledb via timescaledb_toolkit. Opentelemetry gets a Summary or an exponential
+    /// histogram, per its TDigestRepresentation.
     /// You should prefer t-digests when they are available to you :-)
     TDigest,
 }
@@ -66,8 +90,10 @@ impl Display for DistributionMode {
 
 /// Primarily for testing and getting really deep into some stuff, here's
 /// a way to customize how you group aggregates over time.
+#[derive(Default)]
 pub enum TimeSource {
     /// The default time source.
+    #[default]
     SystemTime,
     /// You can customize time.
     DynamicTime {
@@ -87,11 +113,6 @@ impl std::fmt::Debug for TimeSource {
         }
     }
 }
-impl Default for TimeSource {
-    fn default() -> Self {
-        Self::SystemTime
-    }
-}
 
 /// A batcher for aggregated metrics.
 ///
@@ -109,67 +130,300 @@ pub trait AggregationBatcher {
         covered_time: Duration,
         aggregations: &mut AggregatedMetricsMap,
     ) -> Self::TBatch;
+
+    /// Drain the aggregations and the exemplars for them into a batch.
+    ///
+    /// Not every protocol has a place for exemplars, so by default they are dropped.
+    fn batch_aggregations_with_exemplars(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) -> Self::TBatch {
+        exemplars.clear();
+        self.batch_aggregations(now, covered_time, aggregations)
+    }
+
+    /// Keep a batch that did not fit in the channel to its downstream in a spool, for the
+    /// downstream to replay later. Returns false if this batcher's batches can't be spooled.
+    ///
+    /// By default batches can't be spooled, so they are dropped.
+    fn spool_batch(&self, _spool: &Spool, _batch: Self::TBatch) -> bool {
+        false
+    }
+}
+
+// Ensure that you don't tarry long in the drain callback. The aggregator is held up while you are draining.
+// This is to keep overhead relatively low; I don't want to charge you map growth over and over at least for
+// your bread and butter metrics.
+// Just drain into your target type (for example, a metrics batch to send to a goodmetricsd server) within
+// the callback. Send the request outside of this scope.
+/// Where an aggregator puts each window of aggregations
+pub(crate) trait Emit {
+    /// Drain a window without waiting
+    fn emit(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    );
+
+    /// Drain the last window. It is worth waiting for room for this one.
+    async fn emit_final(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    );
+}
+
+/// 1 batcher feeding 1 channel
+pub(crate) struct SendBatches<TAggregationBatcher: AggregationBatcher> {
+    pub(crate) sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+    pub(crate) batcher: TAggregationBatcher,
+    /// Where batches go when the channel is full
+    pub(crate) spool: Option<Spool>,
+}
+
+impl<TAggregationBatcher: AggregationBatcher> Emit for SendBatches<TAggregationBatcher> {
+    fn emit(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        let batch = self.batcher.batch_aggregations_with_exemplars(
+            now,
+            covered_time,
+            aggregations,
+            exemplars,
+        );
+        match self.sender.try_send(batch) {
+            Ok(_) => {
+                log::info!("sent batch to sink")
+            }
+            Err(mpsc::error::TrySendError::Full(batch)) => match &self.spool {
+                Some(spool) if self.batcher.spool_batch(spool, batch) => {
+                    log::warn!(
+                        "metrics batch channel is full - spooled {} metrics batch",
+                        std::any::type_name::<TAggregationBatcher>()
+                    )
+                }
+                _ => log::error!(
+                    "Failed to send {} metrics batch: metrics batch channel is full",
+                    std::any::type_name::<TAggregationBatcher>()
+                ),
+            },
+            Err(error) => {
+                log::error!(
+                    "Failed to send {} metrics batch: {error}",
+                    std::any::type_name::<TAggregationBatcher>()
+                )
+            }
+        }
+    }
+
+    async fn emit_final(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        let batch = self.batcher.batch_aggregations_with_exemplars(
+            now,
+            covered_time,
+            aggregations,
+            exemplars,
+        );
+        match self.sender.send(batch).await {
+            Ok(_) => log::info!("sent final batch to sink"),
+            Err(error) => log::error!(
+                "Failed to send final {} metrics batch: {error}",
+                std::any::type_name::<TAggregationBatcher>()
+            ),
+        }
+    }
 }
 
 /// Aggregates metrics and presents a pollable interface for creating batches of metrics.
 pub struct Aggregator<TMetricsRef> {
-    metrics_queue: std::sync::mpsc::Receiver<TMetricsRef>,
+    metrics_queue: MetricsReceiver<TMetricsRef>,
     map: AggregatedMetricsMap,
+    exemplars: ExemplarsMap,
+    exemplar_reservoir_size: usize,
     distribution_mode: DistributionMode,
+    distribution_rules: DistributionRules,
     time_source: TimeSource,
     cached_position: DimensionPosition,
-    /// A workaround for the tokio::sync::mpsc::Sender charging way too much time
-    /// on send for waking the receiver task across runtimes.
-    poll_interval: Duration,
+    dimension_rules: DimensionRules,
+    cardinality_limits: CardinalityLimits,
+    /// Dimension positions in the current window, across all metrics
+    positions: usize,
+    /// Measurements folded into overflow positions in the current window, by metric
+    cardinality_overflows: HashMap<Name, u64>,
+    /// Where windows go when the channel to the downstream is full
+    spool: Option<Spool>,
 }
 
 impl<TMetricsRef> Aggregator<TMetricsRef>
 where
     TMetricsRef: MetricsRef + Send + 'static,
 {
-    /// Create a new aggregator that pulls from a metrics receiver.
+    /// Create a new aggregator that pulls from a metrics receiver, like the one from StreamSink::new.
     /// Distribution mode customizes how this aggregator will store concrete distributions in memory.
     pub fn new(
-        metrics_queue: std::sync::mpsc::Receiver<TMetricsRef>,
+        metrics_queue: impl Into<MetricsReceiver<TMetricsRef>>,
         distribution_mode: DistributionMode,
     ) -> Self {
         Self {
-            metrics_queue,
+            metrics_queue: metrics_queue.into(),
             map: Default::default(),
+            exemplars: Default::default(),
+            exemplar_reservoir_size: DEFAULT_EXEMPLAR_RESERVOIR_SIZE,
             distribution_mode,
+            distribution_rules: Default::default(),
             time_source: Default::default(),
             cached_position: Default::default(),
-            poll_interval: Duration::from_millis(5),
+            dimension_rules: Default::default(),
+            cardinality_limits: Default::default(),
+            positions: 0,
+            cardinality_overflows: Default::default(),
+            spool: None,
         }
     }
 
     /// Create a new aggregator with an explicit time source. This is mostly for testing.
     #[doc(hidden)]
     pub fn new_with_time_source(
-        metrics_queue: std::sync::mpsc::Receiver<TMetricsRef>,
+        metrics_queue: impl Into<MetricsReceiver<TMetricsRef>>,
         distribution_mode: DistributionMode,
         time_source: TimeSource,
     ) -> Self {
         Self {
-            metrics_queue,
+            metrics_queue: metrics_queue.into(),
             map: Default::default(),
+            exemplars: Default::default(),
+            exemplar_reservoir_size: DEFAULT_EXEMPLAR_RESERVOIR_SIZE,
             distribution_mode,
+            distribution_rules: Default::default(),
             time_source,
             cached_position: Default::default(),
-            poll_interval: Duration::from_millis(5),
+            dimension_rules: Default::default(),
+            cardinality_limits: Default::default(),
+            positions: 0,
+            cardinality_overflows: Default::default(),
+            spool: None,
         }
     }
 
+    /// Keep up to this many exemplars per distribution per reporting window. The default is 4,
+    /// and 0 turns exemplars off.
+    pub fn with_exemplar_reservoir_size(mut self, exemplar_reservoir_size: usize) -> Self {
+        self.exemplar_reservoir_size = exemplar_reservoir_size;
+        self
+    }
+
+    /// Pick the DistributionMode per metric and measurement. Distributions that match no rule
+    /// use the DistributionMode this aggregator was created with.
+    pub fn with_distribution_rules(mut self, distribution_rules: DistributionRules) -> Self {
+        self.distribution_rules = distribution_rules;
+        self
+    }
+
+    /// Rewrite or drop dimensions before they are aggregated, without touching the code that records them.
+    pub fn with_dimension_rules(mut self, dimension_rules: DimensionRules) -> Self {
+        self.dimension_rules = dimension_rules;
+        self
+    }
+
+    /// Limit how many distinct dimension positions are kept per reporting window.
+    /// Measurements for new positions over the limit are folded into an `{overflow=true}` position.
+    pub fn with_cardinality_limits(mut self, cardinality_limits: CardinalityLimits) -> Self {
+        self.cardinality_limits = cardinality_limits;
+        self
+    }
+
+    /// Keep windows that do not fit in the channel to the downstream in this spool, instead of
+    /// dropping them. Give the downstream the same spool with
+    /// `DeliveryConfiguration::aggregator_spool` so it replays them.
+    ///
+    /// Only batchers that know how to spool their batches use it, like GoodmetricsBatcher and
+    /// OpentelemetryBatcher. This applies to aggregate_metrics_forever and aggregate_metrics_until.
+    pub fn with_spool(mut self, spool: Spool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
     /// This task runs a lot. You might want to have a separate 1-2 thread runtime for metrics tasks.
     /// Note that this depends on tokio and the `time` feature.
     pub async fn aggregate_metrics_forever<TAggregationBatcher>(
-        mut self,
+        self,
         cadence: Duration,
         sender: mpsc::Sender<TAggregationBatcher::TBatch>,
-        mut make_batch: TAggregationBatcher,
+        make_batch: TAggregationBatcher,
     ) where
         TAggregationBatcher: AggregationBatcher,
     {
+        self.aggregate_metrics_until(cadence, sender, make_batch, std::future::pending())
+            .await
+    }
+
+    /// Like aggregate_metrics_forever, but it returns after `shutdown` completes.
+    ///
+    /// On shutdown, whatever is already in the metrics queue is aggregated and sent as 1 last,
+    /// partial window. Then the sender is dropped, so once every sender is gone your downstream
+    /// flushes what it has and returns too.
+    pub async fn aggregate_metrics_until<TAggregationBatcher>(
+        self,
+        cadence: Duration,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+        make_batch: TAggregationBatcher,
+        shutdown: impl Future<Output = ()>,
+    ) where
+        TAggregationBatcher: AggregationBatcher,
+    {
+        let spool = self.spool.clone();
+        self.emit_until(
+            cadence,
+            SendBatches {
+                sender,
+                batcher: make_batch,
+                spool,
+            },
+            shutdown,
+        )
+        .await
+    }
+
+    /// Like aggregate_metrics_forever, but each window goes to every downstream in the FanOut.
+    pub async fn fan_out_metrics_forever(self, cadence: Duration, fan_out: FanOut) {
+        self.emit_until(cadence, fan_out, std::future::pending())
+            .await
+    }
+
+    /// Like aggregate_metrics_until, but each window goes to every downstream in the FanOut.
+    pub async fn fan_out_metrics_until(
+        self,
+        cadence: Duration,
+        fan_out: FanOut,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        self.emit_until(cadence, fan_out, shutdown).await
+    }
+
+    async fn emit_until(
+        mut self,
+        cadence: Duration,
+        mut emit: impl Emit,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let mut shutdown = pin!(shutdown);
+        let mut last_emit = self.now_timer();
         // Try to align to some even column since the epoch. It helps make metrics better-aligned when systems have well-aligned clocks.
         // It's usually more convenient in grafana this way.
         let extra_start_offset = self
@@ -178,22 +432,116 @@ where
             .expect("could not get system time")
             .as_millis()
             % cadence.as_millis();
-        tokio::time::sleep(Duration::from_millis(extra_start_offset as u64)).await;
-        let mut last_emit = self.now_timer();
+        let align = pin!(tokio::time::sleep(Duration::from_millis(
+            extra_start_offset as u64
+        )));
+        if let Either::Right(_) = select(align, shutdown.as_mut()).await {
+            self.emit_final_window(last_emit, &mut emit).await;
+            return;
+        }
+        last_emit = self.now_timer();
 
         loop {
-            self.receive_until_next_batch(last_emit, cadence).await;
+            let shut_down = matches!(
+                select(
+                    pin!(self.receive_until_next_batch(last_emit, cadence)),
+                    shutdown.as_mut(),
+                )
+                .await,
+                Either::Right(_)
+            );
+            if shut_down {
+                self.emit_final_window(last_emit, &mut emit).await;
+                return;
+            }
 
             last_emit = self.now_timer();
-            if let Some(batch) = self.drain_into(self.now_wall_clock(), cadence, &mut make_batch) {
-                match sender.try_send(batch) {
-                    Ok(_) => {
-                        log::info!("sent batch to sink")
+            if !self.map.is_empty() {
+                emit.emit(
+                    self.now_wall_clock(),
+                    cadence,
+                    &mut self.map,
+                    &mut self.exemplars,
+                );
+                self.end_window();
+            }
+        }
+    }
+
+    /// Aggregate what is left in the queue, and send it with however much time it covers
+    async fn emit_final_window(&mut self, last_emit: Instant, emit: &mut impl Emit) {
+        while let Ok(more) = self.metrics_queue.try_recv() {
+            self.aggregate_metrics(more);
+        }
+        let covered_time = self.now_timer().saturating_duration_since(last_emit);
+        if !self.map.is_empty() {
+            emit.emit_final(
+                self.now_wall_clock(),
+                covered_time,
+                &mut self.map,
+                &mut self.exemplars,
+            )
+            .await;
+            self.end_window();
+        }
+    }
+
+    /// Aggregate as 1 shard of a ShardedAggregator, handing over each window when asked
+    pub(crate) async fn aggregate_shard(mut self, mut drains: mpsc::Receiver<ShardDrain>) {
+        loop {
+            let drain = match drains.try_recv() {
+                Ok(drain) => drain,
+                Err(mpsc::error::TryRecvError::Disconnected) => return,
+                Err(mpsc::error::TryRecvError::Empty) => {
+                    // Check for drains between chunks, so a busy shard does not hold up the window
+                    let mut received = 0;
+                    while received < 1024 {
+                        match self.metrics_queue.try_recv() {
+                            Ok(more) => {
+                                self.aggregate_metrics(more);
+                                received += 1;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    if 0 < received {
+                        tokio::task::yield_now().await;
+                        continue;
                     }
-                    Err(error) => {
-                        log::error!("Failed to send metrics batch: {error}")
+                    // Idle: sleep until metrics or a drain arrive
+                    let woken = match select(
+                        pin!(self.metrics_queue.recv_timeout(Duration::MAX)),
+                        pin!(drains.recv()),
+                    )
+                    .await
+                    {
+                        Either::Left((more, _)) => Either::Left(more),
+                        Either::Right((drain, _)) => Either::Right(drain),
+                    };
+                    match woken {
+                        Either::Left(Some(more)) => {
+                            self.aggregate_metrics(more);
+                            continue;
+                        }
+                        Either::Left(None) => continue,
+                        Either::Right(Some(drain)) => drain,
+                        Either::Right(None) => return,
                     }
                 }
+            };
+            if drain.final_window {
+                while let Ok(more) = self.metrics_queue.try_recv() {
+                    self.aggregate_metrics(more);
+                }
+            }
+            let window = (
+                std::mem::take(&mut self.map),
+                std::mem::take(&mut self.exemplars),
+            );
+            self.end_window();
+            let _ = drain.respond.send(window);
+            if drain.final_window {
+                return;
             }
         }
     }
@@ -212,30 +560,17 @@ where
         }
     }
 
-    async fn receive_one(&mut self, mut wait_for: Duration) -> bool {
-        loop {
-            match self.metrics_queue.try_recv() {
-                Ok(more) => {
-                    self.aggregate_metrics(more);
-                    break true;
-                }
-                Err(_) => {
-                    let delay = min(wait_for, self.poll_interval);
-                    if delay.is_zero() {
-                        break false;
-                    }
-                    tokio::time::sleep(delay).await;
-                    wait_for -= delay;
-                }
+    async fn receive_one(&mut self, wait_for: Duration) -> bool {
+        match self.metrics_queue.recv_timeout(wait_for).await {
+            Some(more) => {
+                self.aggregate_metrics(more);
+                true
             }
+            None => false,
         }
     }
 
-    // Ensure that you don't tarry long in the drain callback. The aggregator is held up while you are draining.
-    // This is to keep overhead relatively low; I don't want to charge you map growth over and over at least for
-    // your bread and butter metrics.
-    // Just drain into your target type (for example, a metrics batch to send to a goodmetricsd server) within
-    // the callback. Send the request outside of this scope.
+    #[cfg(test)]
     fn drain_into<TAggregationBatcher>(
         &mut self,
         timestamp: SystemTime,
@@ -249,7 +584,29 @@ where
             return None;
         }
 
-        Some(batcher.batch_aggregations(timestamp, duration, &mut self.map))
+        let batch = batcher.batch_aggregations_with_exemplars(
+            timestamp,
+            duration,
+            &mut self.map,
+            &mut self.exemplars,
+        );
+        self.end_window();
+        Some(batch)
+    }
+
+    /// Start counting toward the cardinality limits again, and report what went over them
+    fn end_window(&mut self) {
+        // Batchers usually drain the map, but they do not have to
+        self.positions = self.map.values().map(HashMap::len).sum();
+        for (metrics_name, overflows) in self.cardinality_overflows.drain() {
+            log::warn!(
+                "{metrics_name} went over its cardinality limit - folded {overflows} metrics into its overflow position"
+            );
+            #[cfg(feature = "introspect")]
+            if let Some(gauge) = INTROSPECTION_CARDINALITY_OVERFLOWS.gauge(metrics_name.as_str()) {
+                gauge.observe(overflows as i64)
+            }
+        }
     }
 
     fn aggregate_metrics(&mut self, mut sunk_metrics: TMetricsRef) {
@@ -257,6 +614,17 @@ where
             &mut sunk_metrics.as_mut().metrics_name,
             Name::Str("_uninitialized_"),
         );
+        let mut exemplars = if self.exemplar_reservoir_size == 0 {
+            Vec::new()
+        } else {
+            sunk_metrics.as_mut().take_exemplars()
+        };
+        // Only look at the clock if there is an exemplar to timestamp
+        let exemplar_time = if exemplars.is_empty() {
+            SystemTime::UNIX_EPOCH
+        } else {
+            self.now_wall_clock()
+        };
         let (dimensions, measurements) = sunk_metrics.as_mut().drain();
 
         let dimensioned_measurements_map: &mut DimensionedMeasurementsMap =
@@ -271,18 +639,31 @@ where
             };
 
         self.cached_position.extend(dimensions.drain()); // Use the cached memory
+        if !self.dimension_rules.is_empty() {
+            self.dimension_rules.apply(&mut self.cached_position);
+        }
         let measurements_map: &mut MeasurementAggregationMap =
             match dimensioned_measurements_map.get_mut(&self.cached_position) {
                 Some(map) => map,
                 None => {
+                    if self
+                        .cardinality_limits
+                        .exceeded_by(dimensioned_measurements_map, self.positions)
+                    {
+                        overflow_position(&mut self.cached_position);
+                        *self
+                            .cardinality_overflows
+                            .entry(metrics_name.clone())
+                            .or_default() += 1;
+                    }
                     dimensioned_measurements_map
-                        .insert(self.cached_position.clone(), Default::default());
-                    dimensioned_measurements_map
-                        .get_mut(&self.cached_position)
-                        .expect("I just inserted this 1 line above")
+                        .entry(self.cached_position.clone())
+                        .or_insert_with(|| {
+                            self.positions += 1;
+                            Default::default()
+                        })
                 }
             };
-        self.cached_position.clear(); // Return the cached memory
 
         measurements
             .drain()
@@ -290,26 +671,51 @@ where
                 Measurement::Observation(observation) => {
                     accumulate_statisticset(measurements_map, name, observation);
                 }
-                Measurement::Distribution(distribution) => match self.distribution_mode {
-                    DistributionMode::Histogram => {
-                        accumulate_histogram(measurements_map, name, distribution);
+                Measurement::Distribution(distribution) => {
+                    if let Some(exemplar) =
+                        take_exemplar(&mut exemplars, &name, &distribution, exemplar_time)
+                    {
+                        reservoir(
+                            &mut self.exemplars,
+                            &metrics_name,
+                            &self.cached_position,
+                            &name,
+                            self.exemplar_reservoir_size,
+                        )
+                        .offer(exemplar);
                     }
-                    DistributionMode::TDigest => {
-                        accumulate_tdigest(measurements_map, name, distribution);
+                    // Existing distributions absorb in whatever mode they started the window with
+                    let distribution_mode = if self.distribution_rules.is_empty()
+                        || measurements_map.contains_key(&name)
+                    {
+                        self.distribution_mode
+                    } else {
+                        self.distribution_rules
+                            .distribution_mode(&metrics_name, &name)
+                            .unwrap_or(self.distribution_mode)
+                    };
+                    match distribution_mode {
+                        DistributionMode::Histogram => {
+                            accumulate_histogram(measurements_map, name, distribution);
+                        }
+                        DistributionMode::TDigest => {
+                            accumulate_tdigest(measurements_map, name, distribution);
+                        }
+                        DistributionMode::ExponentialHistogram {
+                            max_buckets,
+                            desired_scale,
+                        } => accumulate_exponential_histogram(
+                            measurements_map,
+                            name,
+                            distribution,
+                            max_buckets,
+                            desired_scale,
+                        ),
                     }
-                    DistributionMode::ExponentialHistogram {
-                        max_buckets,
-                        desired_scale,
-                    } => accumulate_exponential_histogram(
-                        measurements_map,
-                        name,
-                        distribution,
-                        max_buckets,
-                        desired_scale,
-                    ),
-                },
+                }
                 Measurement::Sum(sum) => accumulate_sum(measurements_map, name, sum),
             });
+        self.cached_position.clear(); // Return the cached memory
     }
 
     fn now_wall_clock(&self) -> SystemTime {
@@ -327,6 +733,49 @@ where
     }
 }
 
+/// The exemplar for this measurement, if it was recorded with a trace
+fn take_exemplar(
+    exemplars: &mut Vec<ExemplarTrace>,
+    name: &Name,
+    distribution: &Distribution,
+    time: SystemTime,
+) -> Option<Exemplar> {
+    let index = exemplars
+        .iter()
+        .position(|exemplar| &exemplar.measurement == name)?;
+    let trace = exemplars.swap_remove(index);
+    let value = match distribution {
+        Distribution::I64(i) => *i as f64,
+        Distribution::I32(i) => *i as f64,
+        Distribution::U64(u) => *u as f64,
+        Distribution::U32(u) => *u as f64,
+        Distribution::Collection(collection) => *collection.iter().max()? as f64,
+        Distribution::Timer { nanos } => nanos.load(std::sync::atomic::Ordering::Acquire) as f64,
+    };
+    Some(Exemplar {
+        trace_id: trace.trace_id,
+        span_id: trace.span_id,
+        value,
+        time,
+    })
+}
+
+fn reservoir<'a>(
+    exemplars: &'a mut ExemplarsMap,
+    metrics_name: &Name,
+    position: &DimensionPosition,
+    name: &Name,
+    capacity: usize,
+) -> &'a mut ExemplarReservoir {
+    exemplars
+        .entry(metrics_name.clone())
+        .or_default()
+        .entry(position.clone())
+        .or_default()
+        .entry(name.clone())
+        .or_insert_with(|| ExemplarReservoir::new(capacity))
+}
+
 fn accumulate_histogram(
     measurements_map: &mut HashMap<Name, Aggregation>,
     name: Name,
@@ -452,8 +901,16 @@ mod test {
     use crate::{
         aggregation::StatisticSet,
         allocator::{AlwaysNewMetricsAllocator, MetricsAllocator},
+        downstream::{
+            ConfiguredOpentelemetryBatcher, GoodmetricsBatcher, OpentelemetryBatcher,
+            TDigestRepresentation,
+        },
         metrics::Metrics,
-        pipeline::aggregator::{Aggregation, Aggregator, DistributionMode},
+        pipeline::{
+            aggregator::{Aggregation, Aggregator, DistributionMode},
+            CardinalityLimits, DimensionRules, DistributionRules, NamePattern,
+        },
+        proto::opentelemetry::metrics::v1::{exemplar, metric::Data},
         types::{Dimension, Name, Observation},
     };
 
@@ -561,6 +1018,376 @@ mod test {
         assert_eq!(HashMap::from([]), sink.map);
     }
 
+    /// Every distribution mode has to make it through every batcher without blowing up the aggregator.
+    #[test_log::test(tokio::test)]
+    async fn every_distribution_mode_works_with_every_batcher() {
+        for distribution_mode in [
+            DistributionMode::ExponentialHistogram {
+                max_buckets: 160,
+                desired_scale: 8,
+            },
+            DistributionMode::Histogram,
+            DistributionMode::TDigest,
+        ] {
+            let otlp_batchers = [
+                ConfiguredOpentelemetryBatcher::default(),
+                OpentelemetryBatcher.with_tdigest_representation(
+                    TDigestRepresentation::ExponentialHistogram {
+                        max_buckets: 160,
+                        desired_scale: 8,
+                    },
+                ),
+            ];
+            for mut otlp_batcher in otlp_batchers {
+                let mut sink = distribution_aggregator(distribution_mode).await;
+                let batch = sink
+                    .drain_into(SystemTime::now(), Duration::from_secs(1), &mut otlp_batcher)
+                    .expect("there should be contents in the batch");
+                assert_eq!(1, batch.len(), "{distribution_mode}: {batch:?}");
+                assert!(batch[0].data.is_some(), "{distribution_mode}");
+            }
+
+            let mut sink = distribution_aggregator(distribution_mode).await;
+            let batch = sink
+                .drain_into(
+                    SystemTime::now(),
+                    Duration::from_secs(1),
+                    &mut GoodmetricsBatcher,
+                )
+                .expect("there should be contents in the batch");
+            assert_eq!(1, batch.len(), "{distribution_mode}: {batch:?}");
+            assert_eq!(1, batch[0].measurements.len(), "{distribution_mode}");
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn tdigest_as_otlp_summary() {
+        let mut sink = distribution_aggregator(DistributionMode::TDigest).await;
+        let batch = sink
+            .drain_into(
+                SystemTime::now(),
+                Duration::from_secs(1),
+                &mut OpentelemetryBatcher.with_tdigest_representation(
+                    TDigestRepresentation::Summary {
+                        quantiles: vec![0.0, 0.5, 1.0],
+                    },
+                ),
+            )
+            .expect("there should be contents in the batch");
+
+        let Some(Data::Summary(summary)) = &batch[0].data else {
+            panic!("expected a summary: {batch:?}")
+        };
+        let point = &summary.data_points[0];
+        assert_eq!(100, point.count);
+        assert_eq!(5050.0, point.sum);
+        let quantiles: Vec<(f64, f64)> = point
+            .quantile_values
+            .iter()
+            .map(|v| (v.quantile, v.value))
+            .collect();
+        assert_eq!(3, quantiles.len());
+        assert_eq!((0.0, 1.0), quantiles[0]);
+        assert!((quantiles[1].1 - 50.0).abs() < 2.0, "{quantiles:?}");
+        assert_eq!((1.0, 100.0), quantiles[2]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn tdigest_as_otlp_exponential_histogram() {
+        let mut sink = distribution_aggregator(DistributionMode::TDigest).await;
+        let batch = sink
+            .drain_into(
+                SystemTime::now(),
+                Duration::from_secs(1),
+                &mut OpentelemetryBatcher.with_tdigest_representation(
+                    TDigestRepresentation::ExponentialHistogram {
+                        max_buckets: 16,
+                        desired_scale: 8,
+                    },
+                ),
+            )
+            .expect("there should be contents in the batch");
+
+        let Some(Data::ExponentialHistogram(histogram)) = &batch[0].data else {
+            panic!("expected an exponential histogram: {batch:?}")
+        };
+        let point = &histogram.data_points[0];
+        assert_eq!(100, point.count);
+        assert_eq!(5050.0, point.sum);
+        assert_eq!((1.0, 100.0), (point.min, point.max));
+        let positive = point.positive.as_ref().expect("positive buckets");
+        assert!(positive.bucket_counts.len() <= 16);
+        assert_eq!(100, positive.bucket_counts.iter().sum::<u64>());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn shutdown_sends_a_final_partial_window() {
+        struct CoveredTimeBatcher;
+        impl AggregationBatcher for CoveredTimeBatcher {
+            type TBatch = (Duration, Vec<(Name, DimensionedMeasurementsMap)>);
+
+            fn batch_aggregations(
+                &mut self,
+                _now: SystemTime,
+                covered_time: Duration,
+                aggregations: &mut super::AggregatedMetricsMap,
+            ) -> Self::TBatch {
+                (covered_time, aggregations.drain().collect())
+            }
+        }
+
+        let (sender, receiver) = sync_channel(16);
+        let aggregator: Aggregator<Metrics> =
+            Aggregator::new(receiver, DistributionMode::Histogram);
+        let (batch_sender, mut batch_receiver) = tokio::sync::mpsc::channel(16);
+        let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let cadence = Duration::from_secs(3600);
+        let aggregating = tokio::spawn(aggregator.aggregate_metrics_until(
+            cadence,
+            batch_sender,
+            CoveredTimeBatcher,
+            async move {
+                let _ = shutdown_receiver.await;
+            },
+        ));
+
+        sender
+            .try_send(get_metrics("a", "dimension", "v", 22))
+            .unwrap();
+        sender
+            .try_send(get_metrics("a", "dimension", "v", 20))
+            .unwrap();
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), aggregating)
+            .await
+            .expect("the aggregator should stop promptly")
+            .unwrap();
+
+        let (covered_time, batch) = batch_receiver
+            .recv()
+            .await
+            .expect("there should be a final batch");
+        assert!(covered_time < cadence, "{covered_time:?}");
+        assert_eq!(
+            Vec::from([(
+                Name::from("test"),
+                HashMap::from([(
+                    BTreeMap::from([(Name::from("a"), Dimension::from("dimension"))]),
+                    HashMap::from([(
+                        Name::from("v"),
+                        Aggregation::StatisticSet(StatisticSet {
+                            min: 20,
+                            max: 22,
+                            sum: 42,
+                            count: 2
+                        })
+                    )])
+                )])
+            )]),
+            batch,
+        );
+        assert!(
+            batch_receiver.recv().await.is_none(),
+            "the batch sender is dropped on shutdown"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn exemplars_reach_otlp_histograms() {
+        let (sender, receiver) = sync_channel(16);
+        let mut sink: Aggregator<Metrics> = Aggregator::new(
+            receiver,
+            DistributionMode::ExponentialHistogram {
+                max_buckets: 160,
+                desired_scale: 8,
+            },
+        )
+        .with_exemplar_reservoir_size(2);
+        for i in 1..=10_u8 {
+            let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+            metrics.distribution_with_exemplar("d", i as i64, [i; 16], [i; 8]);
+            sender.try_send(metrics).unwrap();
+        }
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+        metrics.distribution("d", 11);
+        sender.try_send(metrics).unwrap();
+        while sink.receive_one(Duration::from_millis(1)).await {}
+
+        let batch = sink
+            .drain_into(
+                SystemTime::now(),
+                Duration::from_secs(1),
+                &mut OpentelemetryBatcher,
+            )
+            .expect("there should be contents in the batch");
+        assert!(sink.exemplars.is_empty(), "exemplars are drained too");
+
+        let Some(Data::ExponentialHistogram(histogram)) = &batch[0].data else {
+            panic!("expected an exponential histogram: {batch:?}")
+        };
+        let point = &histogram.data_points[0];
+        assert_eq!(11, point.count);
+        assert_eq!(2, point.exemplars.len(), "the reservoir is bounded");
+        for exemplar in &point.exemplars {
+            let Some(exemplar::Value::AsDouble(value)) = exemplar.value else {
+                panic!("exemplars have values: {exemplar:?}")
+            };
+            let i = value as u8;
+            assert!((1..=10).contains(&i), "{value}");
+            assert_eq!(vec![i; 16], exemplar.trace_id, "trace goes with its value");
+            assert_eq!(vec![i; 8], exemplar.span_id, "span goes with its value");
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn exemplars_can_be_turned_off() {
+        let (sender, receiver) = sync_channel(16);
+        let mut sink: Aggregator<Metrics> =
+            Aggregator::new(receiver, DistributionMode::Histogram).with_exemplar_reservoir_size(0);
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+        let _timer = metrics.time_with_exemplar("t", [1; 16], [1; 8]);
+        sender.try_send(metrics).unwrap();
+        assert!(sink.receive_one(Duration::from_millis(1)).await);
+
+        assert!(sink.exemplars.is_empty());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn cardinality_limits_fold_new_positions_into_overflow() {
+        let (sender, receiver) = sync_channel(64);
+        let mut cardinality_limits = CardinalityLimits::default();
+        cardinality_limits.per_metric(3);
+        cardinality_limits.total(5);
+        let mut sink: Aggregator<Metrics> = Aggregator::new(receiver, DistributionMode::Histogram)
+            .with_cardinality_limits(cardinality_limits);
+
+        for window in 0..2 {
+            for user in 0..5_u64 {
+                sender.try_send(get_metrics("user", user, "v", 1)).unwrap();
+            }
+            // Positions that made it in before the limit keep aggregating
+            sender.try_send(get_metrics("user", 0_u64, "v", 1)).unwrap();
+            let mut other = AlwaysNewMetricsAllocator.new_metrics("other");
+            other.dimension("user", 9_u64);
+            other.measurement("v", 1);
+            sender.try_send(other).unwrap();
+            let mut over_total = AlwaysNewMetricsAllocator.new_metrics("other");
+            over_total.dimension("user", 10_u64);
+            over_total.measurement("v", 1);
+            sender.try_send(over_total).unwrap();
+            while sink.receive_one(Duration::from_millis(1)).await {}
+
+            let batch: HashMap<Name, DimensionedMeasurementsMap> = sink
+                .drain_into(
+                    SystemTime::now(),
+                    Duration::from_secs(1),
+                    &mut TestAggregationBatcher,
+                )
+                .expect("there should be contents in the batch")
+                .into_iter()
+                .collect();
+            let count = |metric: &'static str, position: (&'static str, Dimension)| match &batch
+                [&Name::from(metric)][&BTreeMap::from([(Name::from(position.0), position.1)])]
+                [&Name::from("v")]
+            {
+                Aggregation::StatisticSet(statistic_set) => statistic_set.count,
+                other => panic!("expected a statistic set: {other}"),
+            };
+            assert_eq!(4, batch[&Name::from("test")].len(), "window {window}");
+            assert_eq!(2, count("test", ("user", Dimension::from(0_u64))));
+            assert_eq!(1, count("test", ("user", Dimension::from(2_u64))));
+            assert_eq!(
+                2,
+                count("test", ("overflow", Dimension::from(true))),
+                "users 3 and 4 are over the per-metric limit"
+            );
+            assert_eq!(1, count("other", ("user", Dimension::from(9_u64))));
+            assert_eq!(
+                1,
+                count("other", ("overflow", Dimension::from(true))),
+                "user 10 is over the total limit"
+            );
+            assert!(sink.cardinality_overflows.is_empty());
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn distribution_rules_pick_the_mode() {
+        let mut distribution_rules = DistributionRules::default();
+        distribution_rules.rule(
+            NamePattern::Exact("test".to_string()),
+            NamePattern::Glob("*_latency".to_string()),
+            DistributionMode::TDigest,
+        );
+        let (sender, receiver) = sync_channel(1);
+        let mut sink: Aggregator<Metrics> = Aggregator::new(receiver, DistributionMode::Histogram)
+            .with_distribution_rules(distribution_rules);
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+        metrics.distribution("db_latency", 1);
+        metrics.distribution("size", 1);
+        sender.try_send(metrics).unwrap();
+        assert!(sink.receive_one(Duration::from_millis(1)).await);
+
+        let measurements = &sink.map[&Name::Str("test")][&BTreeMap::new()];
+        assert!(matches!(
+            measurements[&Name::Str("db_latency")],
+            Aggregation::TDigest(_)
+        ));
+        assert!(matches!(
+            measurements[&Name::Str("size")],
+            Aggregation::Histogram(_)
+        ));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn dimension_rules_rewrite_before_positions_are_counted() {
+        let mut dimension_rules = DimensionRules::default();
+        dimension_rules.drop("request_id");
+        let mut cardinality_limits = CardinalityLimits::default();
+        cardinality_limits.per_metric(1);
+        let (sender, receiver) = sync_channel(16);
+        let mut sink: Aggregator<Metrics> = Aggregator::new(receiver, DistributionMode::Histogram)
+            .with_dimension_rules(dimension_rules)
+            .with_cardinality_limits(cardinality_limits);
+        for request_id in 0..3_u64 {
+            sender
+                .try_send(get_metrics("request_id", request_id, "latency", 1))
+                .unwrap();
+            assert!(sink.receive_one(Duration::from_millis(1)).await);
+        }
+
+        assert_eq!(
+            HashMap::from([(
+                Name::Str("test"),
+                HashMap::from([(
+                    BTreeMap::new(),
+                    HashMap::from([(
+                        Name::Str("latency"),
+                        Aggregation::StatisticSet(StatisticSet {
+                            min: 1,
+                            max: 1,
+                            sum: 3,
+                            count: 3
+                        })
+                    )])
+                )])
+            )]),
+            sink.map
+        );
+        assert!(sink.cardinality_overflows.is_empty());
+    }
+
+    /// An aggregator holding 1..=100 in a distribution
+    async fn distribution_aggregator(distribution_mode: DistributionMode) -> Aggregator<Metrics> {
+        let (sender, receiver) = sync_channel(1);
+        let mut sink: Aggregator<Metrics> = Aggregator::new(receiver, distribution_mode);
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+        metrics.distribution("d", (1..=100).collect::<Vec<i64>>());
+        sender.try_send(metrics).unwrap();
+        assert!(sink.receive_one(Duration::from_millis(1)).await);
+        sink
+    }
+
     fn get_metrics(
         dimension_name: impl Into<Name>,
         dimension: impl Into<Dimension>,
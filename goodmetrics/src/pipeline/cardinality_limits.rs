@@ -0,0 +1,57 @@
+use crate::{types::Dimension, Name};
+
+use super::{DimensionPosition, DimensionedMeasurementsMap};
+
+/// How many distinct dimension positions an Aggregator keeps per reporting window.
+///
+/// Once a limit is hit, measurements for new positions are folded into 1 `{overflow=true}`
+/// position for their metric instead, so an unbounded dimension like a user id cannot grow
+/// the aggregator's memory without bound. Positions that are already in the window keep
+/// aggregating normally.
+///
+/// Folded measurements are logged when the window is emitted, and counted in `gm_introspect`
+/// as `aggregator_cardinality_overflows` by `metric` with the introspect feature.
+///
+/// The default is unlimited.
+#[derive(Debug, Clone, Copy)]
+pub struct CardinalityLimits {
+    per_metric: usize,
+    total: usize,
+}
+
+impl Default for CardinalityLimits {
+    fn default() -> Self {
+        Self {
+            per_metric: usize::MAX,
+            total: usize::MAX,
+        }
+    }
+}
+
+impl CardinalityLimits {
+    /// Set the most dimension positions 1 metric may have in a window (default unlimited)
+    pub fn per_metric(&mut self, per_metric: usize) {
+        self.per_metric = per_metric
+    }
+
+    /// Set the most dimension positions all metrics together may have in a window (default unlimited).
+    /// Overflow positions count toward this too.
+    pub fn total(&mut self, total: usize) {
+        self.total = total
+    }
+
+    /// Whether a new position would go over a limit
+    pub(crate) fn exceeded_by(
+        &self,
+        metric_positions: &DimensionedMeasurementsMap,
+        total_positions: usize,
+    ) -> bool {
+        self.per_metric <= metric_positions.len() || self.total <= total_positions
+    }
+}
+
+/// Where measurements go when their own position would go over a limit
+pub(crate) fn overflow_position(position: &mut DimensionPosition) {
+    position.clear();
+    position.insert(Name::Str("overflow"), Dimension::Boolean(true));
+}
@@ -0,0 +1,280 @@
+use crate::{types::Dimension, Name};
+
+use super::DimensionPosition;
+
+/// Rewrites dimensions centrally, before an Aggregator looks up their position.
+///
+/// Dropping or collapsing a noisy dimension here keeps it from costing memory in the
+/// Aggregator, and from counting toward its cardinality limits. Rules run in the order you
+/// add them, so a rule sees the names and values that earlier rules produced.
+///
+/// Dimension names and string values match by their text, whichever kind of Name or
+/// Dimension they were recorded with.
+/// ```
+/// # use goodmetrics::pipeline::DimensionRules;
+/// let mut dimension_rules = DimensionRules::default();
+/// dimension_rules.drop("request_id");
+/// dimension_rules.status_class("status");
+/// dimension_rules.allow_values("tenant", ["acme", "initech"], "other");
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DimensionRules {
+    rules: Vec<DimensionRule>,
+}
+
+#[derive(Debug, Clone)]
+enum DimensionRule {
+    Drop(Name),
+    Rename {
+        from: Name,
+        to: Name,
+    },
+    AllowOnly(Vec<Name>),
+    MapValues {
+        dimension: Name,
+        values: Vec<(Dimension, Dimension)>,
+        otherwise: Option<Dimension>,
+    },
+    StatusClass(Name),
+}
+
+impl DimensionRules {
+    /// Remove this dimension
+    pub fn drop(&mut self, dimension: impl Into<Name>) {
+        self.rules.push(DimensionRule::Drop(dimension.into()))
+    }
+
+    /// Call this dimension something else. It replaces any dimension already called `to`.
+    pub fn rename(&mut self, from: impl Into<Name>, to: impl Into<Name>) {
+        self.rules.push(DimensionRule::Rename {
+            from: from.into(),
+            to: to.into(),
+        })
+    }
+
+    /// Remove every dimension except these
+    pub fn allow_only(&mut self, dimensions: impl IntoIterator<Item = impl Into<Name>>) {
+        self.rules.push(DimensionRule::AllowOnly(
+            dimensions.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Replace values of this dimension. Values that are not in the map are kept as they are.
+    pub fn map_values(
+        &mut self,
+        dimension: impl Into<Name>,
+        values: impl IntoIterator<Item = (impl Into<Dimension>, impl Into<Dimension>)>,
+    ) {
+        self.rules.push(DimensionRule::MapValues {
+            dimension: dimension.into(),
+            values: values
+                .into_iter()
+                .map(|(from, to)| (from.into(), to.into()))
+                .collect(),
+            otherwise: None,
+        })
+    }
+
+    /// Keep these values of this dimension, and replace every other value with `otherwise`
+    pub fn allow_values(
+        &mut self,
+        dimension: impl Into<Name>,
+        values: impl IntoIterator<Item = impl Into<Dimension>>,
+        otherwise: impl Into<Dimension>,
+    ) {
+        self.rules.push(DimensionRule::MapValues {
+            dimension: dimension.into(),
+            values: values
+                .into_iter()
+                .map(|value| {
+                    let value = value.into();
+                    (value.clone(), value)
+                })
+                .collect(),
+            otherwise: Some(otherwise.into()),
+        })
+    }
+
+    /// Collapse http status codes in this dimension to their class, like 404 to `4xx`.
+    /// Values that are not status codes are kept as they are.
+    pub fn status_class(&mut self, dimension: impl Into<Name>) {
+        self.rules
+            .push(DimensionRule::StatusClass(dimension.into()))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rewrite a position in place
+    pub(crate) fn apply(&self, position: &mut DimensionPosition) {
+        for rule in &self.rules {
+            match rule {
+                DimensionRule::Drop(dimension) => {
+                    position.retain(|name, _| name.as_str() != dimension.as_str())
+                }
+                DimensionRule::Rename { from, to } => {
+                    if let Some(value) = take(position, from) {
+                        take(position, to);
+                        position.insert(to.clone(), value);
+                    }
+                }
+                DimensionRule::AllowOnly(dimensions) => position.retain(|name, _| {
+                    dimensions
+                        .iter()
+                        .any(|dimension| dimension.as_str() == name.as_str())
+                }),
+                DimensionRule::MapValues {
+                    dimension,
+                    values,
+                    otherwise,
+                } => {
+                    if let Some(value) = value_mut(position, dimension) {
+                        match values.iter().find(|(from, _)| same_value(from, value)) {
+                            Some((_, to)) => {
+                                if !same_value(to, value) {
+                                    *value = to.clone()
+                                }
+                            }
+                            None => {
+                                if let Some(otherwise) = otherwise {
+                                    *value = otherwise.clone()
+                                }
+                            }
+                        }
+                    }
+                }
+                DimensionRule::StatusClass(dimension) => {
+                    if let Some(value) = value_mut(position, dimension) {
+                        if let Some(class) = status_class(value) {
+                            *value = Dimension::Str(class)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn take(position: &mut DimensionPosition, dimension: &Name) -> Option<Dimension> {
+    let name = position
+        .keys()
+        .find(|name| name.as_str() == dimension.as_str())?
+        .clone();
+    position.remove(&name)
+}
+
+fn value_mut<'a>(
+    position: &'a mut DimensionPosition,
+    dimension: &Name,
+) -> Option<&'a mut Dimension> {
+    position
+        .iter_mut()
+        .find(|(name, _)| name.as_str() == dimension.as_str())
+        .map(|(_, value)| value)
+}
+
+fn same_value(a: &Dimension, b: &Dimension) -> bool {
+    match (dimension_str(a), dimension_str(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn dimension_str(dimension: &Dimension) -> Option<&str> {
+    match dimension {
+        Dimension::Str(s) => Some(s),
+        Dimension::String(s) => Some(s),
+        Dimension::Shared(s) => Some(s),
+        Dimension::Number(_) | Dimension::Boolean(_) => None,
+    }
+}
+
+fn status_class(dimension: &Dimension) -> Option<&'static str> {
+    let code = match dimension {
+        Dimension::Number(n) => *n,
+        _ => dimension_str(dimension)?.parse().ok()?,
+    };
+    match code {
+        100..=199 => Some("1xx"),
+        200..=299 => Some("2xx"),
+        300..=399 => Some("3xx"),
+        400..=499 => Some("4xx"),
+        500..=599 => Some("5xx"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use crate::{pipeline::DimensionPosition, types::Dimension, Name};
+
+    use super::DimensionRules;
+
+    fn position(dimensions: &[(&'static str, Dimension)]) -> DimensionPosition {
+        dimensions
+            .iter()
+            .map(|(name, value)| (Name::Str(name), value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn rules_rewrite_in_order() {
+        let mut rules = DimensionRules::default();
+        rules.drop("request_id");
+        rules.rename("code", "status");
+        rules.status_class("status");
+        rules.allow_values("tenant", ["acme", "initech"], "other");
+        rules.map_values("region", [("us-west-2", "us")]);
+
+        let mut rewritten = BTreeMap::from([
+            (
+                Name::String("request_id".to_string()),
+                Dimension::from("abc"),
+            ),
+            (Name::Str("code"), Dimension::Number(404)),
+            (Name::Str("tenant"), Dimension::Str("globex")),
+            (Name::Str("region"), Dimension::Str("us-west-2")),
+        ]);
+        rules.apply(&mut rewritten);
+        assert_eq!(
+            position(&[
+                ("status", Dimension::Str("4xx")),
+                ("tenant", Dimension::Str("other")),
+                ("region", Dimension::Str("us")),
+            ]),
+            rewritten
+        );
+
+        let mut kept = position(&[
+            ("tenant", Dimension::Shared(Arc::new("acme".to_string()))),
+            ("status", Dimension::Str("teapot")),
+            ("region", Dimension::Str("eu")),
+        ]);
+        let expected = kept.clone();
+        rules.apply(&mut kept);
+        assert_eq!(expected, kept);
+    }
+
+    #[test]
+    fn allow_only_drops_everything_else() {
+        let mut rules = DimensionRules::default();
+        rules.allow_only(["method", "status"]);
+
+        let mut rewritten = position(&[
+            ("method", Dimension::Str("GET")),
+            ("status", Dimension::Number(200)),
+            ("path", Dimension::Str("/users/1")),
+        ]);
+        rules.apply(&mut rewritten);
+        assert_eq!(
+            position(&[
+                ("method", Dimension::Str("GET")),
+                ("status", Dimension::Number(200)),
+            ]),
+            rewritten
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use crate::Name;
+
+use super::DistributionMode;
+
+/// Which metric or measurement names a distribution rule applies to
+#[derive(Debug, Clone)]
+pub enum NamePattern {
+    /// Every name
+    Any,
+    /// Only this name
+    Exact(String),
+    /// Names that start with this
+    Prefix(String),
+    /// `*` matches any run of characters and `?` matches any 1 character, like `*_latency`
+    Glob(String),
+}
+
+impl NamePattern {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            NamePattern::Any => true,
+            NamePattern::Exact(exact) => exact == name,
+            NamePattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            NamePattern::Glob(glob) => glob_matches(glob, name),
+        }
+    }
+}
+
+/// Picks a DistributionMode per metric and measurement, instead of 1 for the whole Aggregator.
+///
+/// Rules are checked in the order you add them and the first match wins. Distributions that
+/// match no rule use the Aggregator's DistributionMode.
+///
+/// A distribution keeps the mode it started the reporting window with. Rules are only checked
+/// when a measurement first shows up in a window, so they cost nothing per measurement after that.
+#[derive(Debug, Clone, Default)]
+pub struct DistributionRules {
+    rules: Vec<(NamePattern, NamePattern, DistributionMode)>,
+}
+
+impl DistributionRules {
+    /// Use distribution_mode for measurements that match measurement, in metrics that match metric
+    pub fn rule(
+        &mut self,
+        metric: NamePattern,
+        measurement: NamePattern,
+        distribution_mode: DistributionMode,
+    ) {
+        self.rules.push((metric, measurement, distribution_mode))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The mode of the first rule that matches, if any
+    pub(crate) fn distribution_mode(
+        &self,
+        metrics_name: &Name,
+        measurement_name: &Name,
+    ) -> Option<DistributionMode> {
+        self.rules
+            .iter()
+            .find(|(metric, measurement, _)| {
+                metric.matches(metrics_name.as_str())
+                    && measurement.matches(measurement_name.as_str())
+            })
+            .map(|(_, _, distribution_mode)| *distribution_mode)
+    }
+}
+
+fn glob_matches(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut g, mut n) = (0, 0);
+    // Where to pick back up if the most recent * needs to match more characters
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                backtrack = Some((g, n));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    g = star + 1;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{pipeline::DistributionMode, Name};
+
+    use super::{DistributionRules, NamePattern};
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let mut rules = DistributionRules::default();
+        rules.rule(
+            NamePattern::Exact("api".to_string()),
+            NamePattern::Glob("*_l?tency".to_string()),
+            DistributionMode::TDigest,
+        );
+        rules.rule(
+            NamePattern::Prefix("legacy_".to_string()),
+            NamePattern::Any,
+            DistributionMode::Histogram,
+        );
+        rules.rule(
+            NamePattern::Any,
+            NamePattern::Any,
+            DistributionMode::TDigest,
+        );
+
+        let mode = |metric: &'static str, measurement: &'static str| {
+            rules
+                .distribution_mode(&Name::Str(metric), &Name::Str(measurement))
+                .map(|mode| mode.to_string())
+        };
+        assert_eq!(Some("t_digest".to_string()), mode("api", "db_latency"));
+        assert_eq!(
+            Some("histogram".to_string()),
+            mode("legacy_api", "db_latency")
+        );
+        assert_eq!(Some("t_digest".to_string()), mode("other", "anything"));
+        assert!(DistributionRules::default()
+            .distribution_mode(&Name::Str("api"), &Name::Str("x"))
+            .is_none());
+    }
+
+    #[test]
+    fn globs() {
+        for (glob, name, matches) in [
+            ("*", "", true),
+            ("*", "anything", true),
+            ("*_latency", "db_latency", true),
+            ("*_latency", "db_latency_ms", false),
+            ("db_*_ms", "db_read_write_ms", true),
+            ("db_*_ms", "db_ms", false),
+            ("?b", "db", true),
+            ("?b", "b", false),
+            ("a*b*c", "aXbYbZc", true),
+            ("a*b*c", "aXcYb", false),
+        ] {
+            assert_eq!(matches, super::glob_matches(glob, name), "{glob} {name}");
+        }
+    }
+}
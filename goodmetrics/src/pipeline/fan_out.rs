@@ -0,0 +1,244 @@
+use std::time::{Duration, SystemTime};
+
+use futures::future::BoxFuture;
+use tokio::sync::mpsc;
+
+use super::{
+    aggregator::{Emit, SendBatches},
+    AggregatedMetricsMap, AggregationBatcher, ExemplarsMap,
+};
+use crate::downstream::Spool;
+
+/// Sends each aggregated window to several downstreams, like goodmetricsd and an
+/// opentelemetry collector at the same time.
+///
+/// Every downstream gets its own batcher and its own channel. A slow or failing downstream
+/// fills up its own channel and drops or spools its own batches; the others keep going.
+/// ```
+/// # use std::time::Duration;
+/// # use goodmetrics::{
+/// #     downstream::{GoodmetricsBatcher, OpentelemetryBatcher},
+/// #     pipeline::{Aggregator, DistributionMode, FanOut, StreamSink},
+/// #     Metrics,
+/// # };
+/// # async fn example() {
+/// let (sink, receiver) = StreamSink::<Box<Metrics>>::new();
+/// let aggregator = Aggregator::new(receiver, DistributionMode::Histogram);
+/// let (goodmetrics_sender, goodmetrics_receiver) = tokio::sync::mpsc::channel(128);
+/// let (otlp_sender, otlp_receiver) = tokio::sync::mpsc::channel(128);
+/// let fan_out = FanOut::default()
+///     .with_downstream(GoodmetricsBatcher, goodmetrics_sender)
+///     .with_downstream(OpentelemetryBatcher, otlp_sender);
+/// tokio::spawn(aggregator.fan_out_metrics_forever(Duration::from_secs(1), fan_out));
+/// // Then each downstream sends from its own receiver, like:
+/// // tokio::spawn(goodmetrics_downstream.send_batches_forever(goodmetrics_receiver));
+/// // tokio::spawn(otlp_downstream.send_batches_forever(otlp_receiver));
+/// # }
+/// ```
+#[derive(Default)]
+pub struct FanOut {
+    downstreams: Vec<Box<dyn FanOutDownstream + Send>>,
+}
+
+impl std::fmt::Debug for FanOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FanOut")
+            .field("downstreams", &self.downstreams.len())
+            .finish()
+    }
+}
+
+impl FanOut {
+    /// Also send each window to this channel, batched by this batcher
+    pub fn with_downstream<TAggregationBatcher>(
+        mut self,
+        batcher: TAggregationBatcher,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+    ) -> Self
+    where
+        TAggregationBatcher: AggregationBatcher + Send + 'static,
+        TAggregationBatcher::TBatch: Send,
+    {
+        self.downstreams.push(Box::new(SendBatches {
+            sender,
+            batcher,
+            spool: None,
+        }));
+        self
+    }
+
+    /// Like with_downstream, but windows that do not fit in the channel go to the spool,
+    /// like with Aggregator::with_spool.
+    pub fn with_spooled_downstream<TAggregationBatcher>(
+        mut self,
+        batcher: TAggregationBatcher,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+        spool: Spool,
+    ) -> Self
+    where
+        TAggregationBatcher: AggregationBatcher + Send + 'static,
+        TAggregationBatcher::TBatch: Send,
+    {
+        self.downstreams.push(Box::new(SendBatches {
+            sender,
+            batcher,
+            spool: Some(spool),
+        }));
+        self
+    }
+}
+
+/// Emit, but boxed so different batchers can share a Vec
+trait FanOutDownstream {
+    fn emit(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    );
+
+    fn emit_final<'a>(
+        &'a mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &'a mut AggregatedMetricsMap,
+        exemplars: &'a mut ExemplarsMap,
+    ) -> BoxFuture<'a, ()>;
+}
+
+impl<TAggregationBatcher> FanOutDownstream for SendBatches<TAggregationBatcher>
+where
+    TAggregationBatcher: AggregationBatcher + Send,
+    TAggregationBatcher::TBatch: Send,
+{
+    fn emit(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        Emit::emit(self, now, covered_time, aggregations, exemplars)
+    }
+
+    fn emit_final<'a>(
+        &'a mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &'a mut AggregatedMetricsMap,
+        exemplars: &'a mut ExemplarsMap,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(Emit::emit_final(
+            self,
+            now,
+            covered_time,
+            aggregations,
+            exemplars,
+        ))
+    }
+}
+
+impl Emit for FanOut {
+    fn emit(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        let Some((last, others)) = self.downstreams.split_last_mut() else {
+            aggregations.clear();
+            exemplars.clear();
+            return;
+        };
+        // Batchers drain what they are given, so all but the last get a copy
+        for downstream in others {
+            downstream.emit(
+                now,
+                covered_time,
+                &mut aggregations.clone(),
+                &mut exemplars.clone(),
+            );
+        }
+        last.emit(now, covered_time, aggregations, exemplars);
+    }
+
+    async fn emit_final(
+        &mut self,
+        now: SystemTime,
+        covered_time: Duration,
+        aggregations: &mut AggregatedMetricsMap,
+        exemplars: &mut ExemplarsMap,
+    ) {
+        let Some((last, others)) = self.downstreams.split_last_mut() else {
+            return;
+        };
+        for downstream in others {
+            downstream
+                .emit_final(
+                    now,
+                    covered_time,
+                    &mut aggregations.clone(),
+                    &mut exemplars.clone(),
+                )
+                .await;
+        }
+        last.emit_final(now, covered_time, aggregations, exemplars)
+            .await;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{sync::mpsc::sync_channel, time::Duration};
+
+    use crate::{
+        allocator::{AlwaysNewMetricsAllocator, MetricsAllocator},
+        downstream::{GoodmetricsBatcher, OpentelemetryBatcher},
+        metrics::Metrics,
+        pipeline::{Aggregator, DistributionMode},
+    };
+
+    use super::FanOut;
+
+    #[test_log::test(tokio::test)]
+    async fn every_downstream_gets_every_window() {
+        let (sender, receiver) = sync_channel(16);
+        let aggregator: Aggregator<Metrics> =
+            Aggregator::new(receiver, DistributionMode::Histogram);
+        let (goodmetrics_sender, mut goodmetrics_receiver) = tokio::sync::mpsc::channel(16);
+        // A full channel stands in for a stuck downstream
+        let (stuck_sender, _stuck_receiver) = tokio::sync::mpsc::channel(1);
+        stuck_sender.try_send(Vec::new()).unwrap();
+        let (otlp_sender, mut otlp_receiver) = tokio::sync::mpsc::channel(16);
+        let fan_out = FanOut::default()
+            .with_downstream(GoodmetricsBatcher, goodmetrics_sender)
+            .with_downstream(GoodmetricsBatcher, stuck_sender)
+            .with_downstream(OpentelemetryBatcher, otlp_sender);
+        let aggregating =
+            tokio::spawn(aggregator.fan_out_metrics_forever(Duration::from_millis(10), fan_out));
+
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics("test");
+        metrics.dimension("a", "dimension");
+        metrics.measurement("v", 22);
+        sender.try_send(metrics).unwrap();
+
+        let goodmetrics_batch =
+            tokio::time::timeout(Duration::from_secs(5), goodmetrics_receiver.recv())
+                .await
+                .expect("goodmetrics should get a window")
+                .unwrap();
+        assert_eq!(1, goodmetrics_batch.len());
+        assert_eq!("test", goodmetrics_batch[0].metric);
+        assert!(goodmetrics_batch[0].measurements.contains_key("v"));
+        let otlp_batch = tokio::time::timeout(Duration::from_secs(5), otlp_receiver.recv())
+            .await
+            .expect("otlp should get the same window, even with a stuck downstream")
+            .unwrap();
+        assert_eq!(4, otlp_batch.len(), "a statistic set is 4 otlp metrics");
+
+        aggregating.abort();
+    }
+}
@@ -6,17 +6,28 @@ use futures::StreamExt;
 use futures_batch::ChunksTimeoutStreamExt;
 
 mod aggregator;
+mod cardinality_limits;
+mod dimension_rules;
+mod distribution_rules;
+mod fan_out;
 mod logging_sink;
 mod serializing_sink;
+mod sharded;
 mod stream_sink;
 
 pub use aggregator::{
     AggregatedMetricsMap, AggregationBatcher, Aggregator, DimensionPosition,
-    DimensionedMeasurementsMap, DistributionMode, MeasurementAggregationMap, TimeSource,
+    DimensionedMeasurementsMap, DistributionMode, ExemplarsMap, MeasurementAggregationMap,
+    TimeSource,
 };
+pub use cardinality_limits::CardinalityLimits;
+pub use dimension_rules::DimensionRules;
+pub use distribution_rules::{DistributionRules, NamePattern};
+pub use fan_out::FanOut;
 pub use logging_sink::LoggingSink;
 pub use serializing_sink::SerializingSink;
-pub use stream_sink::StreamSink;
+pub use sharded::{ShardRouting, ShardedAggregator, ShardedSink};
+pub use stream_sink::{MetricsReceiver, StreamSink};
 
 /// A drain that accepts Sunk
 pub trait Sink<Sunk> {
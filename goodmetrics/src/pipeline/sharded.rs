@@ -0,0 +1,455 @@
+use std::{
+    future::Future,
+    hash::{DefaultHasher, Hash, Hasher},
+    pin::pin,
+    time::{Duration, Instant, SystemTime},
+};
+
+use futures::future::{select, Either};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{
+    aggregator::{Emit, SendBatches},
+    AggregatedMetricsMap, AggregationBatcher, Aggregator, ExemplarsMap, FanOut, MetricsReceiver,
+    Sink, StreamSink,
+};
+use crate::{allocator::MetricsRef, downstream::Spool, types::Dimension, Name};
+
+/// How a ShardedSink picks the shard for each Metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShardRouting {
+    /// Each metric name goes to 1 shard. Shards never share a metric.
+    #[default]
+    MetricName,
+    /// Each dimension position goes to 1 shard, so a single busy metric is spread out too.
+    /// Cardinality limits apply per shard; when 2 shards both fold a metric into its overflow
+    /// position, the copies get a `shard` dimension so they do not collide. The same goes for
+    /// positions that DimensionRules rewrite to be the same, so prefer MetricName with those.
+    Position,
+}
+
+/// A metrics sink that spreads metrics over several queues, 1 per Aggregator shard.
+///
+/// Use this with a ShardedAggregator when 1 Aggregator can not keep up with your service.
+/// ```
+/// # use std::time::Duration;
+/// # use goodmetrics::{
+/// #     downstream::GoodmetricsBatcher,
+/// #     pipeline::{Aggregator, DistributionMode, ShardRouting, ShardedAggregator, ShardedSink},
+/// #     Metrics, MetricsFactory, allocator::AlwaysNewMetricsAllocator,
+/// # };
+/// # async fn example() {
+/// let (sink, receivers) = ShardedSink::<Metrics>::new(4, ShardRouting::MetricName);
+/// let aggregator = ShardedAggregator::new(
+///     receivers
+///         .into_iter()
+///         .map(|receiver| Aggregator::new(receiver, DistributionMode::Histogram)),
+/// );
+/// let metrics_factory: MetricsFactory<AlwaysNewMetricsAllocator, ShardedSink<Metrics>> =
+///     MetricsFactory::new(sink);
+/// let (aggregated_batch_sender, aggregated_batch_receiver) = tokio::sync::mpsc::channel(128);
+/// tokio::spawn(aggregator.aggregate_metrics_forever(
+///     Duration::from_secs(1),
+///     aggregated_batch_sender,
+///     GoodmetricsBatcher,
+/// ));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct ShardedSink<TMetricsRef> {
+    shards: Vec<StreamSink<TMetricsRef>>,
+    routing: ShardRouting,
+}
+
+impl<TMetricsRef> Clone for ShardedSink<TMetricsRef> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            routing: self.routing,
+        }
+    }
+}
+
+impl<TMetricsRef> ShardedSink<TMetricsRef> {
+    /// Create a sink with this many shards, and a receiver for each shard's Aggregator.
+    pub fn new(shards: usize, routing: ShardRouting) -> (Self, Vec<MetricsReceiver<TMetricsRef>>) {
+        let (shards, receivers) = (0..shards.max(1)).map(|_| StreamSink::new()).unzip();
+        (Self { shards, routing }, receivers)
+    }
+}
+
+impl<TMetricsRef> Sink<TMetricsRef> for ShardedSink<TMetricsRef>
+where
+    TMetricsRef: MetricsRef,
+{
+    fn accept(&self, mut to_sink: TMetricsRef) {
+        let metrics = to_sink.as_mut();
+        let mut hasher = DefaultHasher::new();
+        metrics.metrics_name.as_str().hash(&mut hasher);
+        let mut hash = hasher.finish();
+        if self.routing == ShardRouting::Position {
+            // Dimensions are in a HashMap, so their hashes are combined without regard to order
+            for (name, dimension) in metrics.drain().0.iter() {
+                let mut hasher = DefaultHasher::new();
+                name.as_str().hash(&mut hasher);
+                dimension.hash(&mut hasher);
+                hash = hash.wrapping_add(hasher.finish());
+            }
+        }
+        self.shards[hash as usize % self.shards.len()].accept(to_sink)
+    }
+}
+
+/// A request for a shard's window
+pub(crate) struct ShardDrain {
+    /// Aggregate everything still queued first, then stop
+    pub(crate) final_window: bool,
+    pub(crate) respond: oneshot::Sender<(AggregatedMetricsMap, ExemplarsMap)>,
+}
+
+/// Several Aggregators, each on its own task, that report as 1.
+///
+/// Each shard aggregates from its own queue, so the work spreads over your runtime's threads.
+/// At each cadence tick every shard's window is merged, and the batches you get are the same
+/// as 1 Aggregator would make. Configure each shard like you would an Aggregator.
+pub struct ShardedAggregator<TMetricsRef> {
+    shards: Vec<Aggregator<TMetricsRef>>,
+    spool: Option<Spool>,
+}
+
+impl<TMetricsRef> ShardedAggregator<TMetricsRef>
+where
+    TMetricsRef: MetricsRef + Send + 'static,
+{
+    /// Aggregate with these shards, like 1 per receiver from a ShardedSink
+    pub fn new(shards: impl IntoIterator<Item = Aggregator<TMetricsRef>>) -> Self {
+        Self {
+            shards: shards.into_iter().collect(),
+            spool: None,
+        }
+    }
+
+    /// Like Aggregator::with_spool, for the merged windows
+    pub fn with_spool(mut self, spool: Spool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Like Aggregator::aggregate_metrics_forever. This spawns a task per shard.
+    pub async fn aggregate_metrics_forever<TAggregationBatcher>(
+        self,
+        cadence: Duration,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+        make_batch: TAggregationBatcher,
+    ) where
+        TAggregationBatcher: AggregationBatcher,
+    {
+        self.aggregate_metrics_until(cadence, sender, make_batch, std::future::pending())
+            .await
+    }
+
+    /// Like Aggregator::aggregate_metrics_until. This spawns a task per shard.
+    pub async fn aggregate_metrics_until<TAggregationBatcher>(
+        self,
+        cadence: Duration,
+        sender: mpsc::Sender<TAggregationBatcher::TBatch>,
+        make_batch: TAggregationBatcher,
+        shutdown: impl Future<Output = ()>,
+    ) where
+        TAggregationBatcher: AggregationBatcher,
+    {
+        let spool = self.spool.clone();
+        self.emit_until(
+            cadence,
+            SendBatches {
+                sender,
+                batcher: make_batch,
+                spool,
+            },
+            shutdown,
+        )
+        .await
+    }
+
+    /// Like Aggregator::fan_out_metrics_forever. This spawns a task per shard.
+    pub async fn fan_out_metrics_forever(self, cadence: Duration, fan_out: FanOut) {
+        self.emit_until(cadence, fan_out, std::future::pending())
+            .await
+    }
+
+    /// Like Aggregator::fan_out_metrics_until. This spawns a task per shard.
+    pub async fn fan_out_metrics_until(
+        self,
+        cadence: Duration,
+        fan_out: FanOut,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        self.emit_until(cadence, fan_out, shutdown).await
+    }
+
+    async fn emit_until(
+        self,
+        cadence: Duration,
+        mut emit: impl Emit,
+        shutdown: impl Future<Output = ()>,
+    ) {
+        let shards: Vec<_> = self
+            .shards
+            .into_iter()
+            .map(|shard| {
+                let (drains, drain_receiver) = mpsc::channel(1);
+                tokio::spawn(shard.aggregate_shard(drain_receiver));
+                drains
+            })
+            .collect();
+
+        let mut shutdown = pin!(shutdown);
+        let mut last_emit = Instant::now();
+        // Aligned like Aggregator, so sharded and unsharded windows line up.
+        let extra_start_offset = SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("could not get system time")
+            .as_millis()
+            % cadence.as_millis();
+        let align = pin!(tokio::time::sleep(Duration::from_millis(
+            extra_start_offset as u64
+        )));
+        if let Either::Right(_) = select(align, shutdown.as_mut()).await {
+            emit_final_window(&shards, last_emit, &mut emit).await;
+            return;
+        }
+        last_emit = Instant::now();
+
+        loop {
+            let next_window = pin!(tokio::time::sleep_until((last_emit + cadence).into()));
+            if let Either::Right(_) = select(next_window, shutdown.as_mut()).await {
+                emit_final_window(&shards, last_emit, &mut emit).await;
+                return;
+            }
+            last_emit = Instant::now();
+            let (mut map, mut exemplars) = collect_window(&shards, false).await;
+            if !map.is_empty() {
+                emit.emit(SystemTime::now(), cadence, &mut map, &mut exemplars);
+            }
+        }
+    }
+}
+
+/// Collect what is left in the shards, and send it with however much time it covers
+async fn emit_final_window(
+    shards: &[mpsc::Sender<ShardDrain>],
+    last_emit: Instant,
+    emit: &mut impl Emit,
+) {
+    let (mut map, mut exemplars) = collect_window(shards, true).await;
+    if !map.is_empty() {
+        emit.emit_final(
+            SystemTime::now(),
+            last_emit.elapsed(),
+            &mut map,
+            &mut exemplars,
+        )
+        .await;
+    }
+}
+
+/// Ask every shard for its window at once, then merge them
+async fn collect_window(
+    shards: &[mpsc::Sender<ShardDrain>],
+    final_window: bool,
+) -> (AggregatedMetricsMap, ExemplarsMap) {
+    let mut responses = Vec::with_capacity(shards.len());
+    for (shard, drains) in shards.iter().enumerate() {
+        let (respond, response) = oneshot::channel();
+        match drains
+            .send(ShardDrain {
+                final_window,
+                respond,
+            })
+            .await
+        {
+            Ok(_) => responses.push((shard, response)),
+            Err(_) => log::error!("aggregator shard {shard} is gone"),
+        }
+    }
+
+    let mut map = AggregatedMetricsMap::default();
+    let mut exemplars = ExemplarsMap::default();
+    for (shard, response) in responses {
+        match response.await {
+            Ok((shard_map, shard_exemplars)) => {
+                merge_shard(&mut map, &mut exemplars, shard, shard_map, shard_exemplars)
+            }
+            Err(_) => log::error!("aggregator shard {shard} did not report its window"),
+        }
+    }
+    (map, exemplars)
+}
+
+fn merge_shard(
+    map: &mut AggregatedMetricsMap,
+    exemplars: &mut ExemplarsMap,
+    shard: usize,
+    shard_map: AggregatedMetricsMap,
+    mut shard_exemplars: ExemplarsMap,
+) {
+    for (metrics_name, positions) in shard_map {
+        let mut metric_exemplars = shard_exemplars.remove(&metrics_name).unwrap_or_default();
+        let merged_positions = map.entry(metrics_name.clone()).or_default();
+        for (mut position, measurements) in positions {
+            let position_exemplars = metric_exemplars.remove(&position);
+            if merged_positions.contains_key(&position) {
+                // Only overflow positions, or positions that dimension rules rewrote, can be in 2 shards
+                position.insert(Name::Str("shard"), Dimension::Number(shard as u64));
+            }
+            if let Some(position_exemplars) = position_exemplars {
+                exemplars
+                    .entry(metrics_name.clone())
+                    .or_default()
+                    .insert(position.clone(), position_exemplars);
+            }
+            merged_positions.insert(position, measurements);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        time::{Duration, SystemTime},
+    };
+
+    use crate::{
+        aggregation::{Aggregation, StatisticSet},
+        allocator::{AlwaysNewMetricsAllocator, MetricsAllocator},
+        metrics::Metrics,
+        pipeline::{
+            AggregatedMetricsMap, AggregationBatcher, Aggregator, CardinalityLimits,
+            DimensionedMeasurementsMap, DistributionMode, Sink,
+        },
+        types::{Dimension, Name},
+    };
+
+    use super::{ShardRouting, ShardedAggregator, ShardedSink};
+
+    struct TestAggregationBatcher;
+    impl AggregationBatcher for TestAggregationBatcher {
+        type TBatch = HashMap<Name, DimensionedMeasurementsMap>;
+
+        fn batch_aggregations(
+            &mut self,
+            _now: SystemTime,
+            _covered_time: Duration,
+            aggregations: &mut AggregatedMetricsMap,
+        ) -> Self::TBatch {
+            std::mem::take(aggregations)
+        }
+    }
+
+    fn metrics(name: &'static str, user: u64) -> Metrics {
+        let mut metrics = AlwaysNewMetricsAllocator.new_metrics(name);
+        metrics.dimension("user", user);
+        metrics.measurement("v", 1);
+        metrics
+    }
+
+    /// Record into a sharded aggregator, and shut it down to get 1 window back
+    async fn sharded_window(
+        routing: ShardRouting,
+        cardinality_limits: CardinalityLimits,
+        record: impl FnOnce(&ShardedSink<Metrics>),
+    ) -> HashMap<Name, DimensionedMeasurementsMap> {
+        let (sink, receivers) = ShardedSink::new(4, routing);
+        let aggregator = ShardedAggregator::new(receivers.into_iter().map(|receiver| {
+            Aggregator::new(receiver, DistributionMode::Histogram)
+                .with_cardinality_limits(cardinality_limits)
+        }));
+        record(&sink);
+        let (batch_sender, mut batch_receiver) = tokio::sync::mpsc::channel(16);
+        let (shutdown, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+        let aggregating = tokio::spawn(aggregator.aggregate_metrics_until(
+            Duration::from_secs(3600),
+            batch_sender,
+            TestAggregationBatcher,
+            async move {
+                let _ = shutdown_receiver.await;
+            },
+        ));
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), aggregating)
+            .await
+            .expect("the aggregator should stop promptly")
+            .unwrap();
+        batch_receiver
+            .recv()
+            .await
+            .expect("there should be a final batch")
+    }
+
+    #[test_log::test(tokio::test(flavor = "multi_thread", worker_threads = 2))]
+    async fn shards_report_like_1_aggregator() {
+        for routing in [ShardRouting::MetricName, ShardRouting::Position] {
+            let window = sharded_window(routing, CardinalityLimits::default(), |sink| {
+                for name in ["a", "b", "c", "d", "e"] {
+                    for user in 0..10 {
+                        sink.accept(metrics(name, user));
+                        sink.accept(metrics(name, user));
+                    }
+                }
+            })
+            .await;
+
+            assert_eq!(5, window.len(), "{routing:?}");
+            for positions in window.values() {
+                assert_eq!(10, positions.len(), "{routing:?}");
+                for (position, measurements) in positions {
+                    assert_eq!(1, position.len(), "{routing:?}");
+                    assert_eq!(
+                        Some(&Aggregation::StatisticSet(StatisticSet {
+                            min: 1,
+                            max: 1,
+                            sum: 2,
+                            count: 2
+                        })),
+                        measurements.get(&Name::from("v")),
+                        "{routing:?}: each position is whole in 1 shard"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test_log::test(tokio::test(flavor = "multi_thread", worker_threads = 2))]
+    async fn overflow_positions_from_several_shards_do_not_collide() {
+        let mut cardinality_limits = CardinalityLimits::default();
+        cardinality_limits.per_metric(1);
+        let window = sharded_window(ShardRouting::Position, cardinality_limits, |sink| {
+            for user in 0..100 {
+                sink.accept(metrics("a", user));
+            }
+        })
+        .await;
+
+        let positions = &window[&Name::from("a")];
+        let total: u64 = positions
+            .values()
+            .map(|measurements| match &measurements[&Name::from("v")] {
+                Aggregation::StatisticSet(statistic_set) => statistic_set.count,
+                other => panic!("expected a statistic set: {other}"),
+            })
+            .sum();
+        assert_eq!(100, total, "nothing is lost when merging");
+        assert!(positions.contains_key(&BTreeMap::from([(
+            Name::from("overflow"),
+            Dimension::from(true)
+        )])));
+        assert!(
+            positions
+                .keys()
+                .any(|position| position.contains_key(&Name::from("shard"))),
+            "{positions:?}"
+        );
+    }
+}
@@ -1,4 +1,12 @@
-use std::sync::mpsc;
+use std::{
+    sync::{
+        atomic::{fence, AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::Notify;
 
 use super::Sink;
 
@@ -6,12 +14,14 @@ use super::Sink;
 #[derive(Debug)]
 pub struct StreamSink<TMetricsRef> {
     queue: mpsc::SyncSender<TMetricsRef>,
+    wake: Arc<Wake>,
 }
 
 impl<TMetricsRef> Clone for StreamSink<TMetricsRef> {
     fn clone(&self) -> Self {
         Self {
             queue: self.queue.clone(),
+            wake: self.wake.clone(),
         }
     }
 }
@@ -19,21 +29,211 @@ impl<TMetricsRef> Clone for StreamSink<TMetricsRef> {
 impl<TMetricsRef> StreamSink<TMetricsRef> {
     /// Create a new stream sink.
     /// StreamSink is a Sink suitable for wiring to multiple metrics factories.
-    /// The receiver is suitable for consuming into an aggregator or batching into raw metrics.
-    pub fn new() -> (Self, mpsc::Receiver<TMetricsRef>) {
+    /// The receiver is suitable for consuming into an aggregator or batching into raw metrics,
+    /// like with `pipeline::stream_batches`.
+    pub fn new() -> (Self, MetricsReceiver<TMetricsRef>) {
         let (sender, receiver) = mpsc::sync_channel(1024);
+        let wake = Arc::new(Wake::default());
 
-        (Self { queue: sender }, receiver)
+        (
+            Self {
+                queue: sender,
+                wake: wake.clone(),
+            },
+            MetricsReceiver {
+                queue: receiver,
+                wake: Some(wake),
+            },
+        )
     }
 }
 
 impl<TMetricsRef> Sink<TMetricsRef> for StreamSink<TMetricsRef> {
     fn accept(&self, to_sink: TMetricsRef) {
         match self.queue.try_send(to_sink) {
-            Ok(_) => (),
+            Ok(_) => self.wake.wake(),
             Err(e) => {
                 log::debug!("could not send metrics: {e:?}");
             }
         }
     }
 }
+
+/// The receiving end of a StreamSink, for an Aggregator.
+///
+/// An Aggregator waiting on this is woken when metrics arrive. Producers only pay for the
+/// wakeup when the Aggregator has run out of work; while it is busy, sending is 1 fence and
+/// 1 atomic load.
+///
+/// A plain `std::sync::mpsc::Receiver` works too. Nothing wakes the Aggregator for those,
+/// so it checks for metrics every 5 milliseconds instead.
+///
+/// You can also consume it yourself, like a `std::sync::mpsc::Receiver`.
+#[derive(Debug)]
+pub struct MetricsReceiver<TMetricsRef> {
+    queue: mpsc::Receiver<TMetricsRef>,
+    wake: Option<Arc<Wake>>,
+}
+
+impl<TMetricsRef> From<mpsc::Receiver<TMetricsRef>> for MetricsReceiver<TMetricsRef> {
+    fn from(queue: mpsc::Receiver<TMetricsRef>) -> Self {
+        Self { queue, wake: None }
+    }
+}
+
+/// How often to check a receiver that nothing wakes
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+impl<TMetricsRef> MetricsReceiver<TMetricsRef> {
+    /// Metrics if any are queued, without blocking
+    pub fn try_recv(&self) -> Result<TMetricsRef, mpsc::TryRecvError> {
+        self.queue.try_recv()
+    }
+
+    /// Block until metrics arrive, or every sender is gone
+    pub fn recv(&self) -> Result<TMetricsRef, mpsc::RecvError> {
+        self.queue.recv()
+    }
+
+    /// The next metrics, or None if none arrive within wait_for
+    pub(crate) async fn recv_timeout(&mut self, wait_for: Duration) -> Option<TMetricsRef> {
+        // Too far away to be a deadline means no deadline
+        let deadline = tokio::time::Instant::now().checked_add(wait_for);
+        loop {
+            if let Ok(metrics) = self.queue.try_recv() {
+                return Some(metrics);
+            }
+            let now = tokio::time::Instant::now();
+            if deadline.is_some_and(|deadline| deadline <= now) {
+                return None;
+            }
+            match &self.wake {
+                Some(wake) => {
+                    // Say we are waiting, then look again: a send from before this point is
+                    // seen now, and a send from after it wakes us. The fence keeps the look
+                    // from happening before the say; it pairs with the fence in Wake::wake.
+                    wake.waiting.store(true, Ordering::SeqCst);
+                    fence(Ordering::SeqCst);
+                    if let Ok(metrics) = self.queue.try_recv() {
+                        wake.waiting.store(false, Ordering::Relaxed);
+                        return Some(metrics);
+                    }
+                    match deadline {
+                        Some(deadline) => {
+                            if tokio::time::timeout_at(deadline, wake.notify.notified())
+                                .await
+                                .is_err()
+                            {
+                                wake.waiting.store(false, Ordering::Relaxed);
+                            }
+                        }
+                        None => wake.notify.notified().await,
+                    }
+                }
+                None => {
+                    let delay = deadline.map_or(POLL_INTERVAL, |deadline| {
+                        POLL_INTERVAL.min(deadline.saturating_duration_since(now))
+                    });
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+impl<TMetricsRef> IntoIterator for MetricsReceiver<TMetricsRef> {
+    type Item = TMetricsRef;
+    type IntoIter = mpsc::IntoIter<TMetricsRef>;
+
+    /// Blocks for each metrics until every sender is gone
+    fn into_iter(self) -> Self::IntoIter {
+        self.queue.into_iter()
+    }
+}
+
+impl<'a, TMetricsRef> IntoIterator for &'a MetricsReceiver<TMetricsRef> {
+    type Item = TMetricsRef;
+    type IntoIter = mpsc::Iter<'a, TMetricsRef>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.queue.iter()
+    }
+}
+
+/// Lets producers wake an idle receiver without paying for it when the receiver is busy
+#[derive(Debug, Default)]
+struct Wake {
+    waiting: AtomicBool,
+    notify: Notify,
+}
+
+impl Wake {
+    /// Call after queueing metrics
+    fn wake(&self) {
+        // The queue write is not SeqCst, so without this it could land after the load of
+        // waiting, and a receiver that just looked at the empty queue would never be woken.
+        fence(Ordering::SeqCst);
+        if self.waiting.load(Ordering::SeqCst) && self.waiting.swap(false, Ordering::SeqCst) {
+            self.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use futures::StreamExt;
+
+    use crate::pipeline::{stream_batches, Sink};
+
+    use super::{MetricsReceiver, StreamSink};
+
+    #[test_log::test(tokio::test(flavor = "multi_thread", worker_threads = 2))]
+    async fn arriving_metrics_wake_the_receiver() {
+        let (sink, mut receiver) = StreamSink::<u32>::new();
+        let sending = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sink.accept(1);
+        });
+
+        let start = Instant::now();
+        assert_eq!(
+            Some(1),
+            receiver.recv_timeout(Duration::from_secs(60)).await
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+        sending.join().unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn times_out_without_metrics() {
+        let (_sink, mut receiver) = StreamSink::<u32>::new();
+        assert_eq!(None, receiver.recv_timeout(Duration::from_millis(10)).await);
+
+        let (_sender, receiver) = std::sync::mpsc::sync_channel::<u32>(1);
+        let mut receiver = MetricsReceiver::from(receiver);
+        assert_eq!(None, receiver.recv_timeout(Duration::from_millis(10)).await);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn receiver_streams_batches() {
+        let (sink, receiver) = StreamSink::<u32>::new();
+        for i in 1..=3 {
+            sink.accept(i);
+        }
+        drop(sink);
+
+        let batches: Vec<Vec<u32>> =
+            stream_batches::<futures::stream::Iter<std::sync::mpsc::IntoIter<u32>>, _, _, _>(
+                receiver,
+                |batch| batch,
+                2,
+                Duration::from_millis(10),
+            )
+            .collect()
+            .await;
+        assert_eq!(vec![vec![1, 2], vec![3]], batches);
+    }
+}
@@ -28,3 +28,18 @@ pub mod opentelemetry {
         }
     }
 }
+
+/// OTLP json wants trace and span ids as hex strings rather than base64
+pub(crate) fn serialize_hex<S: serde::Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    use std::fmt::Write;
+    let hex = bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    serializer.serialize_str(&hex)
+}
@@ -1,5 +1,7 @@
 // This file is @generated by prost-build.
 /// Resource information.
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Resource {
     /// Set of attributes that describe the resource.
@@ -0,0 +1,222 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio_rustls::rustls::RootCertStore;
+use tonic::transport::Server;
+
+use crate::{
+    downstream::{get_client, ChannelType, TlsConfiguration},
+    proto::goodmetrics::{
+        dimension,
+        metrics_client::MetricsClient,
+        metrics_server::{Metrics, MetricsServer},
+        Datum, Dimension, MetricsReply, MetricsRequest,
+    },
+};
+
+use super::{LocalServer, Recorder};
+
+/// A goodmetrics collector on a local port, for tests. It records every
+/// `goodmetrics.Metrics/SendMetrics` request it accepts.
+///
+/// The server stops when this is dropped.
+#[derive(Debug)]
+pub struct FakeGoodmetricsCollector {
+    recorder: Arc<Recorder<MetricsRequest>>,
+    server: LocalServer,
+}
+
+#[derive(Debug)]
+struct Service(Arc<Recorder<MetricsRequest>>);
+
+#[tonic::async_trait]
+impl Metrics for Service {
+    async fn send_metrics(
+        &self,
+        request: tonic::Request<MetricsRequest>,
+    ) -> Result<tonic::Response<MetricsReply>, tonic::Status> {
+        self.0.receive(request.into_inner()).await?;
+        Ok(tonic::Response::new(MetricsReply {}))
+    }
+}
+
+impl FakeGoodmetricsCollector {
+    /// Listen on a free local port. Must be called within a tokio runtime.
+    pub async fn start() -> std::io::Result<Self> {
+        let recorder: Arc<Recorder<MetricsRequest>> = Default::default();
+        let server = LocalServer::start(
+            Server::builder().add_service(MetricsServer::new(Service(recorder.clone()))),
+        )
+        .await?;
+        Ok(Self { recorder, server })
+    }
+
+    /// Where the collector is listening, like "http://127.0.0.1:12345"
+    pub fn endpoint(&self) -> String {
+        self.server.endpoint()
+    }
+
+    /// A client for a GoodmetricsDownstream that sends to this collector
+    pub fn client(&self) -> MetricsClient<ChannelType> {
+        get_client(
+            &self.endpoint(),
+            // Plaintext, so nothing is verified - but nothing warns about it either
+            &TlsConfiguration::with_roots(RootCertStore::empty()),
+            MetricsClient::with_origin,
+        )
+        .expect("a local endpoint should make a valid client")
+    }
+
+    /// Reject the next `count` requests with a status code, without recording them
+    pub fn fail_next(&self, count: usize, code: tonic::Code) {
+        self.recorder.fail_next(count, code)
+    }
+
+    /// Wait this long before answering each request
+    pub fn set_latency(&self, latency: Duration) {
+        self.recorder.set_latency(latency)
+    }
+
+    /// Every request accepted so far, in the order they arrived
+    pub fn requests(&self) -> Vec<MetricsRequest> {
+        self.recorder.requests()
+    }
+
+    /// Every datum accepted so far, with the request's shared dimensions applied
+    pub fn data(&self) -> Vec<Datum> {
+        self.requests()
+            .into_iter()
+            .flat_map(|request| {
+                let shared_dimensions = request.shared_dimensions;
+                request.metrics.into_iter().map(move |mut datum| {
+                    for (name, value) in &shared_dimensions {
+                        datum
+                            .dimensions
+                            .entry(name.clone())
+                            .or_insert_with(|| value.clone());
+                    }
+                    datum
+                })
+            })
+            .collect()
+    }
+
+    /// Wait until at least `count` requests have been accepted. Panics after timeout.
+    pub async fn wait_for_requests(&self, count: usize, timeout: Duration) -> Vec<MetricsRequest> {
+        self.recorder.wait_for_requests(count, timeout).await
+    }
+
+    /// Panics unless a datum named `metric` arrived with all of these dimensions.
+    /// Dimension values are compared by their string form, so `("port", "443")` matches a
+    /// number dimension. Shared dimensions count too.
+    pub fn assert_datum_arrived(&self, metric: &str, dimensions: &[(&str, &str)]) -> Datum {
+        let data = self.data();
+        match data.iter().find(|datum| {
+            datum.metric == metric
+                && dimensions.iter().all(|(name, value)| {
+                    datum
+                        .dimensions
+                        .get(*name)
+                        .is_some_and(|dimension| dimension_string(dimension) == *value)
+                })
+        }) {
+            Some(datum) => datum.clone(),
+            None => panic!(
+                "no datum {metric} with dimensions {dimensions:?} arrived. These did: {:#?}",
+                data.iter()
+                    .map(|datum| (&datum.metric, &datum.dimensions))
+                    .collect::<Vec<_>>()
+            ),
+        }
+    }
+}
+
+fn dimension_string(dimension: &Dimension) -> String {
+    match &dimension.value {
+        Some(dimension::Value::String(s)) => s.clone(),
+        Some(dimension::Value::Number(n)) => n.to_string(),
+        Some(dimension::Value::Boolean(b)) => b.to_string(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        downstream::{DeliveryConfiguration, GoodmetricsDownstream},
+        proto::goodmetrics::{dimension, Datum, Dimension},
+    };
+
+    use super::FakeGoodmetricsCollector;
+
+    fn datum(metric: &str, dimensions: &[(&str, u64)]) -> Datum {
+        Datum {
+            metric: metric.to_string(),
+            dimensions: dimensions
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::Number(*value)),
+                        },
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn records_what_arrives() {
+        let collector = FakeGoodmetricsCollector::start()
+            .await
+            .expect("can listen on a local port");
+        let downstream = GoodmetricsDownstream::new(collector.client(), None, [("host", "a")]);
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([vec![datum(
+                "api",
+                &[("status", 200)],
+            )]])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+
+        assert_eq!(1, collector.requests().len());
+        collector.assert_datum_arrived("api", &[("host", "a"), ("status", "200")]);
+    }
+
+    #[test_log::test(tokio::test)]
+    #[should_panic(expected = "no datum api with dimensions")]
+    async fn missing_data_panics() {
+        let collector = FakeGoodmetricsCollector::start()
+            .await
+            .expect("can listen on a local port");
+        collector.assert_datum_arrived("api", &[]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn injected_failures_are_retried() {
+        let collector = FakeGoodmetricsCollector::start()
+            .await
+            .expect("can listen on a local port");
+        collector.fail_next(2, tonic::Code::Unavailable);
+        collector.set_latency(Duration::from_millis(10));
+        let mut delivery = DeliveryConfiguration::default();
+        delivery.initial_backoff(Duration::from_millis(1));
+        let downstream = GoodmetricsDownstream::new(collector.client(), None, [("host", "a")])
+            .with_delivery_configuration(delivery);
+        tokio::spawn(
+            downstream
+                .send_metrics_stream_forever(futures::stream::iter([vec![datum("api", &[])]])),
+        );
+
+        let requests = collector
+            .wait_for_requests(1, Duration::from_secs(10))
+            .await;
+        assert_eq!(1, requests.len(), "failed requests are not recorded");
+        collector.assert_datum_arrived("api", &[("host", "a")]);
+    }
+}
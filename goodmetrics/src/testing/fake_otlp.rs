@@ -0,0 +1,264 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio_rustls::rustls::RootCertStore;
+use tonic::transport::Server;
+
+use crate::{
+    downstream::{get_client, ChannelType, TlsConfiguration},
+    proto::opentelemetry::{
+        collector::metrics::v1::{
+            metrics_service_client::MetricsServiceClient,
+            metrics_service_server::{MetricsService, MetricsServiceServer},
+            ExportMetricsServiceRequest, ExportMetricsServiceResponse,
+        },
+        common::v1::{any_value, KeyValue},
+        metrics::v1::{metric, Metric},
+    },
+};
+
+use super::{LocalServer, Recorder};
+
+/// An OpenTelemetry collector on a local port, for tests. It records every
+/// `MetricsService/Export` request it accepts.
+///
+/// The server stops when this is dropped.
+#[derive(Debug)]
+pub struct FakeOtlpCollector {
+    recorder: Arc<Recorder<ExportMetricsServiceRequest>>,
+    server: LocalServer,
+}
+
+#[derive(Debug)]
+struct Service(Arc<Recorder<ExportMetricsServiceRequest>>);
+
+#[tonic::async_trait]
+impl MetricsService for Service {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        self.0.receive(request.into_inner()).await?;
+        Ok(tonic::Response::new(ExportMetricsServiceResponse {
+            partial_success: None,
+        }))
+    }
+}
+
+impl FakeOtlpCollector {
+    /// Listen on a free local port. Must be called within a tokio runtime.
+    pub async fn start() -> std::io::Result<Self> {
+        let recorder: Arc<Recorder<ExportMetricsServiceRequest>> = Default::default();
+        let server = LocalServer::start(
+            Server::builder().add_service(MetricsServiceServer::new(Service(recorder.clone()))),
+        )
+        .await?;
+        Ok(Self { recorder, server })
+    }
+
+    /// Where the collector is listening, like "http://127.0.0.1:12345"
+    pub fn endpoint(&self) -> String {
+        self.server.endpoint()
+    }
+
+    /// A client for an OpenTelemetryDownstream that sends to this collector
+    pub fn client(&self) -> MetricsServiceClient<ChannelType> {
+        get_client(
+            &self.endpoint(),
+            // Plaintext, so nothing is verified - but nothing warns about it either
+            &TlsConfiguration::with_roots(RootCertStore::empty()),
+            MetricsServiceClient::with_origin,
+        )
+        .expect("a local endpoint should make a valid client")
+    }
+
+    /// Reject the next `count` requests with a status code, without recording them
+    pub fn fail_next(&self, count: usize, code: tonic::Code) {
+        self.recorder.fail_next(count, code)
+    }
+
+    /// Wait this long before answering each request
+    pub fn set_latency(&self, latency: Duration) {
+        self.recorder.set_latency(latency)
+    }
+
+    /// Every request accepted so far, in the order they arrived
+    pub fn requests(&self) -> Vec<ExportMetricsServiceRequest> {
+        self.recorder.requests()
+    }
+
+    /// Every metric accepted so far
+    pub fn metrics(&self) -> Vec<Metric> {
+        self.requests()
+            .into_iter()
+            .flat_map(|request| request.resource_metrics)
+            .flat_map(|resource_metrics| resource_metrics.scope_metrics)
+            .flat_map(|scope_metrics| scope_metrics.metrics)
+            .collect()
+    }
+
+    /// Wait until at least `count` requests have been accepted. Panics after timeout.
+    pub async fn wait_for_requests(
+        &self,
+        count: usize,
+        timeout: Duration,
+    ) -> Vec<ExportMetricsServiceRequest> {
+        self.recorder.wait_for_requests(count, timeout).await
+    }
+
+    /// Panics unless a metric named `name` arrived with a data point that has all of these
+    /// attributes. Values are compared by their string form, so `("port", "443")` matches an
+    /// int attribute. Resource attributes count too.
+    pub fn assert_metric_arrived(&self, name: &str, attributes: &[(&str, &str)]) -> Metric {
+        let mut arrived = Vec::new();
+        for resource_metrics in self.requests().into_iter().flat_map(|r| r.resource_metrics) {
+            let resource_attributes = resource_metrics
+                .resource
+                .map(|resource| resource.attributes)
+                .unwrap_or_default();
+            for metric in resource_metrics
+                .scope_metrics
+                .into_iter()
+                .flat_map(|scope_metrics| scope_metrics.metrics)
+            {
+                let found = data_point_attributes(&metric).into_iter().any(|point| {
+                    attributes.iter().all(|(key, value)| {
+                        point
+                            .iter()
+                            .chain(resource_attributes.iter())
+                            .any(|attribute| {
+                                attribute.key == *key && value_string(attribute) == *value
+                            })
+                    })
+                });
+                if metric.name == name && found {
+                    return metric;
+                }
+                arrived.push(metric.name);
+            }
+        }
+        panic!("no metric {name} with attributes {attributes:?} arrived. These did: {arrived:?}")
+    }
+}
+
+fn data_point_attributes(metric: &Metric) -> Vec<&Vec<KeyValue>> {
+    match &metric.data {
+        Some(metric::Data::Gauge(gauge)) => {
+            gauge.data_points.iter().map(|p| &p.attributes).collect()
+        }
+        Some(metric::Data::Sum(sum)) => sum.data_points.iter().map(|p| &p.attributes).collect(),
+        Some(metric::Data::Histogram(histogram)) => histogram
+            .data_points
+            .iter()
+            .map(|p| &p.attributes)
+            .collect(),
+        Some(metric::Data::ExponentialHistogram(histogram)) => histogram
+            .data_points
+            .iter()
+            .map(|p| &p.attributes)
+            .collect(),
+        Some(metric::Data::Summary(summary)) => {
+            summary.data_points.iter().map(|p| &p.attributes).collect()
+        }
+        None => Vec::new(),
+    }
+}
+
+fn value_string(attribute: &KeyValue) -> String {
+    match attribute
+        .value
+        .as_ref()
+        .and_then(|value| value.value.as_ref())
+    {
+        Some(any_value::Value::StringValue(s)) => s.clone(),
+        Some(any_value::Value::BoolValue(b)) => b.to_string(),
+        Some(any_value::Value::IntValue(i)) => i.to_string(),
+        Some(any_value::Value::DoubleValue(d)) => d.to_string(),
+        Some(other) => format!("{other:?}"),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        downstream::{DeliveryConfiguration, OpenTelemetryDownstream},
+        proto::opentelemetry::{
+            common::v1::{any_value, AnyValue, KeyValue},
+            metrics::v1::{metric, Gauge, Metric, NumberDataPoint},
+        },
+    };
+
+    use super::FakeOtlpCollector;
+
+    fn gauge(name: &str, attributes: &[(&str, &str)]) -> Metric {
+        Metric {
+            name: name.to_string(),
+            data: Some(metric::Data::Gauge(Gauge {
+                data_points: vec![NumberDataPoint {
+                    attributes: attributes
+                        .iter()
+                        .map(|(key, value)| KeyValue {
+                            key: key.to_string(),
+                            value: Some(AnyValue {
+                                value: Some(any_value::Value::StringValue(value.to_string())),
+                            }),
+                        })
+                        .collect(),
+                    ..Default::default()
+                }],
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn records_what_arrives() {
+        let collector = FakeOtlpCollector::start()
+            .await
+            .expect("can listen on a local port");
+        let mut delivery = DeliveryConfiguration::default();
+        delivery.initial_backoff(Duration::from_millis(1));
+        let downstream = OpenTelemetryDownstream::new_with_dimensions(
+            collector.client(),
+            None::<(&str, _)>,
+            [("host", "a")],
+        )
+        .with_delivery_configuration(delivery);
+        collector.fail_next(1, tonic::Code::Unavailable);
+        collector.set_latency(Duration::from_millis(10));
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([vec![gauge(
+                "api",
+                &[("status", "ok")],
+            )]])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+
+        assert_eq!(1, collector.requests().len());
+        assert_eq!(1, collector.metrics().len());
+        collector.assert_metric_arrived("api", &[("host", "a"), ("status", "ok")]);
+    }
+
+    #[test_log::test(tokio::test)]
+    #[should_panic(expected = "These did: [\"api\"]")]
+    async fn wrong_attributes_panic() {
+        let collector = FakeOtlpCollector::start()
+            .await
+            .expect("can listen on a local port");
+        let downstream = OpenTelemetryDownstream::new(collector.client(), None::<(&str, _)>);
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            downstream.send_metrics_stream_forever(futures::stream::iter([vec![gauge(
+                "api",
+                &[("status", "ok")],
+            )]])),
+        )
+        .await
+        .expect("the downstream should finish when its batch is delivered");
+        collector.assert_metric_arrived("api", &[("status", "error")]);
+    }
+}
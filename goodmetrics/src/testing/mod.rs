@@ -0,0 +1,151 @@
+//! The `testing` feature.
+//! In-process collectors for testing your metrics wiring against something that speaks the
+//! protocol, without mocking clients by hand.
+//!
+//! Each collector listens on a local port, records what it receives, and can be told to fail
+//! or to take its time:
+//! ```
+//! # use std::time::Duration;
+//! # use goodmetrics::{downstream::GoodmetricsDownstream, testing::FakeGoodmetricsCollector};
+//! # async fn example() {
+//! let collector = FakeGoodmetricsCollector::start().await.expect("can listen");
+//! collector.fail_next(2, tonic::Code::Unavailable);
+//! let downstream = GoodmetricsDownstream::new(collector.client(), None, [("host", "a")]);
+//! // ...send some metrics through the downstream...
+//! collector.wait_for_requests(1, Duration::from_secs(5)).await;
+//! collector.assert_datum_arrived("api", &[("host", "a")]);
+//! # }
+//! ```
+
+mod fake_goodmetrics;
+mod fake_otlp;
+
+use std::{collections::VecDeque, net::SocketAddr, sync::Mutex, time::Duration};
+
+use tokio::{net::TcpListener, sync::Notify, task::JoinHandle};
+use tonic::transport::server::{Router, TcpIncoming};
+
+pub use fake_goodmetrics::FakeGoodmetricsCollector;
+pub use fake_otlp::FakeOtlpCollector;
+
+/// What a fake collector has received, and how it should answer next
+#[derive(Debug)]
+struct Recorder<TRequest> {
+    requests: Mutex<Vec<TRequest>>,
+    failures: Mutex<VecDeque<tonic::Code>>,
+    latency: Mutex<Duration>,
+    received: Notify,
+}
+
+impl<TRequest> Default for Recorder<TRequest> {
+    fn default() -> Self {
+        Self {
+            requests: Default::default(),
+            failures: Default::default(),
+            latency: Default::default(),
+            received: Default::default(),
+        }
+    }
+}
+
+impl<TRequest: Clone> Recorder<TRequest> {
+    /// Wait out the latency, then fail or record the request
+    async fn receive(&self, request: TRequest) -> Result<(), tonic::Status> {
+        let latency = *self
+            .latency
+            .lock()
+            .expect("local mutex should not be poisoned");
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        if let Some(code) = self
+            .failures
+            .lock()
+            .expect("local mutex should not be poisoned")
+            .pop_front()
+        {
+            return Err(tonic::Status::new(code, "failing on purpose"));
+        }
+        self.requests
+            .lock()
+            .expect("local mutex should not be poisoned")
+            .push(request);
+        self.received.notify_waiters();
+        Ok(())
+    }
+
+    fn fail_next(&self, count: usize, code: tonic::Code) {
+        self.failures
+            .lock()
+            .expect("local mutex should not be poisoned")
+            .extend(std::iter::repeat_n(code, count));
+    }
+
+    fn set_latency(&self, latency: Duration) {
+        *self
+            .latency
+            .lock()
+            .expect("local mutex should not be poisoned") = latency;
+    }
+
+    fn requests(&self) -> Vec<TRequest> {
+        self.requests
+            .lock()
+            .expect("local mutex should not be poisoned")
+            .clone()
+    }
+
+    async fn wait_for_requests(&self, count: usize, timeout: Duration) -> Vec<TRequest> {
+        let waited = tokio::time::timeout(timeout, async {
+            loop {
+                let received = self.received.notified();
+                let requests = self.requests();
+                if count <= requests.len() {
+                    return requests;
+                }
+                received.await;
+            }
+        })
+        .await;
+        match waited {
+            Ok(requests) => requests,
+            Err(_) => panic!(
+                "expected {count} requests within {timeout:?}, but {} arrived",
+                self.requests().len()
+            ),
+        }
+    }
+}
+
+/// A tonic server on a local port, stopped when it is dropped
+#[derive(Debug)]
+struct LocalServer {
+    address: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl LocalServer {
+    async fn start(router: Router) -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let task = tokio::spawn(async move {
+            if let Err(e) = router
+                .serve_with_incoming(TcpIncoming::from(listener))
+                .await
+            {
+                log::error!("fake collector stopped: {e}");
+            }
+        });
+        Ok(Self { address, task })
+    }
+
+    fn endpoint(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+impl Drop for LocalServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -10,7 +10,7 @@ fn main() {
     eprintln!("I did this so users don't need to have protoc when compiling goodmetrics!");
 
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
         .type_attribute(".", "#[derive()]")
         .out_dir(out_dir.clone())
         .compile_protos(
@@ -20,11 +20,27 @@ fn main() {
         .unwrap();
 
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
         .type_attribute("InstrumentationScope", "#[derive(Eq)]")
         .type_attribute("InstrumentationLibrary", "#[derive(Eq)]")
         .type_attribute("Buckets", "#[derive(Eq)]")
         .type_attribute("ExportMetricsServiceResponse", "#[derive(Eq)]")
+        .type_attribute("ExportMetricsPartialSuccess", "#[derive(Eq)]")
+        // OTLP/HTTP json is plain lowerCamelCase proto3 json, with oneofs inlined and ids in hex
+        .type_attribute(".opentelemetry", "#[derive(serde::Serialize)]")
+        .type_attribute(".opentelemetry", "#[serde(rename_all = \"camelCase\")]")
+        .field_attribute("Metric.data", "#[serde(flatten)]")
+        .field_attribute("AnyValue.value", "#[serde(flatten)]")
+        .field_attribute("NumberDataPoint.value", "#[serde(flatten)]")
+        .field_attribute("Exemplar.value", "#[serde(flatten)]")
+        .field_attribute(
+            "Exemplar.span_id",
+            "#[serde(serialize_with = \"crate::proto::serialize_hex\")]",
+        )
+        .field_attribute(
+            "Exemplar.trace_id",
+            "#[serde(serialize_with = \"crate::proto::serialize_hex\")]",
+        )
         .out_dir(out_dir)
         .compile_protos(
             &[